@@ -0,0 +1,311 @@
+use std::fs;
+
+use serde::Deserialize;
+
+use super::crypto;
+use super::options::{self, Opt};
+
+/// Returns whether a capability is enabled by the given settings.
+type IsEnabled = fn(&Opt) -> bool;
+
+/// The optional features that clients can discover using `GET /capabilities`.
+const CAPABILITIES: [(&str, IsEnabled); 16] = [
+    // Exactly one of these is enabled, depending on who's allowed to post
+    ("allow_list", |opt| opt.allow_list_mode),
+    ("block_list", |opt| !opt.allow_list_mode),
+    ("batch", |_| true),
+    ("compression", |_| true),
+    ("files", |_| true),
+    ("invites", |_| true),
+    ("message_restore", |opt| opt.message_restore_window > 0),
+    ("message_retention", |opt| opt.message_retention_period > 0),
+    ("pinned_messages", |opt| opt.max_pinned_messages > 0),
+    ("profiles", |_| true),
+    ("reactions", |_| true),
+    ("read_receipts", |_| true),
+    ("resumable_uploads", |_| true),
+    ("search", |_| true),
+    ("threads", |_| true),
+    ("welcome_message", |opt| opt.welcome_message.is_some()),
+];
+
+/// Settings loaded from the TOML file given by `--config`. Every setting is optional; settings
+/// that are left out keep the value from the command line (or its default). The names match
+/// those of the command line arguments, with dashes replaced by underscores.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub x25519_public_key: Option<String>,
+    pub x25519_private_key: Option<String>,
+    pub port: Option<u16>,
+    pub host: Option<String>,
+    pub log_file: Option<String>,
+    pub tls: Option<bool>,
+    pub tls_certificate: Option<String>,
+    pub tls_private_key: Option<String>,
+    pub max_file_size: Option<usize>,
+    pub upload_expiration: Option<u64>,
+    pub max_message_size: Option<usize>,
+    pub max_request_size: Option<u64>,
+    pub max_messages_per_minute: Option<u32>,
+    pub max_requests_per_address: Option<u32>,
+    pub address_block_duration: Option<u64>,
+    pub trusted_proxy_header: Option<String>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub max_timestamp_skew: Option<u64>,
+    pub db_pool_size: Option<u32>,
+    pub db_connection_timeout: Option<u64>,
+    pub db_startup_retries: Option<u32>,
+    pub read_replica_dir: Option<String>,
+    pub active_member_window: Option<u64>,
+    pub duplicate_message_window: Option<u64>,
+    pub message_restore_window: Option<u64>,
+    pub message_retention_period: Option<u64>,
+    pub message_retention_sweep_interval: Option<u64>,
+    pub poll_interval: Option<u64>,
+    pub poll_interval_load_threshold: Option<u64>,
+    pub max_page_size: Option<u16>,
+    pub default_page_size: Option<u16>,
+    pub max_pinned_messages: Option<u32>,
+    pub welcome_message: Option<String>,
+    pub content_filter_file: Option<String>,
+    pub file_scanner: Option<String>,
+    pub allowed_file_types: Option<Vec<String>>,
+    pub require_auth_for_reads: Option<bool>,
+    pub allow_list_mode: Option<bool>,
+    pub admin_token_hash: Option<String>,
+    pub cors_allowed_origins: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Reads and parses the configuration file at `path`. The error describes what's wrong in a
+    /// way that can be shown to the server operator as is.
+    pub fn load(path: &str) -> Result<Config, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Err(format!("Couldn't read config file: {} due to error: {}.", path, e))
+            }
+        };
+        match toml::from_str(&contents) {
+            Ok(config) => return Ok(config),
+            Err(e) => {
+                return Err(format!("Couldn't parse config file: {} due to error: {}.", path, e))
+            }
+        }
+    }
+
+    /// Overrides the settings in `opt` with those present in the configuration file. Fails if a
+    /// setting that's only checked at this point is invalid.
+    pub fn apply(self, opt: &mut Opt) -> Result<(), String> {
+        if let Some(x25519_public_key) = self.x25519_public_key {
+            opt.x25519_public_key = x25519_public_key;
+        }
+        if let Some(x25519_private_key) = self.x25519_private_key {
+            opt.x25519_private_key = x25519_private_key;
+        }
+        if let Some(port) = self.port {
+            opt.port = port;
+        }
+        if let Some(host) = self.host {
+            match options::parse_host(&host) {
+                Ok(host) => opt.host = host,
+                Err(_) => return Err(format!("Invalid host: {}. Expected an IP address.", host)),
+            }
+        }
+        if let Some(log_file) = self.log_file {
+            opt.log_file = Some(log_file);
+        }
+        if let Some(tls) = self.tls {
+            opt.tls = tls;
+        }
+        if let Some(tls_certificate) = self.tls_certificate {
+            opt.tls_certificate = tls_certificate;
+        }
+        if let Some(tls_private_key) = self.tls_private_key {
+            opt.tls_private_key = tls_private_key;
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            opt.max_file_size = max_file_size;
+        }
+        if let Some(upload_expiration) = self.upload_expiration {
+            opt.upload_expiration = upload_expiration;
+        }
+        if let Some(max_message_size) = self.max_message_size {
+            opt.max_message_size = max_message_size;
+        }
+        if let Some(max_request_size) = self.max_request_size {
+            opt.max_request_size = max_request_size;
+        }
+        if let Some(max_messages_per_minute) = self.max_messages_per_minute {
+            opt.max_messages_per_minute = max_messages_per_minute;
+        }
+        if let Some(max_requests_per_address) = self.max_requests_per_address {
+            opt.max_requests_per_address = max_requests_per_address;
+        }
+        if let Some(address_block_duration) = self.address_block_duration {
+            opt.address_block_duration = address_block_duration;
+        }
+        if let Some(trusted_proxy_header) = self.trusted_proxy_header {
+            opt.trusted_proxy_header = Some(trusted_proxy_header);
+        }
+        if let Some(trusted_proxies) = self.trusted_proxies {
+            let mut addresses = Vec::with_capacity(trusted_proxies.len());
+            for trusted_proxy in trusted_proxies {
+                match options::parse_host(&trusted_proxy) {
+                    Ok(address) => addresses.push(address),
+                    Err(_) => {
+                        return Err(format!(
+                            "Invalid trusted proxy: {}. Expected an IP address.",
+                            trusted_proxy
+                        ))
+                    }
+                }
+            }
+            opt.trusted_proxies = addresses;
+        }
+        if let Some(max_timestamp_skew) = self.max_timestamp_skew {
+            opt.max_timestamp_skew = max_timestamp_skew;
+        }
+        if let Some(db_pool_size) = self.db_pool_size {
+            opt.db_pool_size = db_pool_size;
+        }
+        if let Some(db_connection_timeout) = self.db_connection_timeout {
+            opt.db_connection_timeout = db_connection_timeout;
+        }
+        if let Some(db_startup_retries) = self.db_startup_retries {
+            opt.db_startup_retries = db_startup_retries;
+        }
+        if let Some(read_replica_dir) = self.read_replica_dir {
+            opt.read_replica_dir = Some(read_replica_dir);
+        }
+        if let Some(active_member_window) = self.active_member_window {
+            opt.active_member_window = active_member_window;
+        }
+        if let Some(duplicate_message_window) = self.duplicate_message_window {
+            opt.duplicate_message_window = duplicate_message_window;
+        }
+        if let Some(message_restore_window) = self.message_restore_window {
+            opt.message_restore_window = message_restore_window;
+        }
+        if let Some(message_retention_period) = self.message_retention_period {
+            opt.message_retention_period = message_retention_period;
+        }
+        if let Some(message_retention_sweep_interval) = self.message_retention_sweep_interval {
+            opt.message_retention_sweep_interval = message_retention_sweep_interval;
+        }
+        if let Some(poll_interval) = self.poll_interval {
+            opt.poll_interval = poll_interval;
+        }
+        if let Some(poll_interval_load_threshold) = self.poll_interval_load_threshold {
+            opt.poll_interval_load_threshold = poll_interval_load_threshold;
+        }
+        if let Some(max_page_size) = self.max_page_size {
+            opt.max_page_size = max_page_size;
+        }
+        if let Some(default_page_size) = self.default_page_size {
+            opt.default_page_size = default_page_size;
+        }
+        if let Some(max_pinned_messages) = self.max_pinned_messages {
+            opt.max_pinned_messages = max_pinned_messages;
+        }
+        if let Some(welcome_message) = self.welcome_message {
+            opt.welcome_message = Some(welcome_message);
+        }
+        if let Some(content_filter_file) = self.content_filter_file {
+            opt.content_filter_file = Some(content_filter_file);
+        }
+        if let Some(file_scanner) = self.file_scanner {
+            opt.file_scanner = file_scanner;
+        }
+        if let Some(allowed_file_types) = self.allowed_file_types {
+            opt.allowed_file_types = allowed_file_types;
+        }
+        if let Some(require_auth_for_reads) = self.require_auth_for_reads {
+            opt.require_auth_for_reads = require_auth_for_reads;
+        }
+        if let Some(allow_list_mode) = self.allow_list_mode {
+            opt.allow_list_mode = allow_list_mode;
+        }
+        if let Some(admin_token_hash) = self.admin_token_hash {
+            opt.admin_token_hash = Some(admin_token_hash);
+        }
+        if let Some(cors_allowed_origins) = self.cors_allowed_origins {
+            opt.cors_allowed_origins = cors_allowed_origins;
+        }
+        return Ok(());
+    }
+}
+
+/// Returns the names of the optional features that are enabled by the given settings.
+pub fn get_capabilities(opt: &Opt) -> Vec<String> {
+    return CAPABILITIES
+        .iter()
+        .filter(|(_, is_enabled)| is_enabled(opt))
+        .map(|(capability, _)| capability.to_string())
+        .collect();
+}
+
+/// Checks that the given settings make sense, regardless of whether they came from the command
+/// line or from a configuration file.
+pub fn validate(opt: &Opt) -> Result<(), String> {
+    if opt.port == 0 {
+        return Err("Invalid port: 0.".to_string());
+    }
+    if opt.db_pool_size == 0 {
+        return Err("Invalid database pool size: 0. Expected at least 1.".to_string());
+    }
+    if opt.max_file_size == 0 {
+        return Err("Invalid maximum file size: 0. Expected at least 1 byte.".to_string());
+    }
+    if opt.upload_expiration == 0 {
+        return Err("Invalid upload expiration: 0. Expected at least 1 second.".to_string());
+    }
+    if opt.max_message_size == 0 {
+        return Err("Invalid maximum message size: 0. Expected at least 1 byte.".to_string());
+    }
+    if opt.max_request_size == 0 {
+        return Err("Invalid maximum request size: 0. Expected at least 1 byte.".to_string());
+    }
+    if opt.max_messages_per_minute == 0 {
+        return Err("Invalid maximum messages per minute: 0. Expected at least 1.".to_string());
+    }
+    if let Some(read_replica_dir) = &opt.read_replica_dir {
+        if !std::path::Path::new(read_replica_dir).is_dir() {
+            return Err(format!("Invalid read replica directory: {}.", read_replica_dir));
+        }
+    }
+    if opt.max_requests_per_address > 0 && opt.address_block_duration == 0 {
+        return Err("Invalid address block duration: 0. Expected at least 1 second.".to_string());
+    }
+    if let Some(header) = &opt.trusted_proxy_header {
+        if warp::http::header::HeaderName::from_bytes(header.as_bytes()).is_err() {
+            return Err(format!("Invalid trusted proxy header: {}.", header));
+        }
+        // Otherwise anyone could pick their own address by setting the header
+        if opt.trusted_proxies.is_empty() {
+            return Err(
+                "Missing trusted proxies. Expected --trusted-proxies to be set along with \
+                --trusted-proxy-header."
+                    .to_string(),
+            );
+        }
+    }
+    if opt.max_page_size == 0 {
+        return Err("Invalid maximum page size: 0. Expected at least 1.".to_string());
+    }
+    if opt.default_page_size == 0 {
+        return Err("Invalid default page size: 0. Expected at least 1.".to_string());
+    }
+    if let Some(admin_token_hash) = &opt.admin_token_hash {
+        if !crypto::is_valid_admin_token_hash(admin_token_hash) {
+            return Err(
+                "Invalid admin token hash. Expected the output of --hash-admin-token.".to_string()
+            );
+        }
+    }
+    if opt.welcome_message.as_deref() == Some("") {
+        return Err("Invalid welcome message: it's empty.".to_string());
+    }
+    return Ok(());
+}
@@ -0,0 +1,72 @@
+use std::fs;
+
+use log::info;
+use parking_lot::RwLock;
+use regex::{RegexSet, RegexSetBuilder};
+
+lazy_static::lazy_static! {
+
+    pub static ref CONTENT_FILTER: RwLock<ContentFilter> = RwLock::new(ContentFilter::empty());
+}
+
+/// A list of disallowed patterns that messages are checked against before they're stored.
+///
+/// Every pattern is a regular expression, so plain words match anywhere in a message. Matching
+/// ignores case.
+pub struct ContentFilter {
+    patterns: RegexSet,
+}
+
+impl ContentFilter {
+    /// A filter that doesn't disallow anything.
+    pub fn empty() -> ContentFilter {
+        return ContentFilter { patterns: RegexSet::empty() };
+    }
+
+    /// Creates a filter from the given patterns. The error describes what's wrong in a way that
+    /// can be shown to the server operator as is.
+    pub fn new(patterns: &[&str]) -> Result<ContentFilter, String> {
+        match RegexSetBuilder::new(patterns).case_insensitive(true).build() {
+            Ok(patterns) => return Ok(ContentFilter { patterns }),
+            Err(e) => return Err(format!("Couldn't parse content filter due to error: {}.", e)),
+        }
+    }
+
+    /// Reads the patterns from the file at `path`, one per line. Empty lines and lines starting
+    /// with `#` are ignored.
+    pub fn load(path: &str) -> Result<ContentFilter, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Err(format!(
+                    "Couldn't read content filter file: {} due to error: {}.",
+                    path, e
+                ))
+            }
+        };
+        let patterns: Vec<&str> = contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        return ContentFilter::new(&patterns);
+    }
+
+    /// Returns whether `text` doesn't match any of the disallowed patterns.
+    pub fn is_allowed(&self, text: &str) -> bool {
+        return !self.patterns.is_match(text);
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        return self.patterns.len();
+    }
+}
+
+/// Replaces the content filter with the patterns in the file at `path`. The current filter is kept
+/// if the file can't be loaded.
+pub fn reload_content_filter(path: &str) -> Result<(), String> {
+    let content_filter = ContentFilter::load(path)?;
+    info!("Loaded {} content filter patterns from: {}.", content_filter.pattern_count(), path);
+    *CONTENT_FILTER.write() = content_filter;
+    return Ok(());
+}
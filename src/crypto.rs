@@ -4,11 +4,16 @@ use std::sync::Mutex;
 
 use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
 use aes_gcm::Aes256Gcm;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
 use hmac::{Hmac, Mac, NewMac};
 use log::{error, warn};
 use rand::{thread_rng, Rng};
 use rand_core::OsRng;
-use sha2::Sha256;
+use scrypt::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use scrypt::Scrypt;
+use sha2::{Digest, Sha256, Sha512};
 
 use super::errors::Error;
 
@@ -20,14 +25,14 @@ type HmacSha256 = Hmac<Sha256>;
 //
 // RUSTFLAGS="-Ctarget-cpu=sandybridge -Ctarget-feature=+aes,+sse2,+sse4.1,+ssse3"
 
-const IV_SIZE: usize = 12;
+pub const IV_SIZE: usize = 12;
 
 lazy_static::lazy_static! {
 
     pub static ref PRIVATE_KEY_PATH: Mutex<String> = Mutex::new("".to_string());
 
     pub static ref PRIVATE_KEY: x25519_dalek::StaticSecret = {
-        let path: &str = &*PRIVATE_KEY_PATH.lock().unwrap();
+        let path: &str = &PRIVATE_KEY_PATH.lock().unwrap();
         let raw_private_key = fs::read_to_string(path).unwrap();
         return curve25519_parser::parse_openssl_25519_privkey(raw_private_key.as_bytes()).unwrap();
     };
@@ -35,7 +40,7 @@ lazy_static::lazy_static! {
     pub static ref PUBLIC_KEY_PATH: Mutex<String> = Mutex::new("".to_string());
 
     pub static ref PUBLIC_KEY: x25519_dalek::PublicKey = {
-        let path: &str = &*PUBLIC_KEY_PATH.lock().unwrap();
+        let path: &str = &PUBLIC_KEY_PATH.lock().unwrap();
         let raw_public_key = fs::read_to_string(path).unwrap();
         return curve25519_parser::parse_openssl_25519_pubkey(raw_public_key.as_bytes()).unwrap();
     };
@@ -45,11 +50,11 @@ pub fn get_x25519_symmetric_key(
     public_key: &[u8], private_key: &x25519_dalek::StaticSecret,
 ) -> Result<Vec<u8>, warp::reject::Rejection> {
     if public_key.len() != 32 {
-        error!(
+        warn!(
             "Couldn't create symmetric key using public key of invalid length: {}.",
             hex::encode(public_key)
         );
-        return Err(warp::reject::custom(Error::DecryptionFailed));
+        return Err(warp::reject::custom(Error::ValidationFailed));
     }
     let public_key: [u8; 32] = public_key.try_into().unwrap(); // Safe because we know it has a length of 32 at this point
     let dalek_public_key = x25519_dalek::PublicKey::from(public_key);
@@ -64,7 +69,7 @@ pub fn encrypt_aes_gcm(
 ) -> Result<Vec<u8>, warp::reject::Rejection> {
     let mut iv = [0u8; IV_SIZE];
     thread_rng().fill(&mut iv[..]);
-    let cipher = Aes256Gcm::new(&GenericArray::from_slice(symmetric_key));
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(symmetric_key));
     match cipher.encrypt(GenericArray::from_slice(&iv), plaintext) {
         Ok(mut ciphertext) => {
             let mut iv_and_ciphertext = iv.to_vec();
@@ -73,7 +78,7 @@ pub fn encrypt_aes_gcm(
         }
         Err(e) => {
             error!("Couldn't encrypt ciphertext due to error: {}.", e);
-            return Err(warp::reject::custom(Error::DecryptionFailed));
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
 }
@@ -83,11 +88,11 @@ pub fn decrypt_aes_gcm(
 ) -> Result<Vec<u8>, warp::reject::Rejection> {
     if iv_and_ciphertext.len() < IV_SIZE {
         warn!("Ignoring ciphertext of invalid size: {}.", iv_and_ciphertext.len());
-        return Err(warp::reject::custom(Error::DecryptionFailed));
+        return Err(warp::reject::custom(Error::ValidationFailed));
     }
     let iv: [u8; IV_SIZE] = iv_and_ciphertext[0..IV_SIZE].try_into().unwrap(); // Safe because we know iv_and_ciphertext has a length of at least IV_SIZE bytes
-    let ciphertext: Vec<u8> = iv_and_ciphertext[IV_SIZE..].try_into().unwrap(); // Safe because we know iv_and_ciphertext has a length of at least IV_SIZE bytes
-    let cipher = Aes256Gcm::new(&GenericArray::from_slice(symmetric_key));
+    let ciphertext: Vec<u8> = iv_and_ciphertext[IV_SIZE..].into(); // Safe because we know iv_and_ciphertext has a length of at least IV_SIZE bytes
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(symmetric_key));
     match cipher.decrypt(GenericArray::from_slice(&iv), &*ciphertext) {
         Ok(plaintext) => return Ok(plaintext),
         Err(e) => {
@@ -102,3 +107,75 @@ pub fn generate_x25519_key_pair() -> (x25519_dalek::StaticSecret, x25519_dalek::
     let public_key = x25519_dalek::PublicKey::from(&private_key);
     return (private_key, public_key);
 }
+
+/// Checks that `public_key` is a hex encoded Session public key, i.e. a version byte followed
+/// by 32 bytes of key data.
+pub fn is_valid_public_key(public_key: &str) -> bool {
+    // Check that it's the right length
+    if public_key.len() != 66 {
+        return false;
+    }
+    // Check that it's a valid hex encoding
+    return public_key.chars().all(|c| c.is_ascii_hexdigit());
+}
+
+/// Verifies that `signature` is a valid XEdDSA signature over `message` for the X25519 `public_key`.
+///
+/// Session clients sign messages with their X25519 key pair by converting it to an Ed25519 key
+/// pair. Because the X25519 public key doesn't encode the sign of the corresponding Ed25519
+/// public key, clients store it in the otherwise unused top bit of the signature.
+pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    if public_key.len() != 32 || signature.len() != 64 {
+        return false;
+    }
+    // Convert the X25519 public key into an Ed25519 public key
+    let public_key: [u8; 32] = public_key.try_into().unwrap(); // Safe because we know it has a length of 32 at this point
+    let sign = (signature[63] & 0x80) >> 7;
+    let ed25519_public_key = match MontgomeryPoint(public_key).to_edwards(sign) {
+        Some(point) => point,
+        None => return false,
+    };
+    // Deconstruct the signature
+    let r: [u8; 32] = signature[0..32].try_into().unwrap(); // Safe because we know the signature has a length of 64 at this point
+    let mut s: [u8; 32] = signature[32..64].try_into().unwrap(); // Safe because we know the signature has a length of 64 at this point
+    s[31] &= 0x7F;
+    let s = match Scalar::from_canonical_bytes(s) {
+        Some(s) => s,
+        None => return false,
+    };
+    // Check that R == sB - hA, where h = SHA512(R || A || M)
+    let h = Scalar::from_hash(
+        Sha512::new().chain(r).chain(ed25519_public_key.compress().as_bytes()).chain(message),
+    );
+    let expected_r =
+        EdwardsPoint::vartime_double_scalar_mul_basepoint(&h, &(-ed25519_public_key), &s);
+    return expected_r.compress() == CompressedEdwardsY(r);
+}
+
+/// Hashes `admin_token` with scrypt and a random salt into a PHC string (`$scrypt$...`) that can be
+/// stored in the server's configuration instead of the token itself.
+pub fn hash_admin_token(admin_token: &str) -> String {
+    let salt: [u8; 16] = thread_rng().gen();
+    // Safe because 16 bytes is within the allowed salt length
+    let salt = SaltString::b64_encode(&salt).unwrap();
+    // Safe because the default parameters are valid
+    return Scrypt.hash_password_simple(admin_token.as_bytes(), salt.as_ref()).unwrap().to_string();
+}
+
+/// Checks that `admin_token_hash` is a scrypt hash as produced by `hash_admin_token`.
+pub fn is_valid_admin_token_hash(admin_token_hash: &str) -> bool {
+    return match PasswordHash::new(admin_token_hash) {
+        Ok(hash) => hash.algorithm == scrypt::ALG_ID && hash.salt.is_some() && hash.hash.is_some(),
+        Err(_) => false,
+    };
+}
+
+/// Checks that `admin_token` matches `admin_token_hash`. The hashes are compared in constant time,
+/// so the response time doesn't reveal how much of a guess was right.
+pub fn verify_admin_token(admin_token: &str, admin_token_hash: &str) -> bool {
+    let hash = match PasswordHash::new(admin_token_hash) {
+        Ok(hash) => hash,
+        Err(_) => return false,
+    };
+    return Scrypt.verify_password(admin_token.as_bytes(), &hash).is_ok();
+}
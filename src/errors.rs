@@ -1,38 +1,313 @@
+use serde::Serialize;
+use warp::http::header::{HeaderValue, ALLOW, RETRY_AFTER};
 use warp::{http::StatusCode, reply::Reply, reply::Response, Rejection};
 
+/// The HTTP methods supported by RPC calls, as listed in the `Allow` header of 405 responses.
+pub const ALLOWED_METHODS: &str = "GET, HEAD, POST, DELETE, PUT, PATCH";
+
 #[derive(Debug)]
 pub enum Error {
+    /// The message matches one of the patterns of the content filter.
+    ContentFiltered,
+    /// The onion request couldn't be decrypted, e.g. because it wasn't encrypted for this server's
+    /// public key.
     DecryptionFailed,
     DatabaseFailedInternally,
+    /// No database connection became available within the configured timeout.
+    DatabaseUnavailable,
+    /// The requesting user was put in a cooldown by a moderator, so they can't post until it
+    /// expires. Contains the number of seconds until it does.
+    InCooldown(u64),
+    /// The server management request didn't include the admin token (or included the wrong one).
+    InvalidAdminToken,
+    /// The request body couldn't be parsed.
+    InvalidBody,
+    /// The onion request payload is malformed, or the decrypted payload isn't a valid RPC call.
     InvalidOnionRequest,
-    /// Usually this means the endpoint or HTTP method specified in the RPC call was malformed.
+    /// A required query parameter is missing or one of the query parameters has an invalid value.
+    InvalidQuery,
+    /// A field of the message in the request body is malformed. Contains a description that names
+    /// the field.
+    InvalidMessage(&'static str),
+    /// One of the messages in a batch of messages is invalid. Contains the index of the message and
+    /// a description of what's wrong with it.
+    InvalidBatchMessage(usize, &'static str),
+    /// Usually this means the endpoint specified in the RPC call was malformed.
     InvalidRpcCall,
+    /// The signature of a message doesn't match its content and sender.
+    InvalidSignature,
+    /// The endpoint specified in the RPC call isn't a valid URI.
+    InvalidUri,
+    /// The invite has been redeemed the maximum number of times.
+    InviteExhausted,
+    /// The invite has expired.
+    InviteExpired,
+    /// The server is in maintenance mode, so only reads are possible.
+    Maintenance,
+    /// The HTTP method specified in the RPC call isn't supported, either at all or by the endpoint.
+    MethodNotAllowed,
+    /// The message exceeds the maximum message length of the room. Contains the maximum length.
+    MessageTooLong(u32),
+    /// The requesting user has been muted, so they can read messages but can't send any.
+    Muted,
     /// The requesting user didn't provide an auth token for a route that requires one.
     NoAuthToken,
+    /// Reads require auth and the requesting user didn't provide a valid auth token (or is banned).
+    NoReadAuthorization,
+    /// The endpoint specified in the RPC call doesn't exist (or isn't used in the current mode).
+    NoSuchEndpoint,
+    /// The public key specified in the RPC call isn't banned (or the ban has expired).
+    NoSuchBan,
+    /// The invite specified in the RPC call doesn't exist (or has been revoked).
+    NoSuchInvite,
+    /// The message specified in the RPC call doesn't exist (or has been deleted).
+    NoSuchMessage,
+    /// The user with the public key specified in the RPC call hasn't set a profile.
+    NoSuchProfile,
     NoSuchRoom,
-    RateLimited,
+    /// The resumable upload specified in the RPC call doesn't exist (or has expired, or was
+    /// started by someone else).
+    NoSuchUpload,
+    /// The room is invite only and the requesting user hasn't redeemed an invite.
+    NotInvited,
+    /// The requesting user tried to edit a message they didn't send.
+    NotMessageSender,
+    /// The room already has the configured maximum number of pinned messages.
+    PinLimitReached,
+    /// The message was deleted too long ago to be restored.
+    RestoreWindowExpired,
+    /// The requesting user already has the maximum number of resumable uploads in progress.
+    TooManyUploads,
+    /// The uploaded file or message exceeds the configured maximum size.
+    PayloadTooLarge,
+    /// The requesting user has to wait before trying again. Contains the number of seconds to wait.
+    RateLimited(u64),
     /// The requesting user provided a valid auth token, but they don't have a high enough permission level.
     Unauthorized,
+    /// The file scanner doesn't allow files with the declared content type.
+    UnsupportedFileType,
+    /// The onion request uses a version of the onion request protocol this server doesn't speak.
+    UnsupportedProtocolVersion,
+    /// The resumable upload can't be completed because not every byte of the file was received.
+    UploadIncomplete,
     ValidationFailed,
 }
 impl warp::reject::Reject for Error {}
 
+impl Error {
+    /// A short description of the error that's included in error responses.
+    #[rustfmt::skip]
+    pub fn message(&self) -> &'static str {
+        match self {
+            Error::ContentFiltered => return "The message contains disallowed content.",
+            Error::DecryptionFailed => return "Couldn't decrypt the request.",
+            Error::DatabaseFailedInternally => return "An internal database error occurred.",
+            Error::DatabaseUnavailable => return "The database is temporarily unavailable.",
+            Error::InCooldown(_) => return "You've been put in a cooldown; try again later.",
+            Error::InvalidAdminToken => return "Missing or invalid admin token.",
+            Error::InvalidBody => return "Couldn't parse the request body.",
+            Error::InvalidOnionRequest => return "Couldn't parse the onion request.",
+            Error::InvalidQuery => return "Missing or invalid query parameter.",
+            Error::InvalidMessage(description) => return description,
+            Error::InvalidBatchMessage(_, description) => return description,
+            Error::InvalidRpcCall => return "Invalid endpoint.",
+            Error::InvalidSignature => return "Invalid message signature.",
+            Error::InvalidUri => return "Couldn't parse the endpoint.",
+            Error::InviteExhausted => return "The invite has already been used.",
+            Error::InviteExpired => return "The invite has expired.",
+            Error::Maintenance => return "The server is in maintenance mode; only reads are possible.",
+            Error::MessageTooLong(_) => return "The message is longer than this room allows.",
+            Error::MethodNotAllowed => return "Unsupported HTTP method.",
+            Error::Muted => return "You've been muted.",
+            Error::NoAuthToken => return "Missing auth token.",
+            Error::NoReadAuthorization => return "A valid auth token is required to read from this server.",
+            Error::NoSuchBan => return "No such ban.",
+            Error::NoSuchEndpoint => return "No such endpoint.",
+            Error::NoSuchInvite => return "No such invite.",
+            Error::NoSuchMessage => return "No such message.",
+            Error::NoSuchProfile => return "No such profile.",
+            Error::NoSuchRoom => return "No such room.",
+            Error::NoSuchUpload => return "No such upload.",
+            Error::NotInvited => return "An invite is required to post in this room.",
+            Error::NotMessageSender => return "Only the sender of a message can edit it.",
+            Error::PinLimitReached => return "The maximum number of pinned messages has been reached.",
+            Error::PayloadTooLarge => return "The payload is too large.",
+            Error::RateLimited(_) => return "Too many requests.",
+            Error::RestoreWindowExpired => return "The message can no longer be restored.",
+            Error::TooManyUploads => return "Too many uploads in progress.",
+            Error::Unauthorized => return "Unauthorized.",
+            Error::UnsupportedFileType => return "Files of this type aren't allowed on this server.",
+            Error::UnsupportedProtocolVersion => return "Unsupported onion request protocol version.",
+            Error::UploadIncomplete => return "Not every chunk of the file has been uploaded.",
+            Error::ValidationFailed => return "Validation failed."
+        };
+    }
+
+    /// A machine readable code that's included in error responses. Every error has its own code,
+    /// and clients can rely on these not changing.
+    #[rustfmt::skip]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::ContentFiltered => return "content_filtered",
+            Error::DecryptionFailed => return "decryption_failed",
+            Error::DatabaseFailedInternally => return "internal_error",
+            Error::DatabaseUnavailable => return "database_unavailable",
+            Error::InCooldown(_) => return "in_cooldown",
+            Error::InvalidAdminToken => return "invalid_admin_token",
+            Error::InvalidBody => return "invalid_body",
+            Error::InvalidOnionRequest => return "invalid_onion_request",
+            Error::InvalidQuery => return "invalid_query",
+            Error::InvalidMessage(_) => return "invalid_message",
+            Error::InvalidBatchMessage(_, _) => return "invalid_batch_message",
+            Error::InvalidRpcCall => return "invalid_rpc_call",
+            Error::InvalidSignature => return "invalid_signature",
+            Error::InvalidUri => return "invalid_uri",
+            Error::InviteExhausted => return "invite_exhausted",
+            Error::InviteExpired => return "invite_expired",
+            Error::Maintenance => return "maintenance",
+            Error::MessageTooLong(_) => return "message_too_long",
+            Error::MethodNotAllowed => return "method_not_allowed",
+            Error::Muted => return "muted",
+            Error::NoAuthToken => return "no_auth_token",
+            Error::NoReadAuthorization => return "no_read_authorization",
+            Error::NoSuchBan => return "no_such_ban",
+            Error::NoSuchEndpoint => return "no_such_endpoint",
+            Error::NoSuchInvite => return "no_such_invite",
+            Error::NoSuchMessage => return "no_such_message",
+            Error::NoSuchProfile => return "no_such_profile",
+            Error::NoSuchRoom => return "no_such_room",
+            Error::NoSuchUpload => return "no_such_upload",
+            Error::NotInvited => return "not_invited",
+            Error::NotMessageSender => return "not_message_sender",
+            Error::PinLimitReached => return "pin_limit_reached",
+            Error::PayloadTooLarge => return "payload_too_large",
+            Error::RateLimited(_) => return "rate_limited",
+            Error::RestoreWindowExpired => return "restore_window_expired",
+            Error::TooManyUploads => return "too_many_uploads",
+            Error::Unauthorized => return "unauthorized",
+            Error::UnsupportedFileType => return "unsupported_file_type",
+            Error::UnsupportedProtocolVersion => return "unsupported_protocol_version",
+            Error::UploadIncomplete => return "upload_incomplete",
+            Error::ValidationFailed => return "validation_failed"
+        };
+    }
+}
+
 #[rustfmt::skip]
 pub fn status_code(e: Rejection) -> StatusCode {
     if let Some(error) = e.find::<Error>() {
         match error {
-            Error::DecryptionFailed | Error::InvalidOnionRequest | Error::InvalidRpcCall 
-                | Error::NoSuchRoom | Error::ValidationFailed => return StatusCode::BAD_REQUEST,
-            Error::NoAuthToken => return StatusCode::UNAUTHORIZED,
-            Error::RateLimited => return StatusCode::TOO_MANY_REQUESTS,
-            Error::Unauthorized => return StatusCode::FORBIDDEN,
+            Error::InvalidBatchMessage(_, _) | Error::InvalidBody | Error::InvalidMessage(_) 
+                | Error::InvalidOnionRequest | Error::InvalidQuery | Error::InvalidRpcCall | Error::InvalidUri 
+                | Error::ValidationFailed => return StatusCode::BAD_REQUEST,
+            Error::DecryptionFailed | Error::InvalidAdminToken | Error::InvalidSignature | Error::NoAuthToken
+                | Error::NoReadAuthorization | Error::NotMessageSender => return StatusCode::UNAUTHORIZED,
+            Error::NoSuchBan | Error::NoSuchEndpoint | Error::NoSuchInvite | Error::NoSuchMessage 
+                | Error::NoSuchProfile | Error::NoSuchRoom | Error::NoSuchUpload => return StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed => return StatusCode::METHOD_NOT_ALLOWED,
+            Error::UnsupportedFileType => return StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::ContentFiltered => return StatusCode::UNPROCESSABLE_ENTITY,
+            Error::InCooldown(_) | Error::Muted => return StatusCode::LOCKED,
+            Error::PinLimitReached | Error::UploadIncomplete => return StatusCode::CONFLICT,
+            Error::MessageTooLong(_) | Error::PayloadTooLarge => return StatusCode::PAYLOAD_TOO_LARGE,
+            Error::RateLimited(_) | Error::TooManyUploads => return StatusCode::TOO_MANY_REQUESTS,
+            Error::InviteExhausted | Error::InviteExpired
+                | Error::RestoreWindowExpired => return StatusCode::GONE,
+            Error::NotInvited | Error::Unauthorized => return StatusCode::FORBIDDEN,
+            Error::DatabaseUnavailable | Error::Maintenance => return StatusCode::SERVICE_UNAVAILABLE,
+            Error::UnsupportedProtocolVersion => return StatusCode::NOT_IMPLEMENTED,
             Error::DatabaseFailedInternally => return StatusCode::INTERNAL_SERVER_ERROR
         };
+    } else if let Some((status_code, _, _)) = describe_warp_rejection(&e) {
+        return status_code;
     } else {
         return StatusCode::INTERNAL_SERVER_ERROR;
     }
 }
 
+/// Returns the status code, code and description for rejections produced by warp's own filters,
+/// e.g. because a route didn't match or a content length limit was exceeded before the body was
+/// read.
+#[rustfmt::skip]
+fn describe_warp_rejection(e: &Rejection) -> Option<(StatusCode, &'static str, &'static str)> {
+    let describe = |status_code: StatusCode, error: Error| Some((status_code, error.code(), error.message()));
+    if e.is_not_found() {
+        return describe(StatusCode::NOT_FOUND, Error::NoSuchEndpoint);
+    } else if e.find::<warp::reject::PayloadTooLarge>().is_some() {
+        return describe(StatusCode::PAYLOAD_TOO_LARGE, Error::PayloadTooLarge);
+    } else if e.find::<warp::reject::MethodNotAllowed>().is_some() {
+        return describe(StatusCode::METHOD_NOT_ALLOWED, Error::MethodNotAllowed);
+    } else if e.find::<warp::reject::LengthRequired>().is_some() {
+        return Some((StatusCode::LENGTH_REQUIRED, "length_required", "Missing content length."));
+    } else if e.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        return Some((StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", "Unsupported content type."));
+    } else if e.find::<warp::reject::InvalidQuery>().is_some() {
+        return describe(StatusCode::BAD_REQUEST, Error::InvalidQuery);
+    } else if e.find::<warp::filters::body::BodyDeserializeError>().is_some() {
+        return describe(StatusCode::BAD_REQUEST, Error::InvalidBody);
+    } else if e.find::<warp::reject::MissingHeader>().is_some()
+        || e.find::<warp::reject::InvalidHeader>().is_some() {
+        return Some((StatusCode::BAD_REQUEST, "invalid_header", "Missing or invalid header."));
+    } else {
+        return None;
+    }
+}
+
+/// Turns the given rejection into a JSON response with the appropriate status code and an `error`
+/// containing a machine readable code and a description of the error. Rate limited requests (and
+/// messages from users in a cooldown) also get a `Retry-After` header, which is repeated in the
+/// body because onion requests don't propagate headers. Errors about a message in a batch include
+/// the index of that message, errors about messages that are too long include the maximum length,
+/// and 405 responses list the supported HTTP methods in an `Allow` header.
 pub fn into_response(e: Rejection) -> Result<Response, Rejection> {
-    return Ok(status_code(e).into_response());
+    let (code, message) = match e.find::<Error>() {
+        Some(error) => (error.code(), error.message()),
+        None => match describe_warp_rejection(&e) {
+            Some((_, code, message)) => (code, message),
+            None => ("internal_error", "An internal server error occurred."),
+        },
+    };
+    let retry_after = match e.find::<Error>() {
+        Some(Error::InCooldown(retry_after)) | Some(Error::RateLimited(retry_after)) => {
+            Some(*retry_after)
+        }
+        _ => None,
+    };
+    let index = match e.find::<Error>() {
+        Some(Error::InvalidBatchMessage(index, _)) => Some(*index),
+        _ => None,
+    };
+    let max_length = match e.find::<Error>() {
+        Some(Error::MessageTooLong(max_length)) => Some(*max_length),
+        _ => None,
+    };
+    let status_code = status_code(e);
+    #[derive(Debug, Serialize)]
+    struct ErrorBody {
+        code: &'static str,
+        message: &'static str,
+    }
+    #[derive(Debug, Serialize)]
+    struct Response {
+        status_code: u16,
+        error: ErrorBody,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_length: Option<u32>,
+    }
+    let error = ErrorBody { code, message };
+    let json =
+        Response { status_code: status_code.as_u16(), error, retry_after, index, max_length };
+    let mut response =
+        warp::reply::with_status(warp::reply::json(&json), status_code).into_response();
+    if let Some(retry_after) = retry_after {
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    if status_code == StatusCode::METHOD_NOT_ALLOWED {
+        response.headers_mut().insert(ALLOW, HeaderValue::from_static(ALLOWED_METHODS));
+    }
+    return Ok(response);
 }
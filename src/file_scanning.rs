@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use log::warn;
+use parking_lot::RwLock;
+use warp::Rejection;
+
+use super::errors::Error;
+
+lazy_static::lazy_static! {
+
+    pub static ref FILE_SCANNER: RwLock<Arc<dyn FileScanner>> = RwLock::new(Arc::new(NoOpScanner));
+}
+
+/// The content type of files that are uploaded without one.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+pub type ScanFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Rejection>> + Send + 'a>>;
+
+/// Decides whether an uploaded file can be stored. Files are scanned after they've been decoded,
+/// but before anything is written, so rejected files don't leave anything behind. Scanning is
+/// async so that implementations can hand the file off to e.g. a virus scanner.
+pub trait FileScanner: Send + Sync {
+    /// Resolves to the error the client should get if the file with the given bytes and declared
+    /// content type should be rejected.
+    fn scan<'a>(&'a self, bytes: &'a [u8], content_type: &'a str) -> ScanFuture<'a>;
+}
+
+/// A scanner that accepts every file.
+pub struct NoOpScanner;
+
+impl FileScanner for NoOpScanner {
+    fn scan<'a>(&'a self, _: &'a [u8], _: &'a str) -> ScanFuture<'a> {
+        return Box::pin(async { Ok(()) });
+    }
+}
+
+/// A scanner that only accepts files of which the declared content type is in a list of allowed
+/// content types. Entries like `image/*` allow every subtype. The bytes aren't checked against the
+/// declared content type, because clients usually encrypt attachments before uploading them.
+pub struct MimeAllowListScanner {
+    allowed_content_types: Vec<String>,
+}
+
+impl MimeAllowListScanner {
+    pub fn new(allowed_content_types: &[String]) -> MimeAllowListScanner {
+        let allowed_content_types = allowed_content_types
+            .iter()
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+            .collect();
+        return MimeAllowListScanner { allowed_content_types };
+    }
+
+    pub fn is_allowed(&self, content_type: &str) -> bool {
+        // Parameters such as `; charset=utf-8` don't affect the type of the file
+        let content_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        let top_level_type = match content_type.split_once('/') {
+            Some((top_level_type, subtype))
+                if !top_level_type.is_empty() && !subtype.is_empty() =>
+            {
+                top_level_type
+            }
+            _ => return false,
+        };
+        return self.allowed_content_types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+            Some(allowed_top_level_type) => allowed_top_level_type == top_level_type,
+            None => *allowed == content_type,
+        });
+    }
+}
+
+impl FileScanner for MimeAllowListScanner {
+    fn scan<'a>(&'a self, _: &'a [u8], content_type: &'a str) -> ScanFuture<'a> {
+        return Box::pin(async move {
+            if !self.is_allowed(content_type) {
+                warn!("Rejecting file with disallowed content type: {}.", content_type);
+                return Err(warp::reject::custom(Error::UnsupportedFileType));
+            }
+            return Ok(());
+        });
+    }
+}
+
+/// Creates the scanner with the given name (`none` or `mime-allow-list`). The error describes
+/// what's wrong in a way that can be shown to the server operator as is.
+pub fn create_file_scanner(
+    name: &str, allowed_file_types: &[String],
+) -> Result<Arc<dyn FileScanner>, String> {
+    match name {
+        "none" => return Ok(Arc::new(NoOpScanner)),
+        "mime-allow-list" => {
+            if allowed_file_types.is_empty() {
+                return Err(
+                    "Missing allowed file types. Expected --allowed-file-types to be set along \
+                    with --file-scanner mime-allow-list."
+                        .to_string(),
+                );
+            }
+            let file_scanner = MimeAllowListScanner::new(allowed_file_types);
+            // Session clients don't declare a content type, so this effectively rejects every file
+            // they upload
+            if !file_scanner.is_allowed(DEFAULT_CONTENT_TYPE) {
+                warn!(
+                    "Files uploaded without a content type are treated as {} and will be rejected, \
+                    which includes every file uploaded by current Session clients. Add {} to \
+                    --allowed-file-types to accept them.",
+                    DEFAULT_CONTENT_TYPE, DEFAULT_CONTENT_TYPE
+                );
+            }
+            return Ok(Arc::new(file_scanner));
+        }
+        _ => {
+            return Err(format!(
+                "Invalid file scanner: {}. Expected none or mime-allow-list.",
+                name
+            ))
+        }
+    }
+}
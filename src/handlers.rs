@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::path::Path;
+use std::sync::atomic::Ordering;
 
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use parking_lot::RwLock;
 use rand::{thread_rng, Rng};
 use rusqlite::params;
@@ -11,11 +12,17 @@ use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use warp::{http::StatusCode, reply::Reply, reply::Response, Rejection};
 
+use super::content_filtering;
 use super::crypto;
 use super::errors::Error;
+use super::file_scanning;
+use super::metrics;
 use super::models;
+use super::onion_requests;
+use super::rate_limiting;
 use super::rpc;
 use super::storage;
+use super::websockets;
 
 enum AuthorizationLevel {
     Basic,
@@ -28,11 +35,47 @@ pub struct GenericStringResponse {
     pub result: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileResponse {
+    pub status_code: u16,
+    /// The base64 encoded file.
+    pub result: String,
+    /// The base64 encoded metadata that was stored along with the file, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
 pub const SESSION_VERSION_UPDATE_INTERVAL: i64 = 30 * 60;
+/// The server ID of the welcome message. Stored messages never get this server ID.
+pub const WELCOME_MESSAGE_SERVER_ID: i64 = 0;
+
+const MAX_REPORT_REASON_LENGTH: usize = 1024;
+const MAX_BAN_REASON_LENGTH: usize = 1024;
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 128;
+const MAX_SEARCH_QUERY_LENGTH: usize = 256;
+const MAX_SEARCH_RESULTS: u16 = 64;
+const DEFAULT_ACTIVE_MEMBERS_WINDOW: i64 = 5 * 60;
+const MAX_BULK_MODERATORS: usize = 256;
+const MAX_MESSAGE_BATCH_SIZE: usize = 16;
+const MAX_SEEN_SERVER_IDS: usize = 1024;
+const MAX_FILE_METADATA_SIZE: usize = 1024;
+/// The length of resumable upload IDs in bytes, before they're hex encoded.
+const UPLOAD_ID_LENGTH: usize = 16;
+/// The number of resumable uploads a user can have in progress at once.
+const MAX_UPLOADS_PER_PUBLIC_KEY: i64 = 8;
+/// The number of messages fetched from the database at a time when exporting a room.
+const EXPORT_CHUNK_SIZE: u32 = 256;
+const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+const MAX_AVATAR_LENGTH: usize = 256;
+/// The maximum number of parameters bound to a single query. SQLite's limit is at least 999.
+const MAX_QUERY_PARAMETERS: usize = 999;
 
 lazy_static::lazy_static! {
 
     pub static ref SESSION_VERSIONS: RwLock<HashMap<String, (i64, String)>> = RwLock::new(HashMap::new());
+
+    /// Maps room IDs to room info, so that it doesn't need to be loaded from the database every time.
+    static ref ROOM_INFO_CACHE: RwLock<HashMap<String, models::RoomInfo>> = RwLock::new(HashMap::new());
 }
 
 // Rooms
@@ -41,7 +84,7 @@ lazy_static::lazy_static! {
 pub async fn create_room(room: models::Room) -> Result<Response, Rejection> {
     // Get a connection
     let pool = &storage::MAIN_POOL;
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Insert the room
     let stmt = format!("REPLACE INTO {} (id, name) VALUES (?1, ?2)", storage::MAIN_TABLE);
     match conn.execute(&stmt, params![&room.id, &room.name]) {
@@ -52,7 +95,7 @@ pub async fn create_room(room: models::Room) -> Result<Response, Rejection> {
         }
     }
     // Set up the database
-    storage::create_database_if_needed(&room.id);
+    storage::create_database_if_needed(&room.id)?;
     // Return
     info!("Added room with ID: {}", &room.id);
     let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
@@ -63,7 +106,7 @@ pub async fn create_room(room: models::Room) -> Result<Response, Rejection> {
 pub async fn delete_room(id: String) -> Result<Response, Rejection> {
     // Get a connection
     let pool = &storage::MAIN_POOL;
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Insert the room
     let stmt = format!("DELETE FROM {} WHERE id = (?1)", storage::MAIN_TABLE);
     match conn.execute(&stmt, params![&id]) {
@@ -83,7 +126,7 @@ pub async fn delete_room(id: String) -> Result<Response, Rejection> {
 pub fn get_room(room_id: &str) -> Result<Response, Rejection> {
     // Get a connection
     let pool = &storage::MAIN_POOL;
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Get the room info if possible
     let raw_query = format!("SELECT id, name FROM {} where id = (?1)", storage::MAIN_TABLE);
     let room = match conn.query_row(&raw_query, params![room_id], |row| {
@@ -105,7 +148,7 @@ pub fn get_room(room_id: &str) -> Result<Response, Rejection> {
 pub fn get_all_rooms() -> Result<Response, Rejection> {
     // Get a connection
     let pool = &storage::MAIN_POOL;
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Get the room info if possible
     let raw_query = format!("SELECT id, name FROM {}", storage::MAIN_TABLE);
     let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
@@ -129,28 +172,169 @@ pub fn get_all_rooms() -> Result<Response, Rejection> {
     return Ok(warp::reply::json(&response).into_response());
 }
 
+pub fn get_room_info(
+    room_id: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get the room info
+    let room_info = get_room_info_from_cache_or_database(room_id, pool)?;
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        room_info: models::RoomInfo,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), room_info };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+pub fn update_room_info(
+    room_id: &str, update: models::RoomInfoUpdate, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Validate the update
+    if let Some(max_message_length) = update.max_message_length {
+        let max_message_size = super::MAX_MESSAGE_SIZE.load(Ordering::SeqCst);
+        if max_message_length as usize > max_message_size {
+            warn!("Ignoring invalid maximum message length: {}.", max_message_length);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    }
+    if let Some(max_messages_per_minute) = update.max_messages_per_minute {
+        let server_max_messages_per_minute = super::MAX_MESSAGES_PER_MINUTE.load(Ordering::SeqCst);
+        if max_messages_per_minute > server_max_messages_per_minute {
+            warn!(
+                "Ignoring invalid maximum number of messages per minute: {}.",
+                max_messages_per_minute
+            );
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    }
+    // Get a connection and open a transaction
+    // The transaction is immediate so that concurrent updates of different fields don't undo each
+    // other
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Apply the update to the stored room info rather than the cached one, which might be outdated
+    let mut room_info = get_room_info_from_database(&tx)?;
+    if let Some(name) = update.name {
+        room_info.name = name;
+    }
+    if let Some(description) = update.description {
+        room_info.description = description;
+    }
+    if let Some(image_url) = update.image_url {
+        room_info.image_url = image_url;
+    }
+    if let Some(invite_only) = update.invite_only {
+        room_info.invite_only = invite_only;
+    }
+    if let Some(max_message_length) = update.max_message_length {
+        room_info.max_message_length =
+            if max_message_length == 0 { None } else { Some(max_message_length) };
+    }
+    if let Some(max_messages_per_minute) = update.max_messages_per_minute {
+        room_info.max_messages_per_minute =
+            if max_messages_per_minute == 0 { None } else { Some(max_messages_per_minute) };
+    }
+    // Store the room info
+    let stmt = format!(
+        "REPLACE INTO {} (id, name, description, image_url, invite_only, max_message_length, max_messages_per_minute) VALUES (0, (?1), (?2), (?3), (?4), (?5), (?6))",
+        storage::ROOM_INFO_TABLE
+    );
+    match tx.execute(
+        &stmt,
+        params![
+            &room_info.name,
+            &room_info.description,
+            &room_info.image_url,
+            room_info.invite_only,
+            room_info.max_message_length,
+            room_info.max_messages_per_minute
+        ],
+    ) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't update room info due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Refresh the cache, keeping it locked until the update is committed so that concurrent updates
+    // end up in the cache in the order they were committed in
+    let mut room_info_cache = ROOM_INFO_CACHE.write();
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    room_info_cache.insert(room_id.to_string(), room_info);
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+fn get_room_info_from_cache_or_database(
+    room_id: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<models::RoomInfo, Rejection> {
+    if let Some(room_info) = ROOM_INFO_CACHE.read().get(room_id) {
+        return Ok(room_info.clone());
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let room_info = get_room_info_from_database(&conn)?;
+    // Don't overwrite room info that was updated in the meantime
+    let mut room_info_cache = ROOM_INFO_CACHE.write();
+    return Ok(room_info_cache.entry(room_id.to_string()).or_insert(room_info).clone());
+}
+
+fn get_room_info_from_database(conn: &rusqlite::Connection) -> Result<models::RoomInfo, Rejection> {
+    // Query the database
+    let raw_query = format!(
+        "SELECT name, description, image_url, invite_only, max_message_length, max_messages_per_minute FROM {}",
+        storage::ROOM_INFO_TABLE
+    );
+    let room_info = match conn.query_row(&raw_query, params![], |row| {
+        Ok(models::RoomInfo {
+            name: row.get(0)?,
+            description: row.get(1)?,
+            image_url: row.get(2)?,
+            invite_only: row.get(3)?,
+            max_message_length: row.get(4)?,
+            max_messages_per_minute: row.get(5)?,
+        })
+    }) {
+        Ok(room_info) => room_info,
+        Err(rusqlite::Error::QueryReturnedNoRows) => models::RoomInfo::default(),
+        Err(e) => {
+            error!("Couldn't get room info due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    return Ok(room_info);
+}
+
 // Files
 
+/// Stores the given file. The optional `base64_encoded_metadata` (e.g. the parameters needed to
+/// decrypt the file) is opaque to the server and is returned as is when the file is retrieved. The
+/// file is only stored if the configured file scanner accepts it given its `content_type`.
 pub async fn store_file(
-    room_id: Option<String>, base64_encoded_bytes: &str, auth_token: Option<String>,
-    pool: &storage::DatabaseConnectionPool,
+    room_id: Option<String>, base64_encoded_bytes: &str, base64_encoded_metadata: Option<&str>,
+    content_type: Option<&str>, auth_token: Option<String>, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    // It'd be nice to use the UUID crate for the file ID, but clients want an integer ID
-    const UPPER_BOUND: u64 = 2u64.pow(53); // JS has trouble if we go higher than this
-    let id: u64 = thread_rng().gen_range(0..UPPER_BOUND);
+    let id = generate_file_id();
     let now = chrono::Utc::now().timestamp();
     // Check authorization level if needed
-    match rpc::MODE {
-        rpc::Mode::OpenGroupServer => {
-            let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
-            let (has_authorization_level, _) =
-                has_authorization_level(&auth_token, AuthorizationLevel::Basic, pool)?;
-            if !has_authorization_level {
-                return Err(warp::reject::custom(Error::Unauthorized));
-            }
-        }
-        rpc::Mode::FileServer => { /* Do nothing */ }
-    }
+    check_file_authorization(auth_token, pool)?;
     // Parse bytes
     let bytes = match base64::decode(base64_encoded_bytes) {
         Ok(bytes) => bytes,
@@ -159,14 +343,29 @@ pub async fn store_file(
             return Err(warp::reject::custom(Error::ValidationFailed));
         }
     };
+    // Check the file size
+    let max_file_size = super::MAX_FILE_SIZE.load(Ordering::SeqCst);
+    if bytes.len() > max_file_size {
+        warn!("Rejecting file of size {} bytes (maximum is {} bytes).", bytes.len(), max_file_size);
+        return Err(warp::reject::custom(Error::PayloadTooLarge));
+    }
+    // Parse the metadata if there is any
+    let metadata = parse_file_metadata(base64_encoded_metadata)?;
+    // Scan the file
+    // The scanner is cloned so that the lock isn't held while scanning
+    let file_scanner = file_scanning::FILE_SCANNER.read().clone();
+    file_scanner.scan(&bytes, content_type.unwrap_or(file_scanning::DEFAULT_CONTENT_TYPE)).await?;
     // Update the database
     // We do this * before * storing the actual file, so that in case something goes
     // wrong we're not left with files that'll never be pruned.
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // INSERT rather than REPLACE so that on the off chance there's already a file with this exact
     // id (i.e. timestamp) we simply error out and get the client to retry.
-    let stmt = format!("INSERT INTO {} (id, timestamp) VALUES (?1, ?2)", storage::FILES_TABLE);
-    let _ = match conn.execute(&stmt, params![id.to_string(), now]) {
+    let stmt = format!(
+        "INSERT INTO {} (id, timestamp, metadata) VALUES (?1, ?2, ?3)",
+        storage::FILES_TABLE
+    );
+    let _ = match conn.execute(&stmt, params![id.to_string(), now, metadata]) {
         Ok(rows) => rows,
         Err(e) => {
             error!("Couldn't insert file record due to error: {}.", e);
@@ -204,141 +403,580 @@ pub async fn store_file(
     return Ok(warp::reply::json(&response).into_response());
 }
 
-pub async fn get_file(
-    room_id: Option<String>, id: u64, auth_token: Option<String>,
-    pool: &storage::DatabaseConnectionPool,
-) -> Result<GenericStringResponse, Rejection> {
-    // Doesn't return a response directly for testing purposes
-    // Check authorization level if needed
+fn generate_file_id() -> u64 {
+    // It'd be nice to use the UUID crate for the file ID, but clients want an integer ID
+    const UPPER_BOUND: u64 = 2u64.pow(53); // JS has trouble if we go higher than this
+    return thread_rng().gen_range(0..UPPER_BOUND);
+}
+
+/// Checks that the requesting user can upload files, which in file server mode is everyone.
+/// Returns their public key in open group server mode.
+fn check_file_authorization(
+    auth_token: Option<String>, pool: &storage::DatabaseConnectionPool,
+) -> Result<Option<String>, Rejection> {
     match rpc::MODE {
         rpc::Mode::OpenGroupServer => {
             let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
-            let (has_authorization_level, _) =
+            let (has_authorization_level, requesting_public_key) =
                 has_authorization_level(&auth_token, AuthorizationLevel::Basic, pool)?;
             if !has_authorization_level {
                 return Err(warp::reject::custom(Error::Unauthorized));
             }
+            return Ok(Some(requesting_public_key));
         }
-        rpc::Mode::FileServer => { /* Do nothing */ }
+        rpc::Mode::FileServer => return Ok(None),
     }
-    // Try to read the file
-    let mut bytes = vec![];
-    // room_id is guaranteed to be present at this point because we checked the auth
-    // token (the auth token will have been rejected if room_id is missing).
-    let raw_path = format!("files/{}_files/{}", room_id.unwrap(), id);
-    let path = Path::new(&raw_path);
-    let mut file = match File::open(path).await {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Couldn't read file due to error: {}.", e);
+}
+
+fn parse_file_metadata(
+    base64_encoded_metadata: Option<&str>,
+) -> Result<Option<Vec<u8>>, Rejection> {
+    let metadata: Option<Vec<u8>> = match base64_encoded_metadata.map(base64::decode) {
+        Some(Ok(metadata)) => Some(metadata),
+        Some(Err(e)) => {
+            warn!("Couldn't parse file metadata from invalid base64 encoding due to error: {}.", e);
             return Err(warp::reject::custom(Error::ValidationFailed));
         }
+        None => None,
     };
-    match file.read_to_end(&mut bytes).await {
+    if let Some(metadata) = &metadata {
+        if metadata.len() > MAX_FILE_METADATA_SIZE {
+            warn!(
+                "Rejecting file metadata of size {} bytes (maximum is {} bytes).",
+                metadata.len(),
+                MAX_FILE_METADATA_SIZE
+            );
+            return Err(warp::reject::custom(Error::PayloadTooLarge));
+        }
+    }
+    return Ok(metadata);
+}
+
+/// A resumable upload that hasn't been completed yet.
+struct Upload {
+    size: u64,
+    metadata: Option<Vec<u8>>,
+    content_type: Option<String>,
+}
+
+/// Starts a resumable upload of a file of the given size. The file is then uploaded in chunks
+/// using `store_upload_chunk`, in any order, and stored using `complete_upload`.
+pub fn init_upload(
+    size: u64, base64_encoded_metadata: Option<&str>, content_type: Option<&str>,
+    auth_token: Option<String>, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level if needed
+    let requesting_public_key = check_file_authorization(auth_token, pool)?;
+    // Check the file size
+    let max_file_size = super::MAX_FILE_SIZE.load(Ordering::SeqCst);
+    if size == 0 {
+        warn!("Ignoring resumable upload of an empty file.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    if size > max_file_size as u64 {
+        warn!("Rejecting file of size {} bytes (maximum is {} bytes).", size, max_file_size);
+        return Err(warp::reject::custom(Error::PayloadTooLarge));
+    }
+    // Parse the metadata if there is any
+    let metadata = parse_file_metadata(base64_encoded_metadata)?;
+    // Update the database
+    let upload_id = hex::encode(thread_rng().gen::<[u8; UPLOAD_ID_LENGTH]>());
+    let now = chrono::Utc::now().timestamp();
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // The count and the insert happen in the same immediate transaction so that concurrent
+    // requests can't exceed the limit
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    if let Some(public_key) = &requesting_public_key {
+        let raw_query =
+            format!("SELECT COUNT(*) FROM {} WHERE public_key = (?1)", storage::UPLOADS_TABLE);
+        let upload_count: i64 = tx
+            .query_row(&raw_query, params![public_key], |row| row.get(0))
+            .map_err(|_| Error::DatabaseFailedInternally)?;
+        if upload_count >= MAX_UPLOADS_PER_PUBLIC_KEY {
+            warn!(
+                "Ignoring resumable upload from: {} with too many uploads in progress.",
+                public_key
+            );
+            return Err(warp::reject::custom(Error::TooManyUploads));
+        }
+    }
+    let stmt = format!(
+        "INSERT INTO {} (id, public_key, size, metadata, content_type, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        storage::UPLOADS_TABLE
+    );
+    match tx.execute(
+        &stmt,
+        params![upload_id, requesting_public_key, size as i64, metadata, content_type, now],
+    ) {
         Ok(_) => (),
         Err(e) => {
-            error!("Couldn't read file due to error: {}.", e);
-            return Err(warp::reject::custom(Error::ValidationFailed));
+            error!("Couldn't insert upload record due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
-    // Base64 encode the result
-    let base64_encoded_bytes = base64::encode(bytes);
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
     // Return
-    let json = GenericStringResponse {
-        status_code: StatusCode::OK.as_u16(),
-        result: base64_encoded_bytes,
-    };
-    return Ok(json);
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        upload_id: String,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), upload_id };
+    return Ok(warp::reply::json(&response).into_response());
 }
 
-pub async fn get_group_image(room_id: &str) -> Result<Response, Rejection> {
-    // Try to read the file
-    let mut bytes = vec![];
-    let raw_path = format!("files/{}", room_id);
-    let path = Path::new(&raw_path);
-    let mut file = match File::open(path).await {
-        Ok(file) => file,
+/// Writes the given chunk of a resumable upload to disk, starting at `offset`. Chunks can be
+/// uploaded in any order, and uploading a chunk again (e.g. because the response to the first
+/// attempt never arrived) overwrites it.
+pub async fn store_upload_chunk(
+    room_id: Option<String>, upload_id: &str, offset: u64, base64_encoded_chunk: &str,
+    auth_token: Option<String>, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level if needed
+    let requesting_public_key = check_file_authorization(auth_token, pool)?;
+    // Get the upload
+    let upload = get_upload(upload_id, requesting_public_key.as_deref(), pool)?;
+    // Parse bytes
+    let bytes = match base64::decode(base64_encoded_chunk) {
+        Ok(bytes) => bytes,
         Err(e) => {
-            error!("Couldn't read file due to error: {}.", e);
+            warn!("Couldn't parse chunk from invalid base64 encoding due to error: {}.", e);
             return Err(warp::reject::custom(Error::ValidationFailed));
         }
     };
-    match file.read_to_end(&mut bytes).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Couldn't read file due to error: {}.", e);
+    // Check that the chunk is part of the file
+    let end = match offset.checked_add(bytes.len() as u64) {
+        Some(end) if !bytes.is_empty() && end <= upload.size => end,
+        _ => {
+            warn!(
+                "Ignoring chunk of {} bytes at offset {} of a file of {} bytes.",
+                bytes.len(),
+                offset,
+                upload.size
+            );
             return Err(warp::reject::custom(Error::ValidationFailed));
         }
     };
-    // Base64 encode the result
-    let base64_encoded_bytes = base64::encode(bytes);
+    // Get a connection and open a transaction
+    // The upload is checked, the chunk is written and the received byte ranges are updated in the
+    // same immediate transaction. This way chunks stored concurrently don't overwrite each
+    // other's ranges, and a chunk is never written after the upload was completed or pruned
+    // concurrently, which would leave the file behind.
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Receiving a chunk keeps the upload from expiring
+    let stmt = format!("UPDATE {} SET updated_at = (?1) WHERE id = (?2)", storage::UPLOADS_TABLE);
+    let now = chrono::Utc::now().timestamp();
+    let updated_count =
+        tx.execute(&stmt, params![now, upload_id]).map_err(|_| Error::DatabaseFailedInternally)?;
+    if updated_count == 0 {
+        return Err(warp::reject::custom(Error::NoSuchUpload));
+    }
+    // Write to file
+    // This happens * before * recording the chunk, so that chunks are never reported as received
+    // when they weren't stored. Chunks are written at their offset rather than appended, which
+    // makes it safe to upload several chunks at once. The file is written synchronously because
+    // the transaction can't be held across an await.
+    // room_id is guaranteed to be present at this point because we checked the auth
+    // token (the auth token will have been rejected if room_id is missing).
+    let room_id = room_id.unwrap();
+    let _ = std::fs::create_dir_all(format!("files/{}_uploads", &room_id));
+    let raw_path = format!("files/{}_uploads/{}", &room_id, upload_id);
+    let result = (|| {
+        use std::io::{Seek, Write};
+        let mut file =
+            std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(&raw_path)?;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        file.write_all(&bytes)?;
+        return file.flush();
+    })();
+    if let Err(e) = result {
+        error!("Couldn't store chunk due to error: {}.", e);
+        return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+    }
+    // Update the database
+    let mut received = get_received_byte_ranges(upload_id, &tx)?;
+    received.push(models::ByteRange { start: offset, end });
+    let received = merge_byte_ranges(received);
+    let stmt = format!("DELETE FROM {} WHERE upload_id = (?1)", storage::UPLOAD_CHUNKS_TABLE);
+    tx.execute(&stmt, params![upload_id]).map_err(|_| Error::DatabaseFailedInternally)?;
+    let stmt = format!(
+        "INSERT INTO {} (upload_id, start_offset, end_offset) VALUES (?1, ?2, ?3)",
+        storage::UPLOAD_CHUNKS_TABLE
+    );
+    for range in &received {
+        tx.execute(&stmt, params![upload_id, range.start as i64, range.end as i64])
+            .map_err(|_| Error::DatabaseFailedInternally)?;
+    }
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
     // Return
-    let json = GenericStringResponse {
-        status_code: StatusCode::OK.as_u16(),
-        result: base64_encoded_bytes,
-    };
-    return Ok(warp::reply::json(&json).into_response());
+    return Ok(get_upload_progress_response(upload.size, received));
 }
 
-pub async fn set_group_image(
-    base64_encoded_bytes: &str, room_id: &str, auth_token: &str,
+/// Returns which byte ranges of a resumable upload have been received, so that a client that lost
+/// track (e.g. because it was restarted) knows where to resume.
+pub fn get_upload_progress(
+    upload_id: &str, auth_token: Option<String>, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level if needed
+    let requesting_public_key = check_file_authorization(auth_token, pool)?;
+    // Get the upload
+    let upload = get_upload(upload_id, requesting_public_key.as_deref(), pool)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let received = get_received_byte_ranges(upload_id, &conn)?;
+    // Return
+    return Ok(get_upload_progress_response(upload.size, received));
+}
+
+/// Stores a resumable upload of which every byte has been received as a regular file. The file is
+/// scanned at this point, because that requires the whole file.
+pub async fn complete_upload(
+    room_id: Option<String>, upload_id: &str, auth_token: Option<String>,
     pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    // Check authorization level
-    let (has_authorization_level, _) =
-        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
-    if !has_authorization_level {
-        return Err(warp::reject::custom(Error::Unauthorized));
+    // Check authorization level if needed
+    let requesting_public_key = check_file_authorization(auth_token, pool)?;
+    // Get the upload
+    let upload = get_upload(upload_id, requesting_public_key.as_deref(), pool)?;
+    let received = {
+        let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+        get_received_byte_ranges(upload_id, &conn)?
+    };
+    if received != vec![models::ByteRange { start: 0, end: upload.size }] {
+        warn!(
+            "Ignoring attempt to complete upload: {} before every chunk was received.",
+            upload_id
+        );
+        return Err(warp::reject::custom(Error::UploadIncomplete));
     }
-    // Parse bytes
-    let bytes = match base64::decode(base64_encoded_bytes) {
+    // Read the file
+    // room_id is guaranteed to be present at this point because we checked the auth
+    // token (the auth token will have been rejected if room_id is missing).
+    let room_id = room_id.unwrap();
+    let upload_path = format!("files/{}_uploads/{}", &room_id, upload_id);
+    let bytes = match tokio::fs::read(&upload_path).await {
         Ok(bytes) => bytes,
         Err(e) => {
-            error!("Couldn't parse bytes from invalid base64 encoding due to error: {}.", e);
-            return Err(warp::reject::custom(Error::ValidationFailed));
-        }
-    };
-    // Write to file
-    let raw_path = format!("files/{}", room_id);
-    let path = Path::new(&raw_path);
-    let mut file = match File::create(path).await {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Couldn't set group image due to error: {}.", e);
+            error!("Couldn't read upload due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
-    match file.write_all(&bytes).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Couldn't set group image due to error: {}.", e);
-            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+    // Scan the file
+    // Rejected files are discarded right away, since uploading them again won't help
+    let file_scanner = file_scanning::FILE_SCANNER.read().clone();
+    let content_type =
+        upload.content_type.as_deref().unwrap_or(file_scanning::DEFAULT_CONTENT_TYPE);
+    if let Err(e) = file_scanner.scan(&bytes, content_type).await {
+        delete_upload(&room_id, upload_id, pool)?;
+        return Err(e);
+    }
+    // Update the database
+    // The upload record is deleted in the same immediate transaction that inserts the file
+    // record, so that if the upload is completed more than once at the same time only one of the
+    // attempts stores a file. The file record is inserted * before * moving the file into place,
+    // so that in case something goes wrong we're not left with files that'll never be pruned.
+    let id = generate_file_id();
+    let now = chrono::Utc::now().timestamp();
+    {
+        let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+        let tx = conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .map_err(|_| Error::DatabaseFailedInternally)?;
+        let stmt = format!("DELETE FROM {} WHERE id = (?1)", storage::UPLOADS_TABLE);
+        let deleted_count =
+            tx.execute(&stmt, params![upload_id]).map_err(|_| Error::DatabaseFailedInternally)?;
+        if deleted_count == 0 {
+            warn!("Ignoring attempt to complete upload: {} that was already completed.", upload_id);
+            return Err(warp::reject::custom(Error::NoSuchUpload));
         }
-    };
+        let stmt = format!("DELETE FROM {} WHERE upload_id = (?1)", storage::UPLOAD_CHUNKS_TABLE);
+        tx.execute(&stmt, params![upload_id]).map_err(|_| Error::DatabaseFailedInternally)?;
+        let stmt = format!(
+            "INSERT INTO {} (id, timestamp, metadata) VALUES (?1, ?2, ?3)",
+            storage::FILES_TABLE
+        );
+        match tx.execute(&stmt, params![id.to_string(), now, upload.metadata]) {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Couldn't insert file record due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    }
+    // Move the file into place
+    let _ = std::fs::create_dir_all(format!("files/{}_files", &room_id));
+    let raw_path = format!("files/{}_files/{}", &room_id, &id);
+    if let Err(e) = tokio::fs::rename(&upload_path, &raw_path).await {
+        error!("Couldn't store file due to error: {}.", e);
+        return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+    }
     // Return
     #[derive(Debug, Deserialize, Serialize)]
     struct Response {
         status_code: u16,
-        room_id: String,
+        result: u64,
     }
-    let response = Response { status_code: StatusCode::OK.as_u16(), room_id: room_id.to_string() };
+    let response = Response { status_code: StatusCode::OK.as_u16(), result: id };
     return Ok(warp::reply::json(&response).into_response());
 }
 
-// Authentication
-
-pub fn get_auth_token_challenge(
-    query_params: HashMap<String, String>, pool: &storage::DatabaseConnectionPool,
-) -> Result<models::Challenge, Rejection> {
-    // Doesn't return a response directly for testing purposes
-    // Get the public key
-    let hex_public_key = query_params
-        .get("public_key")
-        .ok_or_else(|| warp::reject::custom(Error::InvalidRpcCall))?;
-    // Validate the public key
-    if !is_valid_public_key(hex_public_key) {
-        warn!("Ignoring challenge request for invalid public key: {}.", hex_public_key);
-        return Err(warp::reject::custom(Error::ValidationFailed));
+/// Merges byte ranges that overlap or touch, and sorts the result.
+pub fn merge_byte_ranges(mut ranges: Vec<models::ByteRange>) -> Vec<models::ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<models::ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    return merged;
+}
+
+/// Gets the resumable upload with the given ID. Uploads can only be continued by whoever started
+/// them; to everyone else they don't exist.
+fn get_upload(
+    upload_id: &str, requesting_public_key: Option<&str>, pool: &storage::DatabaseConnectionPool,
+) -> Result<Upload, Rejection> {
+    // The ID ends up in a file path, so anything but a valid ID is rejected up front
+    let is_valid_upload_id = upload_id.len() == 2 * UPLOAD_ID_LENGTH
+        && upload_id.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    if !is_valid_upload_id {
+        warn!("Ignoring RPC call with invalid upload ID: {}.", upload_id);
+        return Err(warp::reject::custom(Error::NoSuchUpload));
+    }
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let raw_query = format!(
+        "SELECT public_key, size, metadata, content_type FROM {} WHERE id = (?1)",
+        storage::UPLOADS_TABLE
+    );
+    let result = conn.query_row(&raw_query, params![upload_id], |row| {
+        let public_key: Option<String> = row.get(0)?;
+        let size: i64 = row.get(1)?;
+        let upload = Upload { size: size as u64, metadata: row.get(2)?, content_type: row.get(3)? };
+        return Ok((public_key, upload));
+    });
+    match result {
+        Ok((public_key, upload)) if public_key.as_deref() == requesting_public_key => {
+            return Ok(upload)
+        }
+        Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(warp::reject::custom(Error::NoSuchUpload))
+        }
+        Err(e) => {
+            error!("Couldn't get upload due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+}
+
+fn get_received_byte_ranges(
+    upload_id: &str, conn: &rusqlite::Connection,
+) -> Result<Vec<models::ByteRange>, Rejection> {
+    let raw_query = format!(
+        "SELECT start_offset, end_offset FROM {} WHERE upload_id = (?1) ORDER BY start_offset",
+        storage::UPLOAD_CHUNKS_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![upload_id], |row| {
+        let start: i64 = row.get(0)?;
+        let end: i64 = row.get(1)?;
+        Ok(models::ByteRange { start: start as u64, end: end as u64 })
+    }) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get received byte ranges due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    return Ok(rows.filter_map(|result| result.ok()).collect());
+}
+
+fn get_upload_progress_response(size: u64, received: Vec<models::ByteRange>) -> Response {
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        size: u64,
+        received_bytes: u64,
+        received: Vec<models::ByteRange>,
+    }
+    let received_bytes = received.iter().map(|range| range.end - range.start).sum();
+    let response =
+        Response { status_code: StatusCode::OK.as_u16(), size, received_bytes, received };
+    return warp::reply::json(&response).into_response();
+}
+
+/// Discards a resumable upload along with what was received of it so far.
+fn delete_upload(
+    room_id: &str, upload_id: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<(), Rejection> {
+    // The records are removed first, so that no chunk can be stored for the upload afterwards (see
+    // `store_upload_chunk`)
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    let stmt = format!("DELETE FROM {} WHERE upload_id = (?1)", storage::UPLOAD_CHUNKS_TABLE);
+    tx.execute(&stmt, params![upload_id]).map_err(|_| Error::DatabaseFailedInternally)?;
+    let stmt = format!("DELETE FROM {} WHERE id = (?1)", storage::UPLOADS_TABLE);
+    tx.execute(&stmt, params![upload_id]).map_err(|_| Error::DatabaseFailedInternally)?;
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    let _ = std::fs::remove_file(format!("files/{}_uploads/{}", room_id, upload_id));
+    return Ok(());
+}
+
+pub async fn get_file(
+    room_id: Option<String>, id: u64, auth_token: Option<String>,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<FileResponse, Rejection> {
+    // Doesn't return a response directly for testing purposes
+    // Check authorization level if needed
+    match rpc::MODE {
+        rpc::Mode::OpenGroupServer => {
+            let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
+            let (has_authorization_level, _) =
+                has_authorization_level(&auth_token, AuthorizationLevel::Basic, pool)?;
+            if !has_authorization_level {
+                return Err(warp::reject::custom(Error::Unauthorized));
+            }
+        }
+        rpc::Mode::FileServer => { /* Do nothing */ }
+    }
+    // Try to read the file
+    let mut bytes = vec![];
+    // room_id is guaranteed to be present at this point because we checked the auth
+    // token (the auth token will have been rejected if room_id is missing).
+    let raw_path = format!("files/{}_files/{}", room_id.unwrap(), id);
+    let path = Path::new(&raw_path);
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Couldn't read file due to error: {}.", e);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    };
+    match file.read_to_end(&mut bytes).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't read file due to error: {}.", e);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    };
+    // Get the metadata
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let raw_query = format!("SELECT metadata FROM {} WHERE id = (?1)", storage::FILES_TABLE);
+    let metadata: Option<Vec<u8>> =
+        match conn.query_row(&raw_query, params![id.to_string()], |row| row.get(0)) {
+            Ok(metadata) => metadata,
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => {
+                error!("Couldn't get file metadata due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    // Base64 encode the result
+    let base64_encoded_bytes = base64::encode(bytes);
+    // Return
+    let json = FileResponse {
+        status_code: StatusCode::OK.as_u16(),
+        result: base64_encoded_bytes,
+        metadata: metadata.map(base64::encode),
+    };
+    return Ok(json);
+}
+
+pub async fn get_group_image(room_id: &str) -> Result<Response, Rejection> {
+    // Try to read the file
+    let mut bytes = vec![];
+    let raw_path = format!("files/{}", room_id);
+    let path = Path::new(&raw_path);
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Couldn't read file due to error: {}.", e);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    };
+    match file.read_to_end(&mut bytes).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't read file due to error: {}.", e);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    };
+    // Base64 encode the result
+    let base64_encoded_bytes = base64::encode(bytes);
+    // Return
+    let json = GenericStringResponse {
+        status_code: StatusCode::OK.as_u16(),
+        result: base64_encoded_bytes,
+    };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+pub async fn set_group_image(
+    base64_encoded_bytes: &str, room_id: &str, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Parse bytes
+    let bytes = match base64::decode(base64_encoded_bytes) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Couldn't parse bytes from invalid base64 encoding due to error: {}.", e);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    };
+    // Write to file
+    let raw_path = format!("files/{}", room_id);
+    let path = Path::new(&raw_path);
+    let mut file = match File::create(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Couldn't set group image due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    match file.write_all(&bytes).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't set group image due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        room_id: String,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), room_id: room_id.to_string() };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+// Authentication
+
+pub fn get_auth_token_challenge(
+    query_params: HashMap<String, String>, pool: &storage::DatabaseConnectionPool,
+) -> Result<models::Challenge, Rejection> {
+    // Doesn't return a response directly for testing purposes
+    // Get the public key
+    let hex_public_key =
+        query_params.get("public_key").ok_or_else(|| warp::reject::custom(Error::InvalidQuery))?;
+    // Validate the public key
+    if !crypto::is_valid_public_key(hex_public_key) {
+        warn!("Ignoring challenge request for invalid public key: {}.", hex_public_key);
+        return Err(warp::reject::custom(Error::ValidationFailed));
     }
     // Convert the public key to bytes and cut off the version byte
     // This is safe because we know it has a length of 32 at this point
@@ -348,18 +986,17 @@ pub fn get_auth_token_challenge(
     // Generate a symmetric key from the requesting user's public key and the ephemeral private key
     let symmetric_key = crypto::get_x25519_symmetric_key(&public_key, &ephemeral_private_key)?;
     // Generate a random token (or get the currently pending one if possible)
-    let pending_tokens = get_pending_tokens(&hex_public_key, &pool)?;
-    let token: Vec<u8>;
-    if !pending_tokens.is_empty() {
-        token = pending_tokens[0].1.clone();
+    let pending_tokens = get_pending_tokens(hex_public_key, pool)?;
+    let token: Vec<u8> = if !pending_tokens.is_empty() {
+        pending_tokens[0].1.clone()
     } else {
         let mut buffer = [0u8; 48];
         thread_rng().fill(&mut buffer[..]);
-        token = buffer.to_vec();
-    }
+        buffer.to_vec()
+    };
     // Store the (pending) token
     // Note that a given public key can have multiple pending tokens
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     let now = chrono::Utc::now().timestamp();
     let stmt = format!(
         "INSERT INTO {} (public_key, timestamp, token) VALUES (?1, ?2, ?3)",
@@ -385,7 +1022,7 @@ pub fn claim_auth_token(
     public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
     // Validate the public key
-    if !is_valid_public_key(&public_key) {
+    if !crypto::is_valid_public_key(public_key) {
         warn!("Ignoring claim token request for invalid public key.");
         return Err(warp::reject::custom(Error::ValidationFailed));
     }
@@ -395,9 +1032,9 @@ pub fn claim_auth_token(
         return Err(warp::reject::custom(Error::ValidationFailed));
     }
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Get the pending tokens for the given public key
-    let pending_tokens = get_pending_tokens(&public_key, &pool)?;
+    let pending_tokens = get_pending_tokens(public_key, pool)?;
     // Check that the token being claimed is in fact one of the pending tokens
     let claim = hex::decode(auth_token).unwrap(); // Safe because we validated it above
     let index = pending_tokens
@@ -439,7 +1076,7 @@ pub fn delete_auth_token(
         return Err(warp::reject::custom(Error::Unauthorized));
     }
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Delete the token
     let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::TOKENS_TABLE);
     match conn.execute(&stmt, params![requesting_public_key]) {
@@ -456,14 +1093,26 @@ pub fn delete_auth_token(
 
 // Message sending & receiving
 
-/// Inserts the given `message` into the database if it's valid.
+/// Inserts the given `message` into the database if it's valid, and forwards it to anyone that's
+/// subscribed to the room with the given `room_id`.
+///
+/// If the message has an idempotency key that the requesting user already used for a message within
+/// the last `storage::IDEMPOTENCY_KEY_EXPIRATION` seconds, that message is returned instead. The
+/// same goes for messages that are identical to the requesting user's previous message if that was
+/// sent less than `--duplicate-message-window` seconds ago.
 pub fn insert_message(
-    mut message: models::Message, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+    room_id: &str, mut message: models::Message, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
     // Validate the message
-    if !message.is_valid() {
-        warn!("Ignoring invalid message.");
-        return Err(warp::reject::custom(Error::ValidationFailed));
+    let room_info = get_room_info_from_cache_or_database(room_id, pool)?;
+    check_message(&message, &room_info).map_err(warp::reject::custom)?;
+    let idempotency_key = message.idempotency_key.take();
+    if let Some(idempotency_key) = &idempotency_key {
+        if idempotency_key.is_empty() || idempotency_key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+            warn!("Ignoring message with invalid idempotency key.");
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
     }
     // Check authorization level
     let (has_authorization_level, requesting_public_key) =
@@ -471,35 +1120,97 @@ pub fn insert_message(
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
+    // Check that the message was signed by the requesting user
+    if !has_valid_signature(&message, &requesting_public_key) {
+        warn!("Ignoring message with invalid signature.");
+        return Err(warp::reject::custom(Error::InvalidSignature));
+    }
     // Get a timestamp
     let timestamp = chrono::Utc::now().timestamp_millis();
     // Get a connection and open a transaction
-    let mut conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Check if the requesting user needs to be rate limited
-    let last_5_messages = get_last_5_messages(&requesting_public_key, pool)?;
-    let should_rate_limit: bool;
-    if last_5_messages.len() == 5 {
-        let interval = timestamp - last_5_messages[4].timestamp;
-        // Rate limit if the interval between the fifth last message and the current timestamp is
-        // less than 16 seconds; in other words, the user can send 5 messages every 16 seconds. This
-        // is a very crude way of rate limiting, but it should be sufficient for now.
-        should_rate_limit = interval < 16 * 1000;
-    } else {
-        should_rate_limit = false;
+    // The transaction is immediate so that concurrent retries can't both miss the idempotency key
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Check that the requesting user isn't muted
+    if is_muted(&requesting_public_key, &tx)? {
+        warn!("Ignoring message from muted user.");
+        return Err(warp::reject::custom(Error::Muted));
+    }
+    // Check that the requesting user isn't in a cooldown
+    if let Some(remaining) = get_cooldown_remaining(&requesting_public_key, &tx)? {
+        warn!("Ignoring message from user in cooldown.");
+        return Err(warp::reject::custom(Error::InCooldown(remaining)));
+    }
+    // Check that the requesting user is allowed to post if the room is invite only
+    if requires_invite(&room_info)
+        && !is_allowed(&requesting_public_key, &tx)?
+        && !is_moderator_with_connection(&requesting_public_key, &tx)?
+    {
+        warn!("Ignoring message from user without an invite.");
+        return Err(warp::reject::custom(Error::NotInvited));
+    }
+    // Return the original message if this is a retry
+    if let Some(idempotency_key) = &idempotency_key {
+        if let Some(original_message) =
+            get_message_by_idempotency_key(&requesting_public_key, idempotency_key, &tx)?
+        {
+            debug!("Returning original message for retried insert.");
+            return Ok(message_response(original_message));
+        }
+    }
+    // Return the previous message if this is a duplicate of it
+    let duplicate_message_window = super::DUPLICATE_MESSAGE_WINDOW.load(Ordering::SeqCst);
+    if duplicate_message_window > 0 {
+        if let Some(previous_message) = get_duplicate_message(
+            &requesting_public_key,
+            &message,
+            duplicate_message_window,
+            timestamp,
+            &tx,
+        )? {
+            debug!("Returning previous message for duplicate insert.");
+            return Ok(message_response(previous_message));
+        }
     }
-    if should_rate_limit {
-        return Err(warp::reject::custom(Error::RateLimited));
+    // Check that the message being replied to exists
+    if let Some(reply_to) = message.reply_to {
+        let raw_query = format!(
+            "SELECT COUNT(id) FROM {} WHERE id = (?1) AND is_deleted = 0",
+            storage::MESSAGES_TABLE
+        );
+        let count: u32 = match tx.query_row(&raw_query, params![reply_to], |row| row.get(0)) {
+            Ok(count) => count,
+            Err(e) => {
+                error!("Couldn't query database due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        if count == 0 {
+            warn!("Ignoring reply to missing message: {}.", reply_to);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
     }
+    // Check if the requesting user needs to be rate limited
+    check_message_rate_limit(room_id, &room_info, &requesting_public_key, 1)?;
     // Insert the message
     message.timestamp = timestamp;
     let stmt = format!(
-        "INSERT INTO {} (public_key, timestamp, data, signature, is_deleted) VALUES (?1, ?2, ?3, ?4, ?5)",
+        "INSERT INTO {} (public_key, timestamp, data, signature, is_deleted, reply_to, search_text) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
         storage::MESSAGES_TABLE
     );
     match tx.execute(
         &stmt,
-        params![&requesting_public_key, message.timestamp, message.data, message.signature, 0],
+        params![
+            &requesting_public_key,
+            message.timestamp,
+            message.data,
+            message.signature,
+            0,
+            message.reply_to,
+            storage::get_search_text(&message.data)
+        ],
     ) {
         Ok(_) => (),
         Err(e) => {
@@ -508,211 +1219,2777 @@ pub fn insert_message(
         }
     }
     let id = tx.last_insert_rowid();
+    // Record the idempotency key if needed
+    // An expired key for the same user might still be around if it wasn't pruned yet
+    if let Some(idempotency_key) = &idempotency_key {
+        let stmt = format!(
+            "REPLACE INTO {} (public_key, key, message_id, timestamp) VALUES (?1, ?2, ?3, ?4)",
+            storage::IDEMPOTENCY_KEYS_TABLE
+        );
+        let now = chrono::Utc::now().timestamp();
+        match tx.execute(&stmt, params![&requesting_public_key, idempotency_key, id, now]) {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Couldn't insert message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        }
+    }
     message.server_id = Some(id);
     message.public_key = Some(requesting_public_key);
     // Commit
     tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    metrics::MESSAGES_INSERTED.inc();
+    // Notify subscribers
+    websockets::publish(room_id, &message);
     // Return
-    #[derive(Debug, Deserialize, Serialize)]
-    struct Response {
-        status_code: u16,
-        message: models::Message,
-    }
-    let response = Response { status_code: StatusCode::OK.as_u16(), message };
-    return Ok(warp::reply::json(&response).into_response());
-}
-
-fn get_last_5_messages(
-    public_key: &str, pool: &storage::DatabaseConnectionPool,
-) -> Result<Vec<models::Message>, Rejection> {
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    let raw_query = format!(
-        "SELECT id, public_key, timestamp, data, signature FROM {} WHERE public_key = (?1) ORDER BY timestamp DESC LIMIT 5",
-        storage::MESSAGES_TABLE
-    );
-    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![public_key], |row| {
-        Ok(models::Message {
-            server_id: row.get(0)?,
-            public_key: row.get(1)?,
-            timestamp: row.get(2)?,
-            data: row.get(3)?,
-            signature: row.get(4)?,
-        })
-    }) {
-        Ok(rows) => rows,
-        Err(e) => {
-            error!("Couldn't get last 5 messages due to error: {}.", e);
-            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
-        }
-    };
-    return Ok(rows.filter_map(|result| result.ok()).collect());
+    return Ok(message_response(message));
 }
 
-/// Returns either the last `limit` messages or all messages since `from_server_id, limited to `limit`.
-pub fn get_messages(
-    query_params: HashMap<String, String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
-) -> Result<Vec<models::Message>, Rejection> {
+/// Inserts the given `messages` in a single transaction, so that either all of them are stored with
+/// consecutive server IDs or none of them are. Every message is validated before anything is
+/// inserted, and the index of the first invalid message is reported if there is one.
+pub fn insert_messages(
+    room_id: &str, messages: Vec<models::Message>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    if messages.is_empty() || messages.len() > MAX_MESSAGE_BATCH_SIZE {
+        warn!("Ignoring batch of {} messages.", messages.len());
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
     // Check authorization level
-    let (has_authorization_level, _) =
+    let (has_authorization_level, requesting_public_key) =
         has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Unwrap query parameters
-    let from_server_id: i64;
-    if let Some(str) = query_params.get("from_server_id") {
-        from_server_id = str.parse().unwrap_or(0);
-    } else {
-        from_server_id = 0;
+    // Validate the messages
+    let room_info = get_room_info_from_cache_or_database(room_id, pool)?;
+    for (index, message) in messages.iter().enumerate() {
+        if let Err(e) = check_message(message, &room_info) {
+            warn!("Ignoring batch with invalid message at index {}.", index);
+            return Err(warp::reject::custom(Error::InvalidBatchMessage(index, e.message())));
+        }
+        if message.idempotency_key.is_some() {
+            warn!("Ignoring batch with idempotency key at index {}.", index);
+            return Err(warp::reject::custom(Error::InvalidBatchMessage(
+                index,
+                "Idempotency keys aren't supported for batches of messages.",
+            )));
+        }
+        if !has_valid_signature(message, &requesting_public_key) {
+            warn!("Ignoring batch with invalid signature at index {}.", index);
+            return Err(warp::reject::custom(Error::InvalidBatchMessage(
+                index,
+                Error::InvalidSignature.message(),
+            )));
+        }
     }
-    let limit: u16; // Never return more than 256 messages at once
-    if let Some(str) = query_params.get("limit") {
-        limit = std::cmp::min(str.parse().unwrap_or(256), 256);
-    } else {
-        limit = 256;
+    // Get a timestamp
+    let timestamp = chrono::Utc::now().timestamp_millis();
+    // Get a connection and open a transaction
+    // The transaction is immediate so that no other messages can be inserted in between
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Check that the requesting user isn't muted
+    if is_muted(&requesting_public_key, &tx)? {
+        warn!("Ignoring messages from muted user.");
+        return Err(warp::reject::custom(Error::Muted));
     }
-    // Query the database
-    let raw_query: String;
-    if query_params.get("from_server_id").is_some() {
-        raw_query = format!("SELECT id, public_key, timestamp, data, signature FROM {} WHERE id > (?1) AND is_deleted = 0 ORDER BY id ASC LIMIT (?2)", storage::MESSAGES_TABLE);
-    } else {
-        raw_query = format!(
-            "SELECT id, public_key, timestamp, data, signature FROM {} WHERE is_deleted = 0 ORDER BY id DESC LIMIT (?2)",
-            storage::MESSAGES_TABLE
-        );
+    // Check that the requesting user isn't in a cooldown
+    if let Some(remaining) = get_cooldown_remaining(&requesting_public_key, &tx)? {
+        warn!("Ignoring messages from user in cooldown.");
+        return Err(warp::reject::custom(Error::InCooldown(remaining)));
     }
-    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![from_server_id, limit], |row| {
-        Ok(models::Message {
-            server_id: row.get(0)?,
-            public_key: row.get(1)?,
-            timestamp: row.get(2)?,
-            data: row.get(3)?,
-            signature: row.get(4)?,
-        })
-    }) {
-        Ok(rows) => rows,
+    // Check that the requesting user is allowed to post if the room is invite only
+    if requires_invite(&room_info)
+        && !is_allowed(&requesting_public_key, &tx)?
+        && !is_moderator_with_connection(&requesting_public_key, &tx)?
+    {
+        warn!("Ignoring messages from user without an invite.");
+        return Err(warp::reject::custom(Error::NotInvited));
+    }
+    // Check that the messages being replied to exist
+    let raw_query = format!(
+        "SELECT COUNT(id) FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    for (index, message) in messages.iter().enumerate() {
+        if let Some(reply_to) = message.reply_to {
+            let count: u32 = match tx.query_row(&raw_query, params![reply_to], |row| row.get(0)) {
+                Ok(count) => count,
+                Err(e) => {
+                    error!("Couldn't query database due to error: {}.", e);
+                    return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+                }
+            };
+            if count == 0 {
+                warn!("Ignoring batch with reply to missing message at index {}.", index);
+                return Err(warp::reject::custom(Error::InvalidBatchMessage(
+                    index,
+                    "The message being replied to doesn't exist.",
+                )));
+            }
+        }
+    }
+    // Check if the requesting user needs to be rate limited
+    // Every message in the batch counts towards the limit
+    check_message_rate_limit(room_id, &room_info, &requesting_public_key, messages.len())?;
+    // Insert the messages
+    // Messages that are duplicates of the previous message (which might be in the same batch) get
+    // the server ID of the previous message rather than being inserted
+    let duplicate_message_window = super::DUPLICATE_MESSAGE_WINDOW.load(Ordering::SeqCst);
+    let stmt = format!(
+        "INSERT INTO {} (public_key, timestamp, data, signature, is_deleted, reply_to, search_text) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        storage::MESSAGES_TABLE
+    );
+    let mut server_ids: Vec<i64> = vec![];
+    let mut inserted_messages: Vec<models::Message> = vec![];
+    for mut message in messages {
+        if duplicate_message_window > 0 {
+            if let Some(previous_message) = get_duplicate_message(
+                &requesting_public_key,
+                &message,
+                duplicate_message_window,
+                timestamp,
+                &tx,
+            )? {
+                debug!("Using previous message for duplicate in batch.");
+                server_ids.extend(previous_message.server_id);
+                continue;
+            }
+        }
+        message.timestamp = timestamp;
+        match tx.execute(
+            &stmt,
+            params![
+                &requesting_public_key,
+                message.timestamp,
+                message.data,
+                message.signature,
+                0,
+                message.reply_to,
+                storage::get_search_text(&message.data)
+            ],
+        ) {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Couldn't insert message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        }
+        let id = tx.last_insert_rowid();
+        message.server_id = Some(id);
+        message.public_key = Some(requesting_public_key.clone());
+        server_ids.push(id);
+        inserted_messages.push(message);
+    }
+    // Commit
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    metrics::MESSAGES_INSERTED.inc_by(inserted_messages.len() as u64);
+    // Notify subscribers
+    for message in &inserted_messages {
+        websockets::publish(room_id, message);
+    }
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        server_ids: Vec<i64>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), server_ids };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+fn message_response(message: models::Message) -> Response {
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        message: models::Message,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), message };
+    return warp::reply::json(&response).into_response();
+}
+
+/// Returns the previous message of the user with the given `public_key` if it has the same content
+/// as `message` and was stored less than `window` seconds before `timestamp`, the time at which the
+/// server is storing `message` (in milliseconds). Stored timestamps are always set by the server
+/// rather than the client, so clients can't get around this by lying about when they sent a
+/// message.
+pub fn get_duplicate_message(
+    public_key: &str, message: &models::Message, window: u64, timestamp: i64,
+    conn: &rusqlite::Connection,
+) -> Result<Option<models::Message>, Rejection> {
+    // Only the most recent message counts, so sending something else in between isn't a duplicate
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to, is_deleted FROM {}
+        WHERE public_key = (?1) ORDER BY id DESC LIMIT 1",
+        storage::MESSAGES_TABLE
+    );
+    let result = conn.query_row(&raw_query, params![public_key], |row| {
+        let previous_message = message_from_row(row)?;
+        let is_deleted: bool = row.get(6)?;
+        Ok((previous_message, is_deleted))
+    });
+    let (previous_message, is_deleted) = match result {
+        Ok(result) => result,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
         Err(e) => {
-            error!("Couldn't get messages due to error: {}.", e);
+            error!("Couldn't query database due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
-    let messages: Vec<models::Message> = rows.filter_map(|result| result.ok()).collect();
-    // Record activity for usage statistics
-    // We want to fail silently if any of this goes wrong
-    match update_usage_statistics(auth_token, pool) {
-        Ok(_) => (),
-        Err(_) => println!("Couldn't update usage stats."),
+    // Timestamps are in milliseconds
+    let window: Option<i64> =
+        window.try_into().ok().and_then(|window: i64| window.checked_mul(1000));
+    let cutoff = timestamp.saturating_sub(window.unwrap_or(i64::MAX));
+    let is_duplicate = !is_deleted
+        && previous_message.timestamp > cutoff
+        && previous_message.data == message.data
+        && previous_message.reply_to == message.reply_to;
+    if !is_duplicate {
+        return Ok(None);
+    }
+    return Ok(Some(previous_message));
+}
+
+fn get_message_by_idempotency_key(
+    public_key: &str, idempotency_key: &str, conn: &rusqlite::Connection,
+) -> Result<Option<models::Message>, Rejection> {
+    let raw_query = format!(
+        "SELECT m.id, m.public_key, m.timestamp, m.data, m.signature, m.reply_to FROM {} k
+        INNER JOIN {} m ON m.id = k.message_id
+        WHERE k.public_key = (?1) AND k.key = (?2) AND k.timestamp > (?3) AND m.is_deleted = 0",
+        storage::IDEMPOTENCY_KEYS_TABLE,
+        storage::MESSAGES_TABLE
+    );
+    let now = chrono::Utc::now().timestamp();
+    let expiration = now - storage::IDEMPOTENCY_KEY_EXPIRATION;
+    match conn.query_row(
+        &raw_query,
+        params![public_key, idempotency_key, expiration],
+        message_from_row,
+    ) {
+        Ok(message) => return Ok(Some(message)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+}
+
+/// Checks that the user with the given `auth_token` is allowed to read from the given room. Anyone
+/// is unless `--require-auth-for-reads` is set.
+pub fn check_read_authorization(
+    room_id: Option<&str>, auth_token: Option<&str>,
+) -> Result<(), Rejection> {
+    if !super::REQUIRE_AUTH_FOR_READS.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    return authorize_reader(room_id, auth_token);
+}
+
+/// Checks that `auth_token` belongs to a user of the given room that isn't banned, regardless of
+/// whether reads require auth.
+pub fn authorize_reader(room_id: Option<&str>, auth_token: Option<&str>) -> Result<(), Rejection> {
+    let (room_id, auth_token) = match (room_id, auth_token) {
+        (Some(room_id), Some(auth_token)) => (room_id, auth_token),
+        _ => return Err(warp::reject::custom(Error::NoReadAuthorization)),
     };
-    // Return the messages
-    return Ok(messages);
+    // Don't silently create a database for a room that doesn't exist
+    if !storage::room_exists(room_id)? {
+        return Err(warp::reject::custom(Error::NoSuchRoom));
+    }
+    let pool = storage::pool_by_room_id(room_id);
+    let public_key = match get_public_key_for_auth_token(auth_token, &pool)? {
+        Some(public_key) => public_key,
+        None => return Err(warp::reject::custom(Error::NoReadAuthorization)),
+    };
+    if is_banned(&public_key, &pool)? {
+        return Err(warp::reject::custom(Error::NoReadAuthorization));
+    }
+    return Ok(());
 }
 
-fn update_usage_statistics(
+/// Checks that the user with the given `auth_token` is allowed to subscribe to new messages.
+pub fn check_subscription_authorization(
     auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<(), Rejection> {
-    let public_key = get_public_key_for_auth_token(auth_token, pool)?;
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    let now = chrono::Utc::now().timestamp();
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    return Ok(());
+}
+
+/// Replaces the content of the message with the given `id` if the requesting user is its sender.
+///
+/// The server ID, sender and timestamp of the original message are preserved.
+pub fn update_message(
+    room_id: &str, id: i64, mut message: models::Message, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the message
+    let room_info = get_room_info_from_cache_or_database(room_id, pool)?;
+    check_message(&message, &room_info).map_err(warp::reject::custom)?;
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Check that the new content was signed by the requesting user
+    if !has_valid_signature(&message, &requesting_public_key) {
+        warn!("Ignoring message with invalid signature.");
+        return Err(warp::reject::custom(Error::InvalidSignature));
+    }
+    // Get a connection and open a transaction
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Check that the requesting user isn't muted
+    if is_muted(&requesting_public_key, &tx)? {
+        warn!("Ignoring message update from muted user.");
+        return Err(warp::reject::custom(Error::Muted));
+    }
+    // Get the sender and timestamp of the original message
+    let raw_query = format!(
+        "SELECT public_key, timestamp FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let (sender, timestamp): (String, i64) =
+        match tx.query_row(&raw_query, params![id], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(info) => info,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(warp::reject::custom(Error::NoSuchMessage))
+            }
+            Err(e) => {
+                error!("Couldn't update message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    // Check that the requesting user is the sender of the message
+    if requesting_public_key != sender {
+        warn!("Ignoring attempt to edit message: {} sent by someone else.", id);
+        return Err(warp::reject::custom(Error::NotMessageSender));
+    }
+    // Update the message
     let stmt = format!(
-        "INSERT OR REPLACE INTO {} (public_key, last_active) VALUES(?1, ?2)",
-        storage::USER_ACTIVITY_TABLE
+        "UPDATE {} SET data = (?1), signature = (?2), search_text = (?3) WHERE id = (?4)",
+        storage::MESSAGES_TABLE
     );
-    conn.execute(&stmt, params![public_key, now]).map_err(|_| Error::DatabaseFailedInternally)?;
+    let search_text = storage::get_search_text(&message.data);
+    match tx.execute(&stmt, params![message.data, message.signature, search_text, id]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't update message due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+    // Commit
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Return
+    message.server_id = Some(id);
+    message.public_key = Some(sender);
+    message.timestamp = timestamp;
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        message: models::Message,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), message };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Checks the content of `message`, in the same way for single messages and batches of messages:
+/// that its fields are valid, that its decoded data doesn't exceed the configured maximum message
+/// size or the maximum message length of the room, and that it doesn't match the content filter.
+/// The data is a serialized protobuf, so the content filter only matches text that's stored
+/// verbatim in it. The signature is checked separately, because that requires knowing the sender.
+fn check_message(message: &models::Message, room_info: &models::RoomInfo) -> Result<(), Error> {
+    if let Err(description) = message.validate() {
+        warn!("Ignoring invalid message: {}", description);
+        return Err(Error::InvalidMessage(description));
+    }
+    // The limits apply to the decoded data rather than to its base64 encoding
+    let data = match base64::decode(&message.data) {
+        Ok(data) => data,
+        Err(_) => {
+            warn!("Ignoring message with invalid base64 encoded data.");
+            return Err(Error::ValidationFailed);
+        }
+    };
+    let max_message_size = super::MAX_MESSAGE_SIZE.load(Ordering::SeqCst);
+    if data.len() > max_message_size {
+        warn!(
+            "Ignoring message of size {} bytes (maximum is {} bytes).",
+            data.len(),
+            max_message_size
+        );
+        return Err(Error::PayloadTooLarge);
+    }
+    if let Some(max_message_length) = room_info.max_message_length {
+        if data.len() > max_message_length as usize {
+            warn!(
+                "Ignoring message of length {} bytes (maximum for this room is {} bytes).",
+                data.len(),
+                max_message_length
+            );
+            return Err(Error::MessageTooLong(max_message_length));
+        }
+    }
+    if !content_filtering::CONTENT_FILTER.read().is_allowed(&String::from_utf8_lossy(&data)) {
+        warn!("Ignoring message that matches the content filter.");
+        return Err(Error::ContentFiltered);
+    }
     return Ok(());
 }
 
-// Message deletion
+fn has_valid_signature(message: &models::Message, public_key: &str) -> bool {
+    // Session public keys are X25519 public keys prefixed with 05
+    let public_key = match hex::decode(public_key) {
+        Ok(public_key) if public_key.len() == 33 => public_key,
+        _ => return false,
+    };
+    // The signature is over the decoded data rather than its base64 encoding
+    let data = match base64::decode(&message.data) {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    let signature = match base64::decode(&message.signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    return crypto::verify_signature(&public_key[1..], &data, &signature);
+}
 
-/// Deletes the messages with the given `ids` from the database, if present.
-pub fn delete_messages(
-    ids: Vec<i64>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+/// Returns the messages whose content contains the `q` query parameter, ignoring case. Pages the same
+/// way `get_messages` does when `from_server_id` is set, and otherwise returns the newest matches.
+///
+/// Message data is a base64 encoded protobuf, so matching happens on the decoded copy of it that's
+/// stored alongside it (see `storage::get_search_text`).
+pub fn search_messages(
+    query_params: HashMap<String, String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Vec<models::Message>, Rejection> {
+    // Validate the search query before doing anything else
+    let search_query = match query_params.get("q") {
+        Some(q) if !q.trim().is_empty() && q.len() <= MAX_SEARCH_QUERY_LENGTH => {
+            q.trim().to_lowercase()
+        }
+        _ => {
+            warn!("Ignoring search request with missing or invalid query.");
+            return Err(warp::reject::custom(Error::InvalidQuery));
+        }
+    };
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unwrap query parameters
+    let from_server_id: Option<i64> =
+        query_params.get("from_server_id").map(|str| str.parse().unwrap_or(0));
+    let limit: u16; // Never return more than MAX_SEARCH_RESULTS messages at once
+    if let Some(str) = query_params.get("limit") {
+        limit = std::cmp::min(str.parse().unwrap_or(MAX_SEARCH_RESULTS), MAX_SEARCH_RESULTS);
+    } else {
+        limit = MAX_SEARCH_RESULTS;
+    }
+    // Query the database
+    let raw_query = match from_server_id {
+        Some(_) => format!(
+            "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE id > (?1) AND is_deleted = 0 AND instr(search_text, (?2)) > 0 ORDER BY id ASC LIMIT (?3)",
+            storage::MESSAGES_TABLE
+        ),
+        None => format!(
+            "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE is_deleted = 0 AND instr(search_text, (?1)) > 0 ORDER BY id DESC LIMIT (?2)",
+            storage::MESSAGES_TABLE
+        ),
+    };
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match from_server_id {
+        Some(from_server_id) => {
+            query.query_map(params![from_server_id, search_query, limit], message_from_row)
+        }
+        None => query.query_map(params![search_query, limit], message_from_row),
+    };
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't search messages due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let messages: Vec<models::Message> = rows.filter_map(|result| result.ok()).collect();
+    // Return
+    return Ok(messages);
+}
+
+/// Returns the message with the given `message_id`, unless it has been deleted.
+pub fn get_message(
+    message_id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    // FIXME: Right now a situation can occur where a non-moderator user selects multiple
-    // messages, some of which are their own and some of which aren't, and then hits this endpoint.
-    // When they do, some of the messages would be deleted but an error status code would be
-    // returned, prompting the client to roll back the deletions they made locally. The only thing
-    // preventing this scenario from occurring right now is that we don't allow users to make such
-    // a selection in the Session UI. In the future we should take a better approach to make it
-    // impossible.
-    for id in ids {
-        delete_message(id, auth_token, pool)?;
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    match conn.query_row(&raw_query, params![message_id], message_from_row) {
+        Ok(message) => return Ok(message_response(message)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(warp::reject::custom(Error::NoSuchMessage))
+        }
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
     }
-    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
-    return Ok(warp::reply::json(&json).into_response());
 }
 
-/// Deletes the message with the given `id` from the database, if it's present.
-pub fn delete_message(
-    id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+/// Returns the message with the given `message_id` along with its direct replies. Replies are paged
+/// forward from `from_server_id` if it's set.
+pub fn get_thread(
+    message_id: i64, query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
     // Check authorization level
-    let (has_authorization_level, requesting_public_key) =
+    let (has_authorization_level, _) =
         has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    // Check that the requesting user is either the sender of the message or a moderator
-    let sender_option: Option<String> = {
-        let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-        let raw_query =
-            format!("SELECT public_key FROM {} WHERE id = (?1)", storage::MESSAGES_TABLE);
-        let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-        let rows = match query.query_map(params![id], |row| row.get(0)) {
-            Ok(rows) => rows,
-            Err(e) => {
-                error!("Couldn't delete message due to error: {}.", e);
-                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
-            }
-        };
-        let public_key = rows.filter_map(|result| result.ok()).next();
-        public_key
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unwrap query parameters
+    let from_server_id: i64 =
+        query_params.get("from_server_id").map(|str| str.parse().unwrap_or(0)).unwrap_or(0);
+    let limit = get_page_size(&query_params);
+    // Get the parent message
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let parent = match conn.query_row(&raw_query, params![message_id], message_from_row) {
+        Ok(parent) => parent,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(warp::reject::custom(Error::NoSuchMessage))
+        }
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
     };
-    let sender =
-        sender_option.ok_or_else(|| warp::reject::custom(Error::DatabaseFailedInternally))?;
-    if !is_moderator(&requesting_public_key, pool)? && requesting_public_key != sender {
+    // Get the replies
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to FROM {}
+        WHERE reply_to = (?1) AND id > (?2) AND is_deleted = 0 ORDER BY id ASC LIMIT (?3)",
+        storage::MESSAGES_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![message_id, from_server_id, limit], message_from_row) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get replies due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let replies: Vec<models::Message> = rows.filter_map(|result| result.ok()).collect();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        message: models::Message,
+        replies: Vec<models::Message>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), message: parent, replies };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Returns the message with the given `message_id` along with up to `before` messages stored before
+/// it and up to `after` messages stored after it, in ascending order of server ID. Both counts are
+/// clamped to the maximum page size.
+pub fn get_message_context(
+    message_id: i64, query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Unwrap query parameters
+    let get_count = |key: &str| -> Result<u16, Rejection> {
+        let max_page_size = super::MAX_PAGE_SIZE.load(Ordering::SeqCst);
+        match query_params.get(key).map(|str| str.parse::<u16>()) {
+            Some(Ok(count)) => return Ok(std::cmp::min(count, max_page_size)),
+            Some(Err(_)) => {
+                warn!("Ignoring message context request with invalid {}.", key);
+                return Err(warp::reject::custom(Error::InvalidQuery));
+            }
+            None => return Ok(0),
+        }
+    };
+    let before = get_count("before")?;
+    let after = get_count("after")?;
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Get the target message
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let message = match conn.query_row(&raw_query, params![message_id], message_from_row) {
+        Ok(message) => message,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(warp::reject::custom(Error::NoSuchMessage))
+        }
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Get the messages around it
+    let get_messages = |condition: &str, order: &str, limit: u16| {
+        let raw_query = format!(
+            "SELECT id, public_key, timestamp, data, signature, reply_to FROM {}
+            WHERE id {} (?1) AND is_deleted = 0 ORDER BY id {} LIMIT (?2)",
+            storage::MESSAGES_TABLE,
+            condition,
+            order
+        );
+        let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+        let rows = match query.query_map(params![message_id, limit], message_from_row) {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Couldn't get message context due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        let messages: Vec<models::Message> = rows.filter_map(|result| result.ok()).collect();
+        return Ok(messages);
+    };
+    let mut messages = get_messages("<", "DESC", before)?;
+    messages.reverse();
+    messages.push(message);
+    messages.append(&mut get_messages(">", "ASC", after)?);
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        messages: Vec<models::Message>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), messages };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Checks that the user with the given `auth_token` is allowed to export the room's messages.
+pub fn check_export_authorization(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<(), Rejection> {
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    return Ok(());
+}
+
+/// Streams all messages after `from_server_id` as newline-delimited JSON, oldest first.
+///
+/// Messages are fetched `EXPORT_CHUNK_SIZE` at a time, so memory use doesn't depend on the size
+/// of the room. If the database fails halfway through the connection is closed without finishing
+/// the body; the server ID of the last line received can then be used to resume.
+pub fn export_messages(from_server_id: i64, pool: storage::DatabaseConnectionPool) -> Response {
+    let chunks = futures::stream::unfold(Some(from_server_id), move |from_server_id| {
+        let pool = pool.clone();
+        async move {
+            let from_server_id = from_server_id?;
+            match get_export_chunk(from_server_id, &pool) {
+                Ok((_, None)) => return None,
+                Ok((chunk, last_server_id)) => return Some((Ok(chunk), last_server_id)),
+                Err(e) => {
+                    let description = format!("Couldn't export messages due to error: {:?}.", e);
+                    error!("{}", description);
+                    let e = std::io::Error::other(description);
+                    return Some((Err(e), None));
+                }
+            }
+        }
+    });
+    let mut response = Response::new(warp::hyper::Body::wrap_stream(chunks));
+    response
+        .headers_mut()
+        .insert("Content-Type", warp::http::HeaderValue::from_static("application/x-ndjson"));
+    return response;
+}
+
+/// Returns up to `EXPORT_CHUNK_SIZE` messages after `from_server_id` with one JSON object per
+/// line, along with the server ID of the last of them (or `None` if there aren't any).
+fn get_export_chunk(
+    from_server_id: i64, pool: &storage::DatabaseConnectionPool,
+) -> Result<(String, Option<i64>), Error> {
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE id > (?1) AND is_deleted = 0 ORDER BY id ASC LIMIT (?2)",
+        storage::MESSAGES_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = query
+        .query_map(params![from_server_id, EXPORT_CHUNK_SIZE], message_from_row)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Serialize the messages
+    let mut chunk = String::new();
+    let mut last_server_id = None;
+    for message in rows.filter_map(|result| result.ok()) {
+        let line = serde_json::to_string(&message).map_err(|_| Error::DatabaseFailedInternally)?;
+        chunk.push_str(&line);
+        chunk.push('\n');
+        last_server_id = message.server_id;
+    }
+    return Ok((chunk, last_server_id));
+}
+
+/// Returns either the last `limit` messages or all messages since `from_server_id, limited to `limit`.
+///
+/// If `to_server_id` is set only messages up to and including that server ID are returned, which
+/// makes it possible to fetch the closed range `(from_server_id, to_server_id]`. `order` can be
+/// either `asc` or `desc` and overrides the default ordering. If `since` is set only messages with
+/// a timestamp (in milliseconds) strictly greater than it are returned. If `include_welcome_message`
+/// is set the welcome message is returned first, if the server has one. Only authorization uses
+/// `pool`; the messages are read from `read_pool`, which can be a read replica (see
+/// `storage::read_pool_by_room_id`).
+pub fn get_messages(
+    query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool, read_pool: &storage::DatabaseConnectionPool,
+) -> Result<Vec<models::Message>, Rejection> {
+    return Ok(get_message_page(query_params, auth_token, pool, read_pool)?.messages);
+}
+
+/// A page of messages as returned by `get_message_page`.
+pub struct MessagePage {
+    pub messages: Vec<models::Message>,
+    /// Whether there are more messages beyond the page in the direction it was fetched in.
+    pub has_more: bool,
+    /// The server ID of the last message in the page (not counting the welcome message), if any.
+    pub next_from_server_id: Option<i64>,
+}
+
+/// Like `get_messages`, but also returns whether there are more messages to page through.
+pub fn get_message_page(
+    query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool, read_pool: &storage::DatabaseConnectionPool,
+) -> Result<MessagePage, Rejection> {
+    // Validate the order before doing anything else
+    let order: Option<&str> = match query_params.get("order").map(|str| str.as_str()) {
+        Some("asc") => Some("ASC"),
+        Some("desc") => Some("DESC"),
+        Some(order) => {
+            warn!("Ignoring get messages request with invalid order: {}.", order);
+            return Err(warp::reject::custom(Error::InvalidQuery));
+        }
+        None => None,
+    };
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = read_pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unwrap query parameters
+    let from_server_id: Option<i64> =
+        query_params.get("from_server_id").map(|str| str.parse().unwrap_or(0));
+    let to_server_id: Option<i64> = parse_query_param(&query_params, "to_server_id")?;
+    let since: Option<i64> = parse_query_param(&query_params, "since")?;
+    let limit = get_page_size(&query_params);
+    // An empty range isn't an error; there just aren't any messages in it
+    if let (Some(from_server_id), Some(to_server_id)) = (from_server_id, to_server_id) {
+        if from_server_id > to_server_id {
+            return Ok(MessagePage {
+                messages: vec![],
+                has_more: false,
+                next_from_server_id: None,
+            });
+        }
+    }
+    // The welcome message counts towards the limit, so that pages never exceed it
+    let welcome_message =
+        if query_params.get("include_welcome_message").map(|str| str.as_str()) == Some("true") {
+            get_welcome_message().filter(|_| limit > 0)
+        } else {
+            None
+        };
+    let limit = if welcome_message.is_some() { limit - 1 } else { limit };
+    // Build the WHERE clause based on whichever bounds are present
+    let mut conditions: Vec<&str> = vec![];
+    if from_server_id.is_some() {
+        conditions.push("id > (?1)");
+    }
+    if to_server_id.is_some() {
+        conditions.push("id <= (?2)");
+    }
+    if since.is_some() {
+        // Messages without a timestamp never match, because comparisons with NULL are never true
+        conditions.push("timestamp > (?3)");
+    }
+    conditions.push("is_deleted = 0");
+    // By default page forward from `from_server_id` if it's set; otherwise return the newest messages
+    let default_order = if from_server_id.is_some() { "ASC" } else { "DESC" };
+    let order = order.unwrap_or(default_order);
+    // Query the database
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE {} ORDER BY id {} LIMIT (?4)",
+        storage::MESSAGES_TABLE,
+        conditions.join(" AND "),
+        order
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    // Get one message more than requested to find out whether there are more messages
+    let rows = match query
+        .query_map(params![from_server_id, to_server_id, since, limit as u32 + 1], message_from_row)
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get messages due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let mut messages: Vec<models::Message> = rows.filter_map(|result| result.ok()).collect();
+    let has_more = messages.len() > limit as usize;
+    messages.truncate(limit as usize);
+    let next_from_server_id = messages.last().and_then(|message| message.server_id);
+    // Include reactions if requested
+    if query_params.get("include_reactions").map(|str| str.as_str()) == Some("true") {
+        for message in messages.iter_mut() {
+            if let Some(server_id) = message.server_id {
+                message.reactions = Some(get_reactions(server_id, &conn)?);
+            }
+        }
+    }
+    // Include read counts if requested
+    if query_params.get("include_read_counts").map(|str| str.as_str()) == Some("true") {
+        let server_ids: Vec<i64> =
+            messages.iter().filter_map(|message| message.server_id).collect();
+        let read_counts = get_read_counts(&server_ids, &conn)?;
+        for message in messages.iter_mut() {
+            if let Some(server_id) = message.server_id {
+                message.read_count = Some(read_counts.get(&server_id).copied().unwrap_or(0));
+            }
+        }
+    }
+    // Include display names if requested
+    if query_params.get("include_display_names").map(|str| str.as_str()) == Some("true") {
+        let mut display_names: HashMap<String, Option<String>> = HashMap::new();
+        for message in messages.iter_mut() {
+            if let Some(public_key) = &message.public_key {
+                if !display_names.contains_key(public_key) {
+                    let display_name = get_profile_from_database(public_key, &conn)?
+                        .map(|profile| profile.display_name);
+                    display_names.insert(public_key.clone(), display_name);
+                }
+                message.display_name = display_names[public_key].clone();
+            }
+        }
+    }
+    // Include the welcome message if requested
+    if let Some(welcome_message) = welcome_message {
+        messages.insert(0, welcome_message);
+    }
+    // Return the messages
+    return Ok(MessagePage { messages, has_more, next_from_server_id });
+}
+
+/// Returns the welcome message configured by the server operator as a message, if there is one.
+/// It isn't stored in the database, so it can't be deleted or reacted to.
+fn get_welcome_message() -> Option<models::Message> {
+    let text = super::WELCOME_MESSAGE.read().clone()?;
+    return Some(models::Message {
+        server_id: Some(WELCOME_MESSAGE_SERVER_ID),
+        public_key: None,
+        timestamp: 0,
+        data: base64::encode(text),
+        signature: "".to_string(),
+        reply_to: None,
+        reactions: None,
+        read_count: None,
+        display_name: None,
+        idempotency_key: None,
+    });
+}
+
+/// Returns whether any messages were stored after the message with the given `server_id`. This is
+/// a lot cheaper than getting the messages, so that clients that are already caught up can skip
+/// the work.
+pub fn has_messages_after(
+    server_id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<bool, Rejection> {
+    let last_server_id = get_last_server_id(auth_token, pool)?;
+    // Return
+    return Ok(last_server_id.map(|last_server_id| last_server_id > server_id).unwrap_or(false));
+}
+
+/// Returns the server ID of the most recently stored message, or `None` if the room doesn't have
+/// any messages yet.
+pub fn get_last_server_id(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Option<i64>, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!("SELECT MAX(id) FROM {}", storage::MESSAGES_TABLE);
+    let last_server_id: Option<i64> = match conn.query_row(&raw_query, params![], |row| row.get(0))
+    {
+        Ok(last_server_id) => last_server_id,
+        Err(e) => {
+            error!("Couldn't get last message server ID due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    return Ok(last_server_id);
+}
+
+/// Returns the messages stored after `from_server_id` (the highest server ID up to which the
+/// client has every message), except for the ones in `seen_server_ids`, oldest first. This lets
+/// clients that have gaps in their history fill them without fetching what they already have. Like
+/// other pages of messages, `limit` is clamped to the configured maximum page size.
+pub fn get_missing_messages(
+    from_server_id: i64, seen_server_ids: Vec<i64>, limit: Option<u16>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool, read_pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Bound the amount of work a single request can cause
+    if seen_server_ids.len() > MAX_SEEN_SERVER_IDS {
+        warn!("Ignoring missing messages request with too many seen server IDs.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = read_pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let limit = clamp_page_size(
+        limit,
+        super::DEFAULT_PAGE_SIZE.load(Ordering::SeqCst),
+        super::MAX_PAGE_SIZE.load(Ordering::SeqCst),
+    );
+    // Only the seen server IDs after `from_server_id` can be skipped, so fetching that many
+    // messages more than requested (plus one to find out whether there are more) is enough
+    let seen_server_ids: HashSet<i64> =
+        seen_server_ids.into_iter().filter(|server_id| *server_id > from_server_id).collect();
+    let max_message_count = limit as usize + seen_server_ids.len() + 1;
+    // Query the database
+    let raw_query = format!(
+        "SELECT id, public_key, timestamp, data, signature, reply_to FROM {} WHERE id > (?1) AND is_deleted = 0 ORDER BY id ASC LIMIT (?2)",
+        storage::MESSAGES_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query
+        .query_map(params![from_server_id, max_message_count as u32], message_from_row)
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get missing messages due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let mut messages: Vec<models::Message> = rows
+        .filter_map(|result| result.ok())
+        .filter(|message| !message.server_id.is_some_and(|id| seen_server_ids.contains(&id)))
+        .collect();
+    let has_more = messages.len() > limit as usize;
+    messages.truncate(limit as usize);
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        messages: Vec<models::Message>,
+        has_more: bool,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), messages, has_more };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Strips all fields not listed in the comma separated `fields` from the given messages. Unknown
+/// field names are ignored.
+pub fn select_message_fields(
+    messages: Vec<models::Message>, fields: &str,
+) -> Vec<serde_json::Value> {
+    let fields: Vec<&str> = fields.split(',').map(|field| field.trim()).collect();
+    let mut projected_messages = vec![];
+    for message in messages {
+        let json = match serde_json::to_value(message) {
+            Ok(serde_json::Value::Object(json)) => json,
+            Ok(_) => continue,
+            Err(e) => {
+                error!("Couldn't serialize message due to error: {}.", e);
+                continue;
+            }
+        };
+        let projected_message: serde_json::Map<String, serde_json::Value> =
+            json.into_iter().filter(|(key, _)| fields.contains(&key.as_str())).collect();
+        projected_messages.push(serde_json::Value::Object(projected_message));
+    }
+    return projected_messages;
+}
+
+/// Parses the query parameter with the given name, if it's present. Values that can't be parsed are
+/// rejected rather than ignored, so that e.g. a typo in a bound doesn't silently widen a range.
+fn parse_query_param<T: std::str::FromStr>(
+    query_params: &HashMap<String, String>, name: &str,
+) -> Result<Option<T>, Rejection> {
+    match query_params.get(name).map(|str| str.parse::<T>()) {
+        Some(Ok(value)) => return Ok(Some(value)),
+        Some(Err(_)) => {
+            warn!("Ignoring request with invalid query parameter: {}.", name);
+            return Err(warp::reject::custom(Error::InvalidQuery));
+        }
+        None => return Ok(None),
+    }
+}
+
+/// Returns the `limit` query parameter clamped to the configured maximum page size, or the
+/// configured default page size if it's missing or invalid.
+fn get_page_size(query_params: &HashMap<String, String>) -> u16 {
+    let limit = query_params.get("limit").and_then(|str| str.parse().ok());
+    return clamp_page_size(
+        limit,
+        super::DEFAULT_PAGE_SIZE.load(Ordering::SeqCst),
+        super::MAX_PAGE_SIZE.load(Ordering::SeqCst),
+    );
+}
+
+/// Returns `limit`, or `default_page_size` if there's no limit, clamped to `max_page_size`.
+pub fn clamp_page_size(limit: Option<u16>, default_page_size: u16, max_page_size: u16) -> u16 {
+    return limit.unwrap_or(default_page_size).min(max_page_size);
+}
+
+fn get_reactions(
+    message_id: i64, conn: &storage::DatabaseConnection,
+) -> Result<HashMap<String, u32>, Rejection> {
+    let raw_query = format!(
+        "SELECT emoji, COUNT(*) FROM {} WHERE message_id = (?1) GROUP BY emoji",
+        storage::REACTIONS_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![message_id], |row| Ok((row.get(0)?, row.get(1)?))) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get reactions due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    return Ok(rows.filter_map(|result| result.ok()).collect());
+}
+
+/// Returns the read counts of the messages with the given `message_ids`. Messages that nobody
+/// read yet are left out.
+fn get_read_counts(
+    message_ids: &[i64], conn: &storage::DatabaseConnection,
+) -> Result<HashMap<i64, u32>, Rejection> {
+    let mut read_counts: HashMap<i64, u32> = HashMap::new();
+    // Stay well below SQLite's limit on the number of parameters of a query
+    for message_ids in message_ids.chunks(MAX_QUERY_PARAMETERS) {
+        let raw_query = format!(
+            "SELECT message_id, COUNT(*) FROM {} WHERE message_id IN ({}) GROUP BY message_id",
+            storage::READ_RECEIPTS_TABLE,
+            vec!["?"; message_ids.len()].join(", ")
+        );
+        let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+        let rows = match query.query_map(message_ids, |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Couldn't get read counts due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        for (message_id, read_count) in rows.filter_map(|result| result.ok()) {
+            read_counts.insert(message_id, read_count);
+        }
+    }
+    return Ok(read_counts);
+}
+
+/// Maps a row with the `id`, `public_key`, `timestamp`, `data`, `signature` and `reply_to` columns
+/// of the messages table, in that order, to a message.
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<models::Message> {
+    return Ok(models::Message {
+        server_id: row.get(0)?,
+        public_key: row.get(1)?,
+        timestamp: row.get(2)?,
+        data: row.get(3)?,
+        signature: row.get(4)?,
+        reply_to: row.get(5)?,
+        reactions: None,
+        read_count: None,
+        display_name: None,
+        idempotency_key: None,
+    });
+}
+
+/// Records that the user with the given `auth_token` was just active. This is used both for usage
+/// statistics and for `get_active_members`.
+pub fn update_last_active(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<(), Rejection> {
+    let public_key = get_public_key_for_auth_token(auth_token, pool)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let now = chrono::Utc::now().timestamp();
+    let stmt = format!(
+        "INSERT OR REPLACE INTO {} (public_key, last_active) VALUES(?1, ?2)",
+        storage::USER_ACTIVITY_TABLE
+    );
+    conn.execute(&stmt, params![public_key, now]).map_err(|_| Error::DatabaseFailedInternally)?;
+    return Ok(());
+}
+
+// Reactions
+
+/// Adds a reaction with the given `emoji` to the message with the given `message_id`. Reacting
+/// with the same emoji twice has no additional effect.
+pub fn add_reaction(
+    message_id: i64, emoji: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the emoji
+    if !is_valid_emoji(emoji) {
+        warn!("Ignoring reaction with invalid emoji: {}.", emoji);
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Check that the requesting user isn't muted
+    if is_muted(&requesting_public_key, &conn)? {
+        warn!("Ignoring reaction from muted user.");
+        return Err(warp::reject::custom(Error::Muted));
+    }
+    // Check that the message exists
+    let raw_query = format!(
+        "SELECT COUNT(id) FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let count: u32 = match conn.query_row(&raw_query, params![message_id], |row| row.get(0)) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    if count == 0 {
+        return Err(warp::reject::custom(Error::NoSuchMessage));
+    }
+    // Insert the reaction
+    let stmt = format!(
+        "INSERT OR IGNORE INTO {} (message_id, public_key, emoji) VALUES (?1, ?2, ?3)",
+        storage::REACTIONS_TABLE
+    );
+    match conn.execute(&stmt, params![message_id, &requesting_public_key, emoji]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't add reaction due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Removes the requesting user's reaction with the given `emoji` from the message with the given
+/// `message_id`. Removing a reaction that doesn't exist isn't an error.
+pub fn remove_reaction(
+    message_id: i64, emoji: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Remove the reaction
+    let stmt = format!(
+        "DELETE FROM {} WHERE message_id = (?1) AND public_key = (?2) AND emoji = (?3)",
+        storage::REACTIONS_TABLE
+    );
+    match conn.execute(&stmt, params![message_id, &requesting_public_key, emoji]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't remove reaction due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+fn is_valid_emoji(emoji: &str) -> bool {
+    // Emoji can consist of multiple code points (e.g. skin tone modifiers or ZWJ sequences), so
+    // this only enforces a sensible upper bound rather than checking against a list
+    return !emoji.is_empty() && emoji.len() <= 32;
+}
+
+// Read receipts
+
+/// Records that the requesting user read the message with the given `message_id`. Only the number
+/// of users that read a message is ever exposed, so marking a message as read twice has no
+/// additional effect.
+pub fn mark_message_as_read(
+    message_id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Check that the message exists
+    let raw_query = format!(
+        "SELECT COUNT(id) FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let count: u32 = match conn.query_row(&raw_query, params![message_id], |row| row.get(0)) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    if count == 0 {
+        return Err(warp::reject::custom(Error::NoSuchMessage));
+    }
+    // Insert the read receipt
+    let stmt = format!(
+        "INSERT OR IGNORE INTO {} (message_id, public_key) VALUES (?1, ?2)",
+        storage::READ_RECEIPTS_TABLE
+    );
+    match conn.execute(&stmt, params![message_id, &requesting_public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't mark message as read due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+// Reports
+
+/// Reports the message with the given `message_id` to the moderators. Reporting the same message
+/// twice has no additional effect.
+pub fn report_message(
+    message_id: i64, reason: Option<String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the reason
+    if let Some(reason) = &reason {
+        if reason.len() > MAX_REPORT_REASON_LENGTH {
+            warn!("Ignoring report with a reason that's too long.");
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    }
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Check that the message exists
+    let raw_query = format!(
+        "SELECT COUNT(id) FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let count: u32 = match conn.query_row(&raw_query, params![message_id], |row| row.get(0)) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    if count == 0 {
+        return Err(warp::reject::custom(Error::NoSuchMessage));
+    }
+    // Insert the report
+    let now = chrono::Utc::now().timestamp();
+    let stmt = format!(
+        "INSERT OR IGNORE INTO {} (message_id, reporter, reason, timestamp) VALUES (?1, ?2, ?3, ?4)",
+        storage::REPORTS_TABLE
+    );
+    match conn.execute(&stmt, params![message_id, &requesting_public_key, reason, now]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't report message due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Returns the reports for messages that haven't been deleted yet, if the requesting user is a
+/// moderator. Reports are paginated the same way as messages are in `get_messages`, with
+/// `from_server_id` referring to the ID of a report.
+pub fn get_reports(
+    query_params: HashMap<String, String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unwrap query parameters
+    let from_server_id: Option<i64> =
+        query_params.get("from_server_id").map(|str| str.parse().unwrap_or(0));
+    let limit = get_page_size(&query_params);
+    // By default page forward from `from_server_id` if it's set; otherwise return the newest reports
+    let order = if from_server_id.is_some() { "ASC" } else { "DESC" };
+    // Query the database
+    let raw_query = format!(
+        "SELECT r.id, r.message_id, r.reporter, r.reason, r.timestamp FROM {} r
+        INNER JOIN {} m ON m.id = r.message_id
+        WHERE r.id > (?1) AND m.is_deleted = 0 ORDER BY r.id {} LIMIT (?2)",
+        storage::REPORTS_TABLE,
+        storage::MESSAGES_TABLE,
+        order
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![from_server_id.unwrap_or(0), limit], |row| {
+        Ok(models::Report {
+            id: row.get(0)?,
+            message_id: row.get(1)?,
+            reporter: row.get(2)?,
+            reason: row.get(3)?,
+            timestamp: row.get(4)?,
+        })
+    }) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get reports due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let reports: Vec<models::Report> = rows.filter_map(|result| result.ok()).collect();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        reports: Vec<models::Report>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), reports };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+// Pinned messages
+
+/// Pins the message with the given `message_id` if the requesting user is a moderator. Pinning a
+/// message that's already pinned has no additional effect.
+pub fn pin_message(
+    message_id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection and open a transaction
+    // The transaction is immediate so that concurrent pins can't exceed the maximum
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Check that the message exists
+    let raw_query = format!(
+        "SELECT COUNT(id) FROM {} WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let count: u32 = match tx.query_row(&raw_query, params![message_id], |row| row.get(0)) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    if count == 0 {
+        return Err(warp::reject::custom(Error::NoSuchMessage));
+    }
+    // Check whether the message is already pinned and how many messages are pinned in total
+    let raw_query = format!(
+        "SELECT COUNT(p.id), COALESCE(SUM(p.message_id = (?1)), 0) FROM {} p
+        INNER JOIN {} m ON m.id = p.message_id WHERE m.is_deleted = 0",
+        storage::PINNED_MESSAGES_TABLE,
+        storage::MESSAGES_TABLE
+    );
+    let (pin_count, is_pinned): (u32, bool) =
+        match tx.query_row(&raw_query, params![message_id], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Couldn't query database due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    if !is_pinned {
+        if pin_count >= super::MAX_PINNED_MESSAGES.load(Ordering::SeqCst) {
+            warn!(
+                "Ignoring pin request because the maximum number of pinned messages was reached."
+            );
+            return Err(warp::reject::custom(Error::PinLimitReached));
+        }
+        // Pin the message
+        let now = chrono::Utc::now().timestamp();
+        let stmt = format!(
+            "INSERT OR REPLACE INTO {} (message_id, timestamp) VALUES (?1, ?2)",
+            storage::PINNED_MESSAGES_TABLE
+        );
+        match tx.execute(&stmt, params![message_id, now]) {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Couldn't pin message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    }
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Unpins the message with the given `message_id` if the requesting user is a moderator. Unpinning
+/// a message that isn't pinned has no effect.
+pub fn unpin_message(
+    message_id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unpin the message
+    let stmt = format!("DELETE FROM {} WHERE message_id = (?1)", storage::PINNED_MESSAGES_TABLE);
+    match conn.execute(&stmt, params![message_id]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't unpin message due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Returns the pinned messages that haven't been deleted, in the order in which they were pinned.
+pub fn get_pinned_messages(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Vec<models::Message>, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!(
+        "SELECT m.id, m.public_key, m.timestamp, m.data, m.signature, m.reply_to FROM {} p
+        INNER JOIN {} m ON m.id = p.message_id WHERE m.is_deleted = 0 ORDER BY p.id ASC",
+        storage::PINNED_MESSAGES_TABLE,
+        storage::MESSAGES_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![], message_from_row) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get pinned messages due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let messages: Vec<models::Message> = rows.filter_map(|result| result.ok()).collect();
+    // Return the messages
+    return Ok(messages);
+}
+
+// Message deletion
+
+/// Deletes the messages with the given `ids` from the database, if present.
+pub fn delete_messages(
+    ids: Vec<i64>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // FIXME: Right now a situation can occur where a non-moderator user selects multiple
+    // messages, some of which are their own and some of which aren't, and then hits this endpoint.
+    // When they do, some of the messages would be deleted but an error status code would be
+    // returned, prompting the client to roll back the deletions they made locally. The only thing
+    // preventing this scenario from occurring right now is that we don't allow users to make such
+    // a selection in the Session UI. In the future we should take a better approach to make it
+    // impossible.
+    for id in ids {
+        delete_message(id, auth_token, pool)?;
+    }
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Deletes all messages sent by the given `public_key` if the requesting user is a moderator.
+// The rows borrow the query, so they can't be returned from the block without the binding
+#[allow(clippy::let_and_return)]
+pub fn delete_messages_for_user(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring delete messages for user request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a connection and open a transaction
+    // The transaction is immediate so that messages the user sends in the meantime are either
+    // deleted along with the rest or sent after the deletion, but never lost in between
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Get the IDs of the messages to delete
+    let ids: Vec<i64> = {
+        let raw_query = format!(
+            "SELECT id FROM {} WHERE public_key = (?1) AND is_deleted = 0",
+            storage::MESSAGES_TABLE
+        );
+        let mut query = tx.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+        let rows = match query.query_map(params![public_key], |row| row.get(0)) {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Couldn't delete messages due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        let ids = rows.filter_map(|result| result.ok()).collect();
+        ids
+    };
+    // Delete all messages sent by the given public key and record the deletions
+    // The content of the messages is kept around until the restore window has passed (see
+    // `storage::scrub_deleted_messages`)
+    let delete_stmt = format!(
+        "UPDATE {} SET is_deleted = 1 WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let insert_stmt = format!(
+        "INSERT INTO {} (deleted_message_id, timestamp) VALUES (?1, ?2)",
+        storage::DELETED_MESSAGES_TABLE
+    );
+    let now = chrono::Utc::now().timestamp();
+    let mut deleted_count = 0;
+    for id in ids {
+        let result = tx.execute(&delete_stmt, params![id]).and_then(|count| {
+            if count > 0 {
+                tx.execute(&insert_stmt, params![id, now])?;
+            }
+            Ok(count)
+        });
+        match result {
+            Ok(count) => deleted_count += count,
+            Err(e) => {
+                error!("Couldn't delete messages due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        }
+    }
+    // Commit
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        deleted_count: usize,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), deleted_count };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Deletes the message with the given `id` from the database, if it's present.
+// The rows borrow the query, so they can't be returned from the block without the binding
+#[allow(clippy::let_and_return)]
+pub fn delete_message(
+    id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Check that the requesting user is either the sender of the message or a moderator
+    let sender_option: Option<String> = {
+        let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+        let raw_query =
+            format!("SELECT public_key FROM {} WHERE id = (?1)", storage::MESSAGES_TABLE);
+        let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+        let rows = match query.query_map(params![id], |row| row.get(0)) {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Couldn't delete message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        let public_key = rows.filter_map(|result| result.ok()).next();
+        public_key
+    };
+    let sender =
+        sender_option.ok_or_else(|| warp::reject::custom(Error::DatabaseFailedInternally))?;
+    if !is_moderator(&requesting_public_key, pool)? && requesting_public_key != sender {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a connection and open a transaction
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Delete the message if it's present
+    // The content of the message is kept around until the restore window has passed (see
+    // `storage::scrub_deleted_messages`)
+    let stmt = format!(
+        "UPDATE {} SET is_deleted = 1 WHERE id = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let count = match tx.execute(&stmt, params![id]) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Couldn't delete message due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Update the deletions table if needed
+    if count > 0 {
+        let stmt = format!(
+            "INSERT INTO {} (deleted_message_id, timestamp) VALUES (?1, ?2)",
+            storage::DELETED_MESSAGES_TABLE
+        );
+        let now = chrono::Utc::now().timestamp();
+        match tx.execute(&stmt, params![id, now]) {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Couldn't delete message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    }
+    // Commit
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Deletes all messages, reactions, reports, pins and tombstones in the room if the requesting user
+/// is a moderator. A single scrubbed message and a tombstone for it are always kept at the highest
+/// existing server IDs, because SQLite would otherwise reuse server IDs, which would break clients
+/// that are paging by server ID. If `tombstone` is set, the tombstone gets a new ID so that clients
+/// that are polling for deletions find out about the purge.
+pub fn purge_room(
+    tombstone: bool, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a connection and open a transaction
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Get the highest server IDs before anything is deleted
+    let get_last_id = |table: &str| -> Result<Option<i64>, Rejection> {
+        let raw_query = format!("SELECT MAX(id) FROM {}", table);
+        match tx.query_row(&raw_query, params![], |row| row.get(0)) {
+            Ok(id) => return Ok(id),
+            Err(e) => {
+                error!("Couldn't purge room due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        }
+    };
+    let last_message_id = get_last_id(storage::MESSAGES_TABLE)?;
+    let last_deletion_id = get_last_id(storage::DELETED_MESSAGES_TABLE)?;
+    // Delete everything
+    let tables = [
+        storage::MESSAGES_TABLE,
+        storage::REACTIONS_TABLE,
+        storage::READ_RECEIPTS_TABLE,
+        storage::REPORTS_TABLE,
+        storage::PINNED_MESSAGES_TABLE,
+        storage::IDEMPOTENCY_KEYS_TABLE,
+        storage::DELETED_MESSAGES_TABLE,
+    ];
+    let mut deleted_counts: HashMap<String, usize> = HashMap::new();
+    for table in tables.iter() {
+        let stmt = format!("DELETE FROM {}", table);
+        match tx.execute(&stmt, params![]) {
+            Ok(count) => deleted_counts.insert(table.to_string(), count),
+            Err(e) => {
+                error!("Couldn't purge room due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    }
+    // Leave behind a scrubbed message and a tombstone for it at the highest server IDs
+    if let Some(last_message_id) = last_message_id {
+        let stmt = format!(
+            "INSERT INTO {} (id, public_key, timestamp, data, signature, is_deleted)
+            VALUES (?1, 'deleted', 0, 'deleted', 'deleted', 1)",
+            storage::MESSAGES_TABLE
+        );
+        if let Err(e) = tx.execute(&stmt, params![last_message_id]) {
+            error!("Couldn't purge room due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+        let stmt = format!(
+            "INSERT INTO {} (id, deleted_message_id, timestamp) VALUES (?1, ?2, ?3)",
+            storage::DELETED_MESSAGES_TABLE
+        );
+        let id = match (tombstone, last_deletion_id) {
+            (false, Some(last_deletion_id)) => last_deletion_id,
+            _ => last_deletion_id.unwrap_or(0) + 1,
+        };
+        let now = chrono::Utc::now().timestamp();
+        if let Err(e) = tx.execute(&stmt, params![id, last_message_id, now]) {
+            error!("Couldn't purge room due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+    // Commit
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Return
+    info!("Moderator: {} purged room; deleted: {:?}.", requesting_public_key, deleted_counts);
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        deleted_counts: HashMap<String, usize>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), deleted_counts };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Restores the message with the given `id` if it was deleted within the configured restore window.
+/// The requesting user must either be the sender of the message or a moderator. Restoring a
+/// message that isn't deleted has no effect.
+pub fn restore_message(
+    id: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a connection and open a transaction
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Get the message
+    let raw_query =
+        format!("SELECT public_key, is_deleted FROM {} WHERE id = (?1)", storage::MESSAGES_TABLE);
+    let (sender, is_deleted): (String, bool) =
+        match tx.query_row(&raw_query, params![id], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(result) => result,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(warp::reject::custom(Error::NoSuchMessage))
+            }
+            Err(e) => {
+                error!("Couldn't restore message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    if is_deleted {
+        // Check that the requesting user is either the sender of the message or a moderator
+        if !is_moderator(&requesting_public_key, pool)? && requesting_public_key != sender {
+            return Err(warp::reject::custom(Error::Unauthorized));
+        }
+        // Check that the message was deleted recently enough
+        let raw_query = format!(
+            "SELECT MAX(timestamp) FROM {} WHERE deleted_message_id = (?1)",
+            storage::DELETED_MESSAGES_TABLE
+        );
+        let deleted_at: Option<i64> = match tx.query_row(&raw_query, params![id], |row| row.get(0))
+        {
+            Ok(deleted_at) => deleted_at,
+            Err(e) => {
+                error!("Couldn't restore message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        let window = super::MESSAGE_RESTORE_WINDOW.load(Ordering::SeqCst) as i64;
+        // The content of messages deleted before the restore window was introduced is already gone
+        match deleted_at {
+            Some(deleted_at) if deleted_at > now - window => (),
+            _ => return Err(warp::reject::custom(Error::RestoreWindowExpired)),
+        }
+        // Restore the message
+        let stmt = format!("UPDATE {} SET is_deleted = 0 WHERE id = (?1)", storage::MESSAGES_TABLE);
+        match tx.execute(&stmt, params![id]) {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Couldn't restore message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        // Mark the deletion as undone rather than removing it, so that deletion IDs are never reused
+        let stmt = format!(
+            "UPDATE {} SET is_restored = 1 WHERE deleted_message_id = (?1)",
+            storage::DELETED_MESSAGES_TABLE
+        );
+        match tx.execute(&stmt, params![id]) {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Couldn't restore message due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+        // Commit
+        tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    }
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Returns either the last `limit` deleted messages or all deleted messages since `from_server_id, limited to `limit`.
+///
+/// This follows the same pagination rules as `get_messages`, with the server IDs referring to the
+/// IDs of the deletions rather than those of the deleted messages. Because deletions are only ever
+/// appended, the ID of the last deletion returned can be used as the `from_server_id` of the next
+/// request to page through all deletions without skipping or repeating any. Deletions of messages
+/// that have since been restored are left out. Like for `get_messages`, only authorization uses
+/// `pool`.
+pub fn get_deleted_messages(
+    query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool, read_pool: &storage::DatabaseConnectionPool,
+) -> Result<Vec<models::DeletedMessage>, Rejection> {
+    // Validate the order before doing anything else
+    let order: Option<&str> = match query_params.get("order").map(|str| str.as_str()) {
+        Some("asc") => Some("ASC"),
+        Some("desc") => Some("DESC"),
+        Some(order) => {
+            warn!("Ignoring get deleted messages request with invalid order: {}.", order);
+            return Err(warp::reject::custom(Error::InvalidQuery));
+        }
+        None => None,
+    };
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = read_pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unwrap query parameters
+    let from_server_id: Option<i64> =
+        query_params.get("from_server_id").map(|str| str.parse().unwrap_or(0));
+    let to_server_id: Option<i64> = parse_query_param(&query_params, "to_server_id")?;
+    let limit = get_page_size(&query_params);
+    // An empty range isn't an error; there just aren't any deletions in it
+    if let (Some(from_server_id), Some(to_server_id)) = (from_server_id, to_server_id) {
+        if from_server_id > to_server_id {
+            return Ok(vec![]);
+        }
+    }
+    // Build the WHERE clause based on whichever bounds are present
+    let mut conditions: Vec<&str> = vec!["COALESCE(is_restored, 0) = 0"];
+    if from_server_id.is_some() {
+        conditions.push("id > (?1)");
+    }
+    if to_server_id.is_some() {
+        conditions.push("id <= (?2)");
+    }
+    // By default page forward from `from_server_id` if it's set; otherwise return the newest deletions
+    let default_order = if from_server_id.is_some() { "ASC" } else { "DESC" };
+    let order = order.unwrap_or(default_order);
+    // Query the database
+    let raw_query = format!(
+        "SELECT id, deleted_message_id FROM {} WHERE {} ORDER BY id {} LIMIT (?3)",
+        storage::DELETED_MESSAGES_TABLE,
+        conditions.join(" AND "),
+        order
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![from_server_id, to_server_id, limit], |row| {
+        Ok(models::DeletedMessage { id: row.get(0)?, deleted_message_id: row.get(1)? })
+    }) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't get deleted messages due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let deleted_messages: Vec<models::DeletedMessage> =
+        rows.filter_map(|result| result.ok()).collect();
+    // Return the IDs
+    return Ok(deleted_messages);
+}
+
+/// Prunes deletions with a server ID lower than `before` if the requesting user is a moderator, so
+/// that the deletions feed doesn't grow forever. Deletions within the restore window are kept so
+/// their messages can still be restored, and the latest deletion is always kept so that server IDs
+/// of future deletions aren't reused.
+pub fn prune_deleted_messages(
+    before: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Prune the deletions
+    let stmt = format!(
+        "DELETE FROM {0} WHERE id < (?1) AND id < (SELECT MAX(id) FROM {0})
+        AND (timestamp IS NULL OR timestamp <= (?2))",
+        storage::DELETED_MESSAGES_TABLE
+    );
+    let now = chrono::Utc::now().timestamp();
+    let window = super::MESSAGE_RESTORE_WINDOW.load(Ordering::SeqCst) as i64;
+    let deleted_count = match conn.execute(&stmt, params![before, now - window]) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Couldn't prune deleted messages due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    info!("Moderator: {} pruned {} deleted messages.", requesting_public_key, deleted_count);
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        deleted_count: usize,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), deleted_count };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+// Moderation
+
+pub async fn add_moderator_public(
+    body: models::ChangeModeratorRequestBody, auth_token: &str,
+) -> Result<Response, Rejection> {
+    let pool = storage::pool_by_room_id(&body.room_id);
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, &pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    info!("Moderator: {} is adding moderator: {}.", requesting_public_key, &body.public_key);
+    return add_moderator(body).await;
+}
+
+// Not publicly exposed.
+pub async fn add_moderator(
+    body: models::ChangeModeratorRequestBody,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(&body.public_key) {
+        warn!("Ignoring add moderator request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Get a database connection
+    let pool = storage::pool_by_room_id(&body.room_id);
+    // Don't double add moderators
+    if is_moderator(&body.public_key, &pool)? {
+        let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+        return Ok(warp::reply::json(&json).into_response());
+    }
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Insert the moderator
+    let stmt = format!("INSERT INTO {} (public_key) VALUES (?1)", storage::MODERATORS_TABLE);
+    match conn.execute(&stmt, params![&body.public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't make public key moderator due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+    // Return
+    info!("Added moderator: {} to room with ID: {}", &body.public_key, &body.room_id);
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Makes all of the given `public_keys` moderators if the requesting user is a moderator. Either
+/// all public keys are added or none are; public keys that are already moderators are skipped.
+pub fn add_moderators(
+    public_keys: Vec<String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public keys
+    if public_keys.is_empty() || public_keys.len() > MAX_BULK_MODERATORS {
+        warn!("Ignoring add moderators request with {} public keys.", public_keys.len());
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    if public_keys.iter().any(|public_key| !crypto::is_valid_public_key(public_key)) {
+        warn!("Ignoring add moderators request containing an invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection and open a transaction
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Insert the moderators that aren't moderators yet
+    let raw_query =
+        format!("SELECT COUNT(*) FROM {} WHERE public_key = (?1)", storage::MODERATORS_TABLE);
+    let stmt = format!("INSERT INTO {} (public_key) VALUES (?1)", storage::MODERATORS_TABLE);
+    let mut added_count = 0;
+    for public_key in &public_keys {
+        let existing_count: i64 =
+            match tx.query_row(&raw_query, params![public_key], |row| row.get(0)) {
+                Ok(count) => count,
+                Err(e) => {
+                    error!("Couldn't query database due to error: {}.", e);
+                    return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+                }
+            };
+        if existing_count > 0 {
+            continue;
+        }
+        match tx.execute(&stmt, params![public_key]) {
+            Ok(_) => added_count += 1,
+            Err(e) => {
+                error!("Couldn't make public key moderator due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        }
+    }
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Return
+    let skipped_count = public_keys.len() - added_count;
+    info!(
+        "Moderator: {} added {} moderators and skipped {}.",
+        requesting_public_key, added_count, skipped_count
+    );
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        added_count: usize,
+        skipped_count: usize,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), added_count, skipped_count };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+pub async fn delete_moderator_public(
+    body: models::ChangeModeratorRequestBody, auth_token: &str,
+) -> Result<Response, Rejection> {
+    let pool = storage::pool_by_room_id(&body.room_id);
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, &pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    info!("Moderator: {} is deleting moderator: {}.", requesting_public_key, &body.public_key);
+    return delete_moderator(body).await;
+}
+
+// Not publicly exposed.
+/// Removing a public key that isn't a moderator isn't an error.
+pub async fn delete_moderator(
+    body: models::ChangeModeratorRequestBody,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(&body.public_key) {
+        warn!("Ignoring delete moderator request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Get a database connection
+    let pool = storage::pool_by_room_id(&body.room_id);
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Insert the moderator
+    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::MODERATORS_TABLE);
+    match conn.execute(&stmt, params![&body.public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't delete moderator due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+    // Return
+    info!("Deleted moderator: {} from room with ID: {}", &body.public_key, &body.room_id);
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Returns the full list of moderators.
+pub fn get_moderators(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Vec<String>, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Return
+    let public_keys = get_moderators_vector(pool)?;
+    return Ok(public_keys);
+}
+
+/// Bans the given `public_key` if the requesting user is a moderator, and deletes
+/// all messages sent by `public_key`.
+pub fn ban_and_delete_all_messages(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring ban and delete all messages request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Ban the user
+    ban(public_key, None, None, auth_token, pool)?;
+    // Get the IDs of the messages to delete
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let raw_query = format!(
+        "SELECT id FROM {} WHERE public_key = (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![public_key], |row| row.get(0)) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't delete messages due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let ids: Vec<i64> = rows.filter_map(|result| result.ok()).collect();
+    // Delete all messages sent by the given public key
+    delete_messages(ids, auth_token, pool)?;
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Bans the given `public_key` if the requesting user is a moderator.
+///
+/// If `duration` (in seconds) is set the ban expires after that amount of time; otherwise it's
+/// permanent. Banning a public key that's already banned replaces the existing ban, including its
+/// reason.
+pub fn ban(
+    public_key: &str, duration: Option<i64>, reason: Option<String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring ban request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Validate the duration
+    if let Some(duration) = duration {
+        if duration <= 0 {
+            warn!("Ignoring ban request with invalid duration: {}.", duration);
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    }
+    // Validate the reason
+    reject_if_invalid_ban_reason(&reason)?;
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Calculate when the ban expires, if ever
+    let now = chrono::Utc::now().timestamp();
+    let banned_until: Option<i64> = duration.map(|duration| now.saturating_add(duration));
+    // Get a connection and open a transaction
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Remove any existing (possibly expired) ban so that a public key is never banned twice
+    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::BLOCK_LIST_TABLE);
+    match tx.execute(&stmt, params![public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't ban public key due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Insert the ban
+    let stmt = format!(
+        "INSERT INTO {} (public_key, banned_at, banned_until, reason, banned_by) VALUES (?1, ?2, ?3, ?4, ?5)",
+        storage::BLOCK_LIST_TABLE
+    );
+    match tx.execute(&stmt, params![public_key, now, banned_until, reason, requesting_public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't ban public key due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    match tx.commit() {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't ban public key due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    pool.invalidate_block_list();
+    metrics::BANS.inc();
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Replaces the reason of the ban of the given `public_key` if the requesting user is a moderator.
+/// A `reason` of `None` removes it.
+pub fn update_ban_reason(
+    public_key: &str, reason: Option<String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring ban reason update for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Validate the reason
+    reject_if_invalid_ban_reason(&reason)?;
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Update the reason
+    // Expired bans are pruned periodically, but might not have been pruned yet
+    let stmt = format!(
+        "UPDATE {} SET reason = (?1) WHERE public_key = (?2) AND (banned_until IS NULL OR banned_until > (?3))",
+        storage::BLOCK_LIST_TABLE
+    );
+    let now = chrono::Utc::now().timestamp();
+    let updated_row_count = match conn.execute(&stmt, params![reason, public_key, now]) {
+        Ok(updated_row_count) => updated_row_count,
+        Err(e) => {
+            error!("Couldn't update ban reason due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    if updated_row_count == 0 {
+        return Err(warp::reject::custom(Error::NoSuchBan));
+    }
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+fn reject_if_invalid_ban_reason(reason: &Option<String>) -> Result<(), Rejection> {
+    if let Some(reason) = reason {
+        if reason.len() > MAX_BAN_REASON_LENGTH {
+            warn!("Ignoring ban with a reason that's too long.");
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    }
+    return Ok(());
+}
+
+/// Unbans the given `public_key` if the requesting user is a moderator.
+pub fn unban(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring unban request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Don't double unban public keys
+    if !is_banned(public_key, pool)? {
+        return Ok(StatusCode::OK.into_response());
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Delete the ban
+    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::BLOCK_LIST_TABLE);
+    match conn.execute(&stmt, params![public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't unban public key due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    pool.invalidate_block_list();
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Returns the full list of banned public keys. Moderators also get when each ban expires and
+/// why it was made.
+pub fn get_banned_public_keys(
+    query_params: HashMap<String, String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    let is_moderator = is_moderator(&requesting_public_key, pool)?;
+    // Moderators can get just the details of every ban, most recent first, e.g. for an audit log
+    let is_detailed = query_params.get("detailed").map(|str| str.as_str()) == Some("true");
+    if is_detailed {
+        if !is_moderator {
+            return Err(warp::reject::custom(Error::Unauthorized));
+        }
+        let mut bans = get_bans_vector(pool)?;
+        // Bans without a creation time are older than the ones with one
+        bans.sort_by_key(|ban| std::cmp::Reverse(ban.banned_at));
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Response {
+            status_code: u16,
+            bans: Vec<models::Ban>,
+        }
+        let response = Response { status_code: StatusCode::OK.as_u16(), bans };
+        return Ok(warp::reply::json(&response).into_response());
+    }
+    // Return
+    let bans = get_bans_vector(pool)?;
+    let public_keys = bans.iter().map(|ban| ban.public_key.clone()).collect();
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        banned_members: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bans: Option<Vec<models::Ban>>,
+    }
+    let response = Response {
+        status_code: StatusCode::OK.as_u16(),
+        banned_members: public_keys,
+        bans: if is_moderator { Some(bans) } else { None },
+    };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Returns whether the given `public_key` is currently banned and, if the ban is temporary, until
+/// when, so that clients can check this without getting the whole block list. Banned users can
+/// only get the ban status of their own public key.
+pub fn get_ban_status(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring ban status request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    // Banned users wouldn't be able to find out when their ban expires otherwise
+    let requesting_public_key = get_public_key_for_auth_token(auth_token, pool)?
+        .ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
+    if requesting_public_key != public_key {
+        let (has_authorization_level, _) =
+            has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+        if !has_authorization_level {
+            return Err(warp::reject::custom(Error::Unauthorized));
+        }
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    // Expired bans are pruned periodically, but might not have been pruned yet
+    let raw_query = format!(
+        "SELECT banned_until FROM {} WHERE public_key = (?1) AND (banned_until IS NULL OR banned_until > (?2))",
+        storage::BLOCK_LIST_TABLE
+    );
+    let now = chrono::Utc::now().timestamp();
+    let (banned, banned_until) =
+        match conn.query_row(&raw_query, params![public_key, now], |row| row.get(0)) {
+            Ok(banned_until) => (true, banned_until),
+            Err(rusqlite::Error::QueryReturnedNoRows) => (false, None),
+            Err(e) => {
+                error!("Couldn't query database due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        banned: bool,
+        banned_until: Option<i64>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), banned, banned_until };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Mutes the given `public_key` if the requesting user is a moderator. Muted users can still read
+/// messages, but can't send any.
+pub fn mute(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring mute request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Insert the public key
+    // Muting a public key that's already muted has no effect
+    let stmt =
+        format!("INSERT OR IGNORE INTO {} (public_key) VALUES (?1)", storage::MUTE_LIST_TABLE);
+    match conn.execute(&stmt, params![public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't mute public key due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Unmutes the given `public_key` if the requesting user is a moderator.
+pub fn unmute(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring unmute request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Remove the public key
+    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::MUTE_LIST_TABLE);
+    match conn.execute(&stmt, params![public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't unmute public key due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Returns the full list of muted public keys.
+pub fn get_muted_public_keys(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!("SELECT public_key FROM {}", storage::MUTE_LIST_TABLE);
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![], |row| row.get(0)) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let public_keys: Vec<String> = rows.filter_map(|result| result.ok()).collect();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        muted_members: Vec<String>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), muted_members: public_keys };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+// Cooldowns
+
+/// Puts the given `public_key` in a cooldown for the next `duration` seconds if the requesting
+/// user is a moderator. Unlike muted users, users in a cooldown can post again once it expires.
+/// Putting a user that's already in a cooldown in one replaces the existing cooldown.
+pub fn set_cooldown(
+    public_key: &str, duration: i64, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring cooldown request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Validate the duration
+    if duration <= 0 {
+        warn!("Ignoring cooldown request with invalid duration: {}.", duration);
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Insert the cooldown
+    let expires_at = chrono::Utc::now().timestamp().saturating_add(duration);
+    let stmt = format!(
+        "INSERT OR REPLACE INTO {} (public_key, expires_at) VALUES (?1, ?2)",
+        storage::COOLDOWNS_TABLE
+    );
+    match conn.execute(&stmt, params![public_key, expires_at]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't set cooldown due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Ends the cooldown of the given `public_key` early if the requesting user is a moderator.
+/// Removing a cooldown that doesn't exist (or has expired) isn't an error.
+pub fn remove_cooldown(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring cooldown removal request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Remove the cooldown
+    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::COOLDOWNS_TABLE);
+    match conn.execute(&stmt, params![public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't remove cooldown due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Returns the cooldowns that haven't expired yet.
+pub fn get_cooldowns(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    // Expired cooldowns are pruned periodically, but might not have been pruned yet
+    let raw_query = format!(
+        "SELECT public_key, expires_at FROM {} WHERE expires_at > (?1)",
+        storage::COOLDOWNS_TABLE
+    );
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let now = chrono::Utc::now().timestamp();
+    let rows = match query.query_map(params![now], |row| {
+        Ok(models::Cooldown { public_key: row.get(0)?, expires_at: row.get(1)? })
+    }) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let cooldowns: Vec<models::Cooldown> = rows.filter_map(|result| result.ok()).collect();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        cooldowns: Vec<models::Cooldown>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), cooldowns };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Returns the number of seconds until the cooldown of the given `public_key` expires, if it's in
+/// one.
+fn get_cooldown_remaining(
+    public_key: &str, conn: &rusqlite::Connection,
+) -> Result<Option<u64>, Rejection> {
+    let raw_query =
+        format!("SELECT expires_at FROM {} WHERE public_key = (?1)", storage::COOLDOWNS_TABLE);
+    let expires_at: i64 = match conn.query_row(&raw_query, params![public_key], |row| row.get(0)) {
+        Ok(expires_at) => expires_at,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Expired cooldowns are pruned periodically, but might not have been pruned yet
+    let now = chrono::Utc::now().timestamp();
+    if expires_at <= now {
+        return Ok(None);
+    }
+    return Ok(Some((expires_at - now) as u64));
+}
+
+/// Adds the given `public_key` to the allow list if the requesting user is a moderator, so that
+/// the user can post in the room if it's invite only (or the server is in allow list mode).
+pub fn allow(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring allow request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Insert the public key
+    // Allowing a public key that's already allowed has no effect
+    let stmt =
+        format!("INSERT OR IGNORE INTO {} (public_key) VALUES (?1)", storage::ALLOW_LIST_TABLE);
+    match conn.execute(&stmt, params![public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't allow public key due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+/// Removes the given `public_key` from the allow list if the requesting user is a moderator. This
+/// also undoes redeeming an invite.
+pub fn disallow(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Validate the public key
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring disallow request for invalid public key.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    // Get a connection and open a transaction
-    let mut conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    let tx = conn.transaction().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Delete the message if it's present
-    let stmt = format!("UPDATE {} SET public_key = 'deleted', timestamp = 0, data = 'deleted', signature = 'deleted', is_deleted = 1 WHERE id = (?1)", storage::MESSAGES_TABLE);
-    let count = match tx.execute(&stmt, params![id]) {
-        Ok(count) => count,
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Remove the public key
+    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::ALLOW_LIST_TABLE);
+    match conn.execute(&stmt, params![public_key]) {
+        Ok(_) => (),
         Err(e) => {
-            error!("Couldn't delete message due to error: {}.", e);
+            error!("Couldn't disallow public key due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
-    // Update the deletions table if needed
-    if count > 0 {
-        let stmt = format!(
-            "INSERT INTO {} (deleted_message_id) VALUES (?1)",
-            storage::DELETED_MESSAGES_TABLE
-        );
-        match tx.execute(&stmt, params![id]) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Couldn't delete message due to error: {}.", e);
-                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
-            }
-        };
-    }
-    // Commit
-    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
     // Return
     let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
     return Ok(warp::reply::json(&json).into_response());
 }
 
-/// Returns either the last `limit` deleted messages or all deleted messages since `from_server_id, limited to `limit`.
-pub fn get_deleted_messages(
-    query_params: HashMap<String, String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
-) -> Result<Vec<models::DeletedMessage>, Rejection> {
+/// Returns the full list of allowed public keys.
+pub fn get_allowed_public_keys(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
     // Check authorization level
     let (has_authorization_level, _) =
         has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
@@ -720,201 +3997,366 @@ pub fn get_deleted_messages(
         return Err(warp::reject::custom(Error::Unauthorized));
     }
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Unwrap query parameters
-    let from_server_id: i64;
-    if let Some(str) = query_params.get("from_server_id") {
-        from_server_id = str.parse().unwrap_or(0);
-    } else {
-        from_server_id = 0;
-    }
-    let limit: u16; // Never return more than 256 messages at once
-    if let Some(str) = query_params.get("limit") {
-        limit = std::cmp::min(str.parse().unwrap_or(256), 256);
-    } else {
-        limit = 256;
-    }
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Query the database
-    let raw_query: String;
-    if query_params.get("from_server_id").is_some() {
-        raw_query = format!(
-            "SELECT id, deleted_message_id FROM {} WHERE id > (?1) ORDER BY id ASC LIMIT (?2)",
-            storage::DELETED_MESSAGES_TABLE
-        );
-    } else {
-        raw_query = format!(
-            "SELECT id, deleted_message_id FROM {} ORDER BY id DESC LIMIT (?2)",
-            storage::DELETED_MESSAGES_TABLE
-        );
-    }
+    let raw_query = format!("SELECT public_key FROM {}", storage::ALLOW_LIST_TABLE);
     let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![from_server_id, limit], |row| {
-        Ok(models::DeletedMessage { id: row.get(0)?, deleted_message_id: row.get(1)? })
-    }) {
+    let rows = match query.query_map(params![], |row| row.get(0)) {
         Ok(rows) => rows,
         Err(e) => {
-            error!("Couldn't get deleted messages due to error: {}.", e);
+            error!("Couldn't query database due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
-    let deleted_messages: Vec<models::DeletedMessage> =
-        rows.filter_map(|result| result.ok()).collect();
-    // Return the IDs
-    return Ok(deleted_messages);
+    let public_keys: Vec<String> = rows.filter_map(|result| result.ok()).collect();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        allowed_members: Vec<String>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), allowed_members: public_keys };
+    return Ok(warp::reply::json(&response).into_response());
 }
 
-// Moderation
-
-pub async fn add_moderator_public(
-    body: models::ChangeModeratorRequestBody, auth_token: &str,
+/// Returns the public keys of the users that made an authenticated request within the last
+/// `window` seconds (5 minutes by default).
+pub fn get_active_members(
+    query_params: HashMap<String, String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    let pool = storage::pool_by_room_id(&body.room_id);
+    // Validate the window
+    let window: i64 = match query_params.get("window").map(|str| str.parse::<i64>()) {
+        Some(Ok(window)) if window > 0 => window,
+        None => DEFAULT_ACTIVE_MEMBERS_WINDOW,
+        Some(_) => {
+            warn!("Ignoring active members request with invalid window.");
+            return Err(warp::reject::custom(Error::InvalidQuery));
+        }
+    };
+    // Check authorization level
     let (has_authorization_level, _) =
-        has_authorization_level(auth_token, AuthorizationLevel::Moderator, &pool)?;
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    return add_moderator(body).await;
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!(
+        "SELECT public_key FROM {} WHERE last_active > (?1) ORDER BY last_active DESC",
+        storage::USER_ACTIVITY_TABLE
+    );
+    let cutoff = chrono::Utc::now().timestamp().saturating_sub(window);
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![cutoff], |row| row.get(0)) {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let public_keys: Vec<String> = rows.filter_map(|result| result.ok()).collect();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        active_members: Vec<String>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), active_members: public_keys };
+    return Ok(warp::reply::json(&response).into_response());
 }
 
-// Not publicly exposed.
-pub async fn add_moderator(
-    body: models::ChangeModeratorRequestBody,
+// Profiles
+
+/// Sets the display name and avatar of the requesting user. Control characters are stripped from
+/// the display name before it's stored.
+pub fn set_profile(
+    display_name: &str, avatar: Option<String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
+    // Validate the profile
+    let display_name: String = display_name.chars().filter(|c| !c.is_control()).collect();
+    let display_name = display_name.trim().to_string();
+    if display_name.is_empty() || display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+        warn!("Ignoring set profile request with invalid display name.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    if let Some(avatar) = &avatar {
+        if avatar.is_empty()
+            || avatar.len() > MAX_AVATAR_LENGTH
+            || avatar.chars().any(|c| c.is_control())
+        {
+            warn!("Ignoring set profile request with invalid avatar.");
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    }
+    // Check authorization level
+    let (has_authorization_level, requesting_public_key) =
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
     // Get a database connection
-    let pool = storage::pool_by_room_id(&body.room_id);
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Insert the moderator
-    let stmt = format!("INSERT INTO {} (public_key) VALUES (?1)", storage::MODERATORS_TABLE);
-    match conn.execute(&stmt, params![&body.public_key]) {
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Check that the requesting user isn't muted
+    if is_muted(&requesting_public_key, &conn)? {
+        warn!("Ignoring profile update from muted user.");
+        return Err(warp::reject::custom(Error::Muted));
+    }
+    // Store the profile
+    let stmt = format!(
+        "INSERT OR REPLACE INTO {} (public_key, display_name, avatar, timestamp) VALUES (?1, ?2, ?3, ?4)",
+        storage::PROFILES_TABLE
+    );
+    let now = chrono::Utc::now().timestamp();
+    match conn.execute(&stmt, params![&requesting_public_key, &display_name, &avatar, now]) {
         Ok(_) => (),
         Err(e) => {
-            error!("Couldn't make public key moderator due to error: {}.", e);
+            error!("Couldn't set profile due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     }
     // Return
-    info!("Added moderator: {} to room with ID: {}", &body.public_key, &body.room_id);
-    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
-    return Ok(warp::reply::json(&json).into_response());
+    let profile = models::Profile { public_key: requesting_public_key, display_name, avatar };
+    return Ok(profile_response(profile));
 }
 
-pub async fn delete_moderator_public(
-    body: models::ChangeModeratorRequestBody, auth_token: &str,
+/// Returns the profile of the user with the given `public_key`.
+pub fn get_profile(
+    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    let pool = storage::pool_by_room_id(&body.room_id);
+    // Check authorization level
     let (has_authorization_level, _) =
-        has_authorization_level(auth_token, AuthorizationLevel::Moderator, &pool)?;
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    return delete_moderator(body).await;
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Return
+    match get_profile_from_database(public_key, &conn)? {
+        Some(profile) => return Ok(profile_response(profile)),
+        None => return Err(warp::reject::custom(Error::NoSuchProfile)),
+    }
 }
 
-// Not publicly exposed.
-pub async fn delete_moderator(
-    body: models::ChangeModeratorRequestBody,
+fn get_profile_from_database(
+    public_key: &str, conn: &rusqlite::Connection,
+) -> Result<Option<models::Profile>, Rejection> {
+    let raw_query = format!(
+        "SELECT public_key, display_name, avatar FROM {} WHERE public_key = (?1)",
+        storage::PROFILES_TABLE
+    );
+    match conn.query_row(&raw_query, params![public_key], |row| {
+        Ok(models::Profile {
+            public_key: row.get(0)?,
+            display_name: row.get(1)?,
+            avatar: row.get(2)?,
+        })
+    }) {
+        Ok(profile) => return Ok(Some(profile)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+}
+
+fn profile_response(profile: models::Profile) -> Response {
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        profile: models::Profile,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), profile };
+    return warp::reply::json(&response).into_response();
+}
+
+// Invites
+
+/// Creates an invite if the requesting user is a moderator. The invite can be redeemed at most
+/// `max_redemptions` times if it's set, and only for the next `duration` seconds if that's set.
+pub fn create_invite(
+    max_redemptions: Option<u32>, duration: Option<i64>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
+    // Validate the limits
+    if max_redemptions == Some(0) || duration.map(|duration| duration <= 0).unwrap_or(false) {
+        warn!("Ignoring invite request with invalid limits.");
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Generate the invite
+    let code = hex::encode(thread_rng().gen::<[u8; 16]>());
+    let expires_at =
+        duration.map(|duration| chrono::Utc::now().timestamp().saturating_add(duration));
+    let invite = models::Invite { code, max_redemptions, redemption_count: 0, expires_at };
     // Get a database connection
-    let pool = storage::pool_by_room_id(&body.room_id);
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Insert the moderator
-    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::MODERATORS_TABLE);
-    match conn.execute(&stmt, params![&body.public_key]) {
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Insert the invite
+    let stmt = format!(
+        "INSERT INTO {} (code, max_redemptions, expires_at) VALUES (?1, ?2, ?3)",
+        storage::INVITES_TABLE
+    );
+    match conn.execute(&stmt, params![&invite.code, invite.max_redemptions, invite.expires_at]) {
         Ok(_) => (),
         Err(e) => {
-            error!("Couldn't delete moderator due to error: {}.", e);
+            error!("Couldn't create invite due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
-    }
+    };
     // Return
-    info!("Deleted moderator: {} from room with ID: {}", &body.public_key, &body.room_id);
-    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
-    return Ok(warp::reply::json(&json).into_response());
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        invite: models::Invite,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), invite };
+    return Ok(warp::reply::json(&response).into_response());
 }
 
-/// Returns the full list of moderators.
-pub fn get_moderators(
-    auth_token: &str, pool: &storage::DatabaseConnectionPool,
-) -> Result<Vec<String>, Rejection> {
+/// Adds the requesting user to the allow list of the room if `code` is a valid invite. Redeeming an
+/// invite when already on the allow list doesn't count as a redemption.
+pub fn redeem_invite(
+    code: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
     // Check authorization level
-    let (has_authorization_level, _) =
+    let (has_authorization_level, requesting_public_key) =
         has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
+    // Get a connection and open a transaction
+    // The transaction is immediate so that concurrent redemptions can't exceed the maximum
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|_| Error::DatabaseFailedInternally)?;
+    // Check that the invite can still be redeemed
+    let raw_query = format!(
+        "SELECT max_redemptions, redemption_count, expires_at FROM {} WHERE code = (?1)",
+        storage::INVITES_TABLE
+    );
+    let (max_redemptions, redemption_count, expires_at): (Option<u32>, u32, Option<i64>) = match tx
+        .query_row(&raw_query, params![code], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+    {
+        Ok(row) => row,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(warp::reject::custom(Error::NoSuchInvite))
+        }
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    if is_allowed(&requesting_public_key, &tx)? {
+        let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+        return Ok(warp::reply::json(&json).into_response());
+    }
+    if let Some(expires_at) = expires_at {
+        if expires_at <= chrono::Utc::now().timestamp() {
+            return Err(warp::reject::custom(Error::InviteExpired));
+        }
+    }
+    if let Some(max_redemptions) = max_redemptions {
+        if redemption_count >= max_redemptions {
+            return Err(warp::reject::custom(Error::InviteExhausted));
+        }
+    }
+    // Redeem the invite
+    let stmt = format!(
+        "UPDATE {} SET redemption_count = redemption_count + 1 WHERE code = (?1)",
+        storage::INVITES_TABLE
+    );
+    match tx.execute(&stmt, params![code]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't redeem invite due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    let stmt = format!("INSERT INTO {} (public_key) VALUES (?1)", storage::ALLOW_LIST_TABLE);
+    match tx.execute(&stmt, params![&requesting_public_key]) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Couldn't redeem invite due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    };
+    tx.commit().map_err(|_| Error::DatabaseFailedInternally)?;
     // Return
-    let public_keys = get_moderators_vector(pool)?;
-    return Ok(public_keys);
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
 }
 
-/// Bans the given `public_key` if the requesting user is a moderator, and deletes
-/// all messages sent by `public_key`.
-pub fn ban_and_delete_all_messages(
-    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+/// Returns all invites that haven't been revoked, including ones that can't be redeemed anymore.
+pub fn get_invites(
+    auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    // Validate the public key
-    if !is_valid_public_key(&public_key) {
-        warn!("Ignoring ban and delete all messages request for invalid public key.");
-        return Err(warp::reject::custom(Error::ValidationFailed));
-    }
     // Check authorization level
     let (has_authorization_level, _) =
         has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    // Ban the user
-    ban(public_key, auth_token, pool)?;
-    // Get the IDs of the messages to delete
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
     let raw_query = format!(
-        "SELECT id FROM {} WHERE public_key = (?1) AND is_deleted = 0",
-        storage::MESSAGES_TABLE
+        "SELECT code, max_redemptions, redemption_count, expires_at FROM {}",
+        storage::INVITES_TABLE
     );
     let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![public_key], |row| Ok(row.get(0)?)) {
+    let rows = match query.query_map(params![], |row| {
+        Ok(models::Invite {
+            code: row.get(0)?,
+            max_redemptions: row.get(1)?,
+            redemption_count: row.get(2)?,
+            expires_at: row.get(3)?,
+        })
+    }) {
         Ok(rows) => rows,
         Err(e) => {
-            error!("Couldn't delete messages due to error: {}.", e);
+            error!("Couldn't query database due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
-    let ids: Vec<i64> = rows.filter_map(|result| result.ok()).collect();
-    // Delete all messages sent by the given public key
-    delete_messages(ids, auth_token, pool)?;
+    let invites: Vec<models::Invite> = rows.filter_map(|result| result.ok()).collect();
     // Return
-    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
-    return Ok(warp::reply::json(&json).into_response());
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        invites: Vec<models::Invite>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), invites };
+    return Ok(warp::reply::json(&response).into_response());
 }
 
-/// Bans the given `public_key` if the requesting user is a moderator.
-pub fn ban(
-    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+/// Revokes the invite with the given `code` if the requesting user is a moderator. Users that
+/// already redeemed the invite stay on the allow list.
+pub fn revoke_invite(
+    code: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    // Validate the public key
-    if !is_valid_public_key(&public_key) {
-        warn!("Ignoring ban request for invalid public key.");
-        return Err(warp::reject::custom(Error::ValidationFailed));
-    }
     // Check authorization level
     let (has_authorization_level, _) =
         has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    // Don't double ban public keys
-    if is_banned(&public_key, pool)? {
-        return Ok(StatusCode::OK.into_response());
-    }
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Insert the message
-    let stmt = format!("INSERT INTO {} (public_key) VALUES (?1)", storage::BLOCK_LIST_TABLE);
-    match conn.execute(&stmt, params![public_key]) {
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Remove the invite
+    let stmt = format!("DELETE FROM {} WHERE code = (?1)", storage::INVITES_TABLE);
+    match conn.execute(&stmt, params![code]) {
         Ok(_) => (),
         Err(e) => {
-            error!("Couldn't ban public key due to error: {}.", e);
+            error!("Couldn't revoke invite due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
@@ -923,44 +4365,82 @@ pub fn ban(
     return Ok(warp::reply::json(&json).into_response());
 }
 
-/// Unbans the given `public_key` if the requesting user is a moderator.
-pub fn unban(
-    public_key: &str, auth_token: &str, pool: &storage::DatabaseConnectionPool,
+// General
+
+/// Returns the number of members of the room. If `breakdown=true` is passed, also returns the
+/// number of members that sent a message within the configured active member window.
+pub fn get_member_count(
+    query_params: HashMap<String, String>, auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
-    // Validate the public key
-    if !is_valid_public_key(&public_key) {
-        warn!("Ignoring unban request for invalid public key.");
-        return Err(warp::reject::custom(Error::ValidationFailed));
-    }
     // Check authorization level
     let (has_authorization_level, _) =
-        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+        has_authorization_level(auth_token, AuthorizationLevel::Basic, pool)?;
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
-    // Don't double unban public keys
-    if !is_banned(&public_key, pool)? {
-        return Ok(StatusCode::OK.into_response());
-    }
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Insert the message
-    let stmt = format!("DELETE FROM {} WHERE public_key = (?1)", storage::BLOCK_LIST_TABLE);
-    match conn.execute(&stmt, params![public_key]) {
-        Ok(_) => (),
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!("SELECT COUNT(DISTINCT public_key) FROM {}", storage::TOKENS_TABLE);
+    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
+    let rows = match query.query_map(params![], |row| row.get(0)) {
+        Ok(rows) => rows,
         Err(e) => {
-            error!("Couldn't unban public key due to error: {}.", e);
+            error!("Couldn't query database due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
+    let public_key_count: u32 = rows
+        .filter_map(|result| result.ok())
+        .next()
+        .ok_or_else(|| warp::reject::custom(Error::DatabaseFailedInternally))?;
+    // Return the breakdown if requested
+    if query_params.get("breakdown").map(|str| str.as_str()) == Some("true") {
+        // Message timestamps are in milliseconds
+        let window = super::ACTIVE_MEMBER_WINDOW.load(Ordering::SeqCst) as i64;
+        let cutoff = chrono::Utc::now().timestamp_millis() - window * 1000;
+        let raw_query = format!(
+            "SELECT COUNT(DISTINCT public_key) FROM {} WHERE timestamp > (?1) AND is_deleted = 0",
+            storage::MESSAGES_TABLE
+        );
+        let active_public_key_count: u32 =
+            match conn.query_row(&raw_query, params![cutoff], |row| row.get(0)) {
+                Ok(count) => count,
+                Err(e) => {
+                    error!("Couldn't query database due to error: {}.", e);
+                    return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+                }
+            };
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Response {
+            status_code: u16,
+            total: u32,
+            active: u32,
+        }
+        let response = Response {
+            status_code: StatusCode::OK.as_u16(),
+            total: public_key_count,
+            active: active_public_key_count,
+        };
+        return Ok(warp::reply::json(&response).into_response());
+    }
     // Return
-    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
-    return Ok(warp::reply::json(&json).into_response());
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        member_count: u32,
+    }
+    let response =
+        Response { status_code: StatusCode::OK.as_u16(), member_count: public_key_count };
+    return Ok(warp::reply::json(&response).into_response());
 }
 
-/// Returns the full list of banned public keys.
-pub fn get_banned_public_keys(
-    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+/// Returns the number of messages in the room that haven't been deleted, optionally only counting
+/// messages stored after `from_server_id`. Like for `get_messages`, only authorization uses
+/// `pool`.
+pub fn get_message_count(
+    query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool, read_pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
     // Check authorization level
     let (has_authorization_level, _) =
@@ -968,21 +4448,44 @@ pub fn get_banned_public_keys(
     if !has_authorization_level {
         return Err(warp::reject::custom(Error::Unauthorized));
     }
+    // Get a database connection
+    let conn = read_pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unwrap query parameters
+    let from_server_id: i64;
+    if let Some(str) = query_params.get("from_server_id") {
+        from_server_id = str.parse().unwrap_or(0);
+    } else {
+        from_server_id = 0;
+    }
+    // Query the database
+    let raw_query = format!(
+        "SELECT COUNT(id) FROM {} WHERE id > (?1) AND is_deleted = 0",
+        storage::MESSAGES_TABLE
+    );
+    let message_count: u32 =
+        match conn.query_row(&raw_query, params![from_server_id], |row| row.get(0)) {
+            Ok(count) => count,
+            Err(e) => {
+                error!("Couldn't query database due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
     // Return
-    let public_keys = get_banned_public_keys_vector(pool)?;
     #[derive(Debug, Deserialize, Serialize)]
     struct Response {
         status_code: u16,
-        banned_members: Vec<String>,
+        message_count: u32,
     }
-    let response = Response { status_code: StatusCode::OK.as_u16(), banned_members: public_keys };
+    let response = Response { status_code: StatusCode::OK.as_u16(), message_count };
     return Ok(warp::reply::json(&response).into_response());
 }
 
-// General
-
-pub fn get_member_count(
-    auth_token: &str, pool: &storage::DatabaseConnectionPool,
+/// Returns the server IDs of up to `limit` messages stored after `from_server_id` that haven't been
+/// deleted, oldest first, so that clients catching up can fetch only the messages they're missing.
+/// Like for `get_messages`, only authorization uses `pool`.
+pub fn get_message_ids(
+    query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool, read_pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
     // Check authorization level
     let (has_authorization_level, _) =
@@ -991,29 +4494,87 @@ pub fn get_member_count(
         return Err(warp::reject::custom(Error::Unauthorized));
     }
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = read_pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Unwrap query parameters
+    let from_server_id: i64 = match query_params.get("from_server_id") {
+        Some(str) => str.parse().unwrap_or(0),
+        None => 0,
+    };
+    let limit = get_page_size(&query_params);
     // Query the database
-    let raw_query = format!("SELECT COUNT(DISTINCT public_key) FROM {}", storage::TOKENS_TABLE);
+    let raw_query = format!(
+        "SELECT id FROM {} WHERE id > (?1) AND is_deleted = 0 ORDER BY id ASC LIMIT (?2)",
+        storage::MESSAGES_TABLE
+    );
     let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![], |row| row.get(0)) {
+    // Get one server ID more than requested to find out whether there are more messages
+    let rows = match query.query_map(params![from_server_id, limit as u32 + 1], |row| row.get(0)) {
         Ok(rows) => rows,
         Err(e) => {
-            error!("Couldn't query database due to error: {}.", e);
+            error!("Couldn't get message IDs due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
     };
-    let public_key_count: u32 = rows
-        .filter_map(|result| result.ok())
-        .next()
-        .ok_or_else(|| warp::reject::custom(Error::DatabaseFailedInternally))?;
+    let mut server_ids: Vec<i64> = rows.filter_map(|result| result.ok()).collect();
+    let has_more = server_ids.len() > limit as usize;
+    server_ids.truncate(limit as usize);
     // Return
     #[derive(Debug, Deserialize, Serialize)]
     struct Response {
         status_code: u16,
-        member_count: u32,
+        server_ids: Vec<i64>,
+        has_more: bool,
     }
-    let response =
-        Response { status_code: StatusCode::OK.as_u16(), member_count: public_key_count };
+    let response = Response { status_code: StatusCode::OK.as_u16(), server_ids, has_more };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Returns the number of messages the user with the given `public_key` has posted that haven't been
+/// deleted. If `since` is set only messages stored strictly after that time are counted.
+pub fn get_user_message_count(
+    public_key: &str, query_params: HashMap<String, String>, auth_token: &str,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check authorization level
+    let (has_authorization_level, _) =
+        has_authorization_level(auth_token, AuthorizationLevel::Moderator, pool)?;
+    if !has_authorization_level {
+        return Err(warp::reject::custom(Error::Unauthorized));
+    }
+    // Unwrap query parameters
+    let since: i64 = match query_params.get("since") {
+        Some(str) => match str.parse() {
+            Ok(since) => since,
+            Err(_) => {
+                warn!("Ignoring message count request with invalid since: {}.", str);
+                return Err(warp::reject::custom(Error::InvalidQuery));
+            }
+        },
+        None => 0,
+    };
+    // Get a database connection
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    // Messages without a timestamp are only counted if there's no time window
+    let raw_query = format!(
+        "SELECT COUNT(id) FROM {} WHERE public_key = (?1) AND is_deleted = 0 AND ((?2) = 0 OR timestamp > (?2))",
+        storage::MESSAGES_TABLE
+    );
+    let message_count: u32 =
+        match conn.query_row(&raw_query, params![public_key, since], |row| row.get(0)) {
+            Ok(count) => count,
+            Err(e) => {
+                error!("Couldn't query database due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        message_count: u32,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), message_count };
     return Ok(warp::reply::json(&response).into_response());
 }
 
@@ -1022,7 +4583,7 @@ pub fn compact_poll(
 ) -> Result<Response, Rejection> {
     let mut response_bodies: Vec<models::CompactPollResponseBody> = vec![];
     let main_pool = &storage::MAIN_POOL;
-    let main_conn = main_pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let main_conn = main_pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     for request_body in request_bodies {
         // Unwrap the request body
         let models::CompactPollRequestBody {
@@ -1050,16 +4611,25 @@ pub fn compact_poll(
                 continue;
             }
         };
-        // Get the database connection pool
+        // Get the database connection pools
         let pool = storage::pool_by_room_id(&room_id);
+        let read_pool = storage::read_pool_by_room_id(&room_id);
         // Get the new messages
         let mut get_messages_query_params: HashMap<String, String> = HashMap::new();
         if let Some(from_message_server_id) = from_message_server_id {
             get_messages_query_params
                 .insert("from_server_id".to_string(), from_message_server_id.to_string());
         }
-        let messages = match get_messages(get_messages_query_params, &auth_token, &pool) {
-            Ok(messages) => messages,
+        let messages = get_messages(get_messages_query_params, &auth_token, &pool, &read_pool);
+        let messages = match messages {
+            Ok(messages) => {
+                // Auth tokens for compact polls are in the body, so the RPC call handler can't
+                // record activity for them
+                if update_last_active(&auth_token, &pool).is_err() {
+                    warn!("Couldn't update last active timestamp.");
+                }
+                messages
+            }
             Err(e) => {
                 let status_code = super::errors::status_code(e);
                 let response_body = models::CompactPollResponseBody {
@@ -1079,7 +4649,12 @@ pub fn compact_poll(
             get_deletions_query_params
                 .insert("from_server_id".to_string(), from_deletion_server_id.to_string());
         }
-        let deletions = match get_deleted_messages(get_deletions_query_params, &auth_token, &pool) {
+        let deletions = match get_deleted_messages(
+            get_deletions_query_params,
+            &auth_token,
+            &pool,
+            &read_pool,
+        ) {
             Ok(deletions) => deletions,
             Err(e) => {
                 let status_code = super::errors::status_code(e);
@@ -1130,6 +4705,101 @@ pub fn compact_poll(
     return Ok(warp::reply::json(&response).into_response());
 }
 
+/// Returns the optional features this server supports, so that clients can avoid using features
+/// that older or differently configured servers don't have.
+pub fn get_capabilities() -> Result<Response, Rejection> {
+    let capabilities = super::CAPABILITIES.read().clone();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        capabilities: Vec<String>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), capabilities };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Returns the server's public key along with the versions of the server and the onion request
+/// protocol, so that clients can check that they're talking to the server they expect.
+pub fn get_server_info() -> Result<Response, Rejection> {
+    let public_key = super::HEX_PUBLIC_KEY.read().clone();
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        public_key: String,
+        version: String,
+        protocol_version: u16,
+    }
+    let response = Response {
+        status_code: StatusCode::OK.as_u16(),
+        public_key,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_version: onion_requests::PROTOCOL_VERSION,
+    };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// Returns a 200 if a connection can be obtained from `pool` and used to run a trivial query, and a
+/// 503 otherwise.
+pub fn check_health(pool: &storage::DatabaseConnectionPool) -> Result<Response, Rejection> {
+    let is_healthy = match pool.get() {
+        Ok(conn) => conn.query_row("SELECT 1", params![], |row| row.get::<_, i64>(0)).is_ok(),
+        Err(e) => {
+            error!("Couldn't get database connection for health check due to error: {}.", e);
+            false
+        }
+    };
+    let (status_code, status) = if is_healthy {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        status: String,
+    }
+    let response = Response { status_code: status_code.as_u16(), status: status.to_string() };
+    return Ok(warp::reply::with_status(warp::reply::json(&response), status_code).into_response());
+}
+
+// Not publicly exposed.
+pub async fn set_maintenance_mode(
+    body: models::MaintenanceModeRequestBody,
+) -> Result<Response, Rejection> {
+    // Only log actual transitions, so that the log shows when maintenance started and ended
+    let was_enabled = super::MAINTENANCE_MODE.swap(body.enabled, Ordering::SeqCst);
+    if body.enabled && !was_enabled {
+        warn!("Entered maintenance mode; writes are rejected until it's disabled.");
+    } else if !body.enabled && was_enabled {
+        info!("Left maintenance mode; writes are accepted again.");
+    }
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
+// Not publicly exposed.
+pub async fn reload_content_filter() -> Result<Response, Rejection> {
+    let path = match super::CONTENT_FILTER_FILE.read().clone() {
+        Some(path) => path,
+        None => {
+            warn!("Ignoring content filter reload because no content filter file is configured.");
+            return Err(warp::reject::custom(Error::ValidationFailed));
+        }
+    };
+    // Keep using the current filter if the new one is invalid
+    if let Err(e) = content_filtering::reload_content_filter(&path) {
+        error!("{}", e);
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Return
+    let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+    return Ok(warp::reply::json(&json).into_response());
+}
+
 // Not publicly exposed.
 pub async fn get_url() -> Result<Response, Rejection> {
     let url = super::get_url();
@@ -1143,7 +4813,7 @@ pub async fn get_session_version(platform: &str) -> Result<String, Rejection> {
         let last_updated = version_info.0;
         if now - last_updated < SESSION_VERSION_UPDATE_INTERVAL {
             let tag = version_info.1.to_string();
-            println!("Returning cached value: {}", tag);
+            debug!("Returning cached value: {}", tag);
             return Ok(tag);
         }
     }
@@ -1163,10 +4833,7 @@ pub async fn get_stats_for_room(
     room: String, query_map: HashMap<String, i64>,
 ) -> Result<Response, Rejection> {
     let now = chrono::Utc::now().timestamp();
-    let window = match query_map.get("window") {
-        Some(val) => val,
-        None => &3600i64,
-    };
+    let window = query_map.get("window").unwrap_or(&3600i64);
 
     let upperbound = match query_map.get("start") {
         Some(val) => val,
@@ -1175,7 +4842,7 @@ pub async fn get_stats_for_room(
 
     let lowerbound = upperbound - window;
     let pool = storage::pool_by_room_id(&room);
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
 
     let raw_query_users = format!(
         "SELECT COUNT(public_key) FROM {} WHERE last_active > ?1 AND last_active <= ?2",
@@ -1184,15 +4851,14 @@ pub async fn get_stats_for_room(
     let mut query_users =
         conn.prepare(&raw_query_users).map_err(|_| Error::DatabaseFailedInternally)?;
 
-    let active = match query_users
-        .query_row(params![lowerbound, upperbound], |row| Ok(row.get::<_, u32>(0)?))
-    {
-        Ok(row) => row,
-        Err(_e) => return Err(warp::reject::custom(Error::DatabaseFailedInternally)),
-    };
+    let active =
+        match query_users.query_row(params![lowerbound, upperbound], |row| row.get::<_, u32>(0)) {
+            Ok(row) => row,
+            Err(_e) => return Err(warp::reject::custom(Error::DatabaseFailedInternally)),
+        };
 
     let raw_query_posts = format!(
-        "SELECT COUNT(id) FROM {} WHERE timestamp >= ?1 AND timestamp <= ?2",
+        "SELECT COUNT(id) FROM {} WHERE timestamp >= ?1 AND timestamp <= ?2 AND is_deleted = 0",
         storage::MESSAGES_TABLE
     );
 
@@ -1200,7 +4866,7 @@ pub async fn get_stats_for_room(
         conn.prepare(&raw_query_posts).map_err(|_| Error::DatabaseFailedInternally)?;
 
     let posts = match query_posts
-        .query_row(params![lowerbound * 1000, upperbound * 1000], |row| Ok(row.get::<_, u32>(0)?))
+        .query_row(params![lowerbound * 1000, upperbound * 1000], |row| row.get::<_, u32>(0))
     {
         Ok(row) => row,
         Err(_e) => return Err(warp::reject::custom(Error::DatabaseFailedInternally)),
@@ -1221,7 +4887,7 @@ pub async fn get_stats_for_room(
 fn get_pending_tokens(
     public_key: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Vec<(i64, Vec<u8>)>, Rejection> {
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     let raw_query = format!(
         "SELECT timestamp, token FROM {} WHERE public_key = (?1) AND timestamp > (?2)",
         storage::PENDING_TOKENS_TABLE
@@ -1244,7 +4910,7 @@ fn get_pending_tokens(
 
 fn get_moderators_vector(pool: &storage::DatabaseConnectionPool) -> Result<Vec<String>, Rejection> {
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Query the database
     let raw_query = format!("SELECT public_key FROM {}", storage::MODERATORS_TABLE);
     let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
@@ -1259,22 +4925,92 @@ fn get_moderators_vector(pool: &storage::DatabaseConnectionPool) -> Result<Vec<S
     return Ok(rows.filter_map(|result| result.ok()).collect());
 }
 
+/// Returns the public key associated with the given `auth_token` along with whether that public
+/// key is a moderator, banned or muted, to help clients figure out why their requests are being
+/// rejected. Unlike other routes this doesn't reject banned users, and callers without a valid auth
+/// token get an anonymous result rather than an error.
+pub fn whoami(
+    auth_token: Option<&str>, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    let public_key = match auth_token {
+        Some(auth_token) => get_public_key_for_auth_token(auth_token, pool)?,
+        None => None,
+    };
+    let (is_moderator, is_banned, is_muted) = match &public_key {
+        Some(public_key) => {
+            let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+            (
+                is_moderator(public_key, pool)?,
+                is_banned(public_key, pool)?,
+                is_muted(public_key, &conn)?,
+            )
+        }
+        None => (false, false, false),
+    };
+    // Return
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        public_key: Option<String>,
+        is_moderator: bool,
+        is_banned: bool,
+        is_muted: bool,
+    }
+    let response = Response {
+        status_code: StatusCode::OK.as_u16(),
+        public_key,
+        is_moderator,
+        is_banned,
+        is_muted,
+    };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
 fn is_moderator(
     public_key: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<bool, Rejection> {
-    let public_keys = get_moderators_vector(&pool)?;
-    return Ok(public_keys.contains(&public_key.to_owned()));
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    return is_moderator_with_connection(public_key, &conn);
 }
 
-fn get_banned_public_keys_vector(
-    pool: &storage::DatabaseConnectionPool,
-) -> Result<Vec<String>, Rejection> {
+/// Like `is_moderator`, but uses the given connection, e.g. so that it can be used within a
+/// transaction.
+fn is_moderator_with_connection(
+    public_key: &str, conn: &rusqlite::Connection,
+) -> Result<bool, Rejection> {
+    let raw_query = format!(
+        "SELECT COUNT(public_key) FROM {} WHERE public_key = (?1)",
+        storage::MODERATORS_TABLE
+    );
+    match conn.query_row(&raw_query, params![public_key], |row| row.get::<_, u32>(0)) {
+        Ok(public_key_count) => return Ok(public_key_count != 0),
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
+}
+
+fn get_bans_vector(pool: &storage::DatabaseConnectionPool) -> Result<Vec<models::Ban>, Rejection> {
     // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Query the database
-    let raw_query = format!("SELECT public_key FROM {}", storage::BLOCK_LIST_TABLE);
+    // Expired bans are pruned periodically, but might not have been pruned yet
+    let raw_query = format!(
+        "SELECT public_key, banned_at, banned_until, reason, banned_by FROM {} WHERE banned_until IS NULL OR banned_until > (?1)",
+        storage::BLOCK_LIST_TABLE
+    );
+    let now = chrono::Utc::now().timestamp();
     let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![], |row| row.get(0)) {
+    let rows = match query.query_map(params![now], |row| {
+        Ok(models::Ban {
+            public_key: row.get(0)?,
+            banned_at: row.get(1)?,
+            banned_until: row.get(2)?,
+            reason: row.get(3)?,
+            banned_by: row.get(4)?,
+        })
+    }) {
         Ok(rows) => rows,
         Err(e) => {
             error!("Couldn't query database due to error: {}.", e);
@@ -1285,58 +5021,126 @@ fn get_banned_public_keys_vector(
     return Ok(rows.filter_map(|result| result.ok()).collect());
 }
 
-fn is_banned(public_key: &str, pool: &storage::DatabaseConnectionPool) -> Result<bool, Rejection> {
-    // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
-    // Query the database
+/// Rejects the given number of messages from `public_key` if they'd exceed the maximum number of
+/// messages per minute. Rooms that override the server wide maximum keep their own count, so that
+/// messages posted elsewhere don't count towards the room's limit and vice versa.
+/// Takes the room info rather than getting it, because this is called while a transaction is open
+/// and getting the room info might need another database connection.
+fn check_message_rate_limit(
+    room_id: &str, room_info: &models::RoomInfo, public_key: &str, message_count: usize,
+) -> Result<(), Rejection> {
+    // The server wide maximum also caps the maximum of rooms that override it, in case it was
+    // lowered after the override was set
+    let server_max_messages_per_minute = super::MAX_MESSAGES_PER_MINUTE.load(Ordering::SeqCst);
+    let (key, max_messages_per_minute) = match room_info.max_messages_per_minute {
+        Some(max_messages_per_minute) => (
+            format!("{}/{}", room_id, public_key),
+            max_messages_per_minute.min(server_max_messages_per_minute),
+        ),
+        None => (public_key.to_string(), server_max_messages_per_minute),
+    };
+    // Batches that could never fit within the limit shouldn't be retried
+    let message_count = message_count as u32;
+    if message_count > max_messages_per_minute {
+        warn!("Ignoring {} messages, which is more than the rate limit allows.", message_count);
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    // Tokens are only taken if there are enough for every message, so that a rejected batch
+    // doesn't count towards the limit
+    let rate_limiter = &rate_limiting::MESSAGE_RATE_LIMITER;
+    if !rate_limiter.try_acquire(&key, message_count, max_messages_per_minute) {
+        // Round up to at least a second, so that clients that retry right away aren't rejected
+        // again
+        let retry_after = rate_limiter
+            .seconds_until_available(&key, message_count, max_messages_per_minute)
+            .max(1);
+        return Err(warp::reject::custom(Error::RateLimited(retry_after)));
+    }
+    return Ok(());
+}
+
+/// Returns whether only users on the allow list (and moderators) can post in the given room, either
+/// because it's invite only or because the server is in allow list mode.
+fn requires_invite(room_info: &models::RoomInfo) -> bool {
+    if super::ALLOW_LIST_MODE.load(Ordering::SeqCst) {
+        return true;
+    }
+    return room_info.invite_only;
+}
+
+fn is_allowed(public_key: &str, conn: &rusqlite::Connection) -> Result<bool, Rejection> {
     let raw_query = format!(
         "SELECT COUNT(public_key) FROM {} WHERE public_key = (?1)",
-        storage::BLOCK_LIST_TABLE
+        storage::ALLOW_LIST_TABLE
     );
-    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![public_key], |row| row.get(0)) {
-        Ok(rows) => rows,
+    match conn.query_row(&raw_query, params![public_key], |row| row.get::<_, u32>(0)) {
+        Ok(public_key_count) => return Ok(public_key_count != 0),
         Err(e) => {
             error!("Couldn't query database due to error: {}.", e);
             return Err(warp::reject::custom(Error::DatabaseFailedInternally));
         }
-    };
-    let public_key_count: u32 = rows
-        .filter_map(|result| result.ok())
-        .next()
-        .ok_or_else(|| warp::reject::custom(Error::DatabaseFailedInternally))?;
-    return Ok(public_key_count != 0);
+    }
+}
+
+fn is_muted(public_key: &str, conn: &rusqlite::Connection) -> Result<bool, Rejection> {
+    let raw_query = format!(
+        "SELECT COUNT(public_key) FROM {} WHERE public_key = (?1)",
+        storage::MUTE_LIST_TABLE
+    );
+    match conn.query_row(&raw_query, params![public_key], |row| row.get::<_, u32>(0)) {
+        Ok(public_key_count) => return Ok(public_key_count != 0),
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+        }
+    }
 }
 
-fn is_valid_public_key(public_key: &str) -> bool {
-    // Check that it's a valid hex encoding
-    if hex::decode(public_key).is_err() {
-        return false;
+/// Returns whether `public_key` is currently banned from the room of `pool`. This is the only place
+/// where bans are checked; every endpoint that needs authorization goes through here (by way of
+/// `has_authorization_level`), so banned users are rejected consistently.
+pub fn is_banned(
+    public_key: &str, pool: &storage::DatabaseConnectionPool,
+) -> Result<bool, Rejection> {
+    // The block list isn't used in allow list mode
+    if super::ALLOW_LIST_MODE.load(Ordering::SeqCst) {
+        return Ok(false);
     }
-    // Check that it's the right length
-    if public_key.len() != 66 {
-        return false;
-    } // The version byte + 32 bytes of random data
-      // It appears to be a valid public key
-    return true;
+    // Expired bans are pruned periodically, but might not have been pruned yet
+    let now = chrono::Utc::now().timestamp();
+    let generation = match pool.get_cached_ban_status(public_key, now) {
+        Ok(is_banned) => return Ok(is_banned),
+        Err(generation) => generation,
+    };
+    // Load the block list from the database
+    // This runs for every authorized request, so it's retried if the database hiccups
+    let raw_query = format!("SELECT public_key, banned_until FROM {}", storage::BLOCK_LIST_TABLE);
+    let bans: HashMap<String, Option<i64>> = storage::query_with_retry(pool, |conn| {
+        let mut query = conn.prepare(&raw_query)?;
+        let rows = query.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        return rows.collect();
+    })?;
+    let is_banned = match bans.get(public_key) {
+        Some(banned_until) => banned_until.is_none_or(|t| t > now),
+        None => false,
+    };
+    pool.cache_block_list(bans, generation);
+    return Ok(is_banned);
 }
 
 fn get_public_key_for_auth_token(
     auth_token: &str, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Option<String>, Rejection> {
-    // Get a database connection
-    let conn = pool.get().map_err(|_| Error::DatabaseFailedInternally)?;
     // Query the database
+    // This runs for every authorized request, so it's retried if the database hiccups
     let raw_query = format!("SELECT public_key FROM {} WHERE token = (?1)", storage::TOKENS_TABLE);
-    let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
-    let rows = match query.query_map(params![auth_token], |row| row.get(0)) {
-        Ok(rows) => rows,
-        Err(e) => {
-            error!("Couldn't query database due to error: {}.", e);
-            return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+    let public_key = storage::query_with_retry(pool, |conn| {
+        match conn.query_row(&raw_query, params![auth_token], |row| row.get(0)) {
+            Ok(public_key) => return Ok(Some(public_key)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e),
         }
-    };
-    let public_key: Option<String> = rows.filter_map(|result| result.ok()).next();
+    })?;
     // Return
     return Ok(public_key);
 }
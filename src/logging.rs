@@ -1,3 +1,5 @@
+use std::future::Future;
+
 use log::LevelFilter;
 use log4rs::{
     append::{
@@ -9,18 +11,54 @@ use log4rs::{
     filter::threshold::ThresholdFilter,
 };
 
+/// The MDC key under which the ID of the request that's currently being handled is stored.
+pub const REQUEST_ID_KEY: &str = "request_id";
+
+// Log lines that aren't produced while handling a request show "-" instead of a request ID
+const CONSOLE_PATTERN: &str = "{h({l})} {d} [{X(request_id)(-)}] {m}{n}";
+const FILE_PATTERN: &str = "{l} {d} [{X(request_id)(-)}] {m}{n}";
+
+/// Runs `future`, tagging every log line it produces with `request_id`.
+///
+/// The MDC is thread local and a future can be moved between threads whenever it yields, so the
+/// request ID is set every time the future is polled rather than once up front. Calls can be
+/// nested, in which case the outer request ID is restored once the inner future yields.
+pub async fn with_request_id<F: Future>(request_id: &str, future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    return futures::future::poll_fn(|cx| {
+        let previous_request_id = log_mdc::insert(REQUEST_ID_KEY, request_id);
+        let result = future.as_mut().poll(cx);
+        match previous_request_id {
+            Some(previous_request_id) => log_mdc::insert(REQUEST_ID_KEY, previous_request_id),
+            None => log_mdc::remove(REQUEST_ID_KEY),
+        };
+        return result;
+    })
+    .await;
+}
+
+/// Returns the request ID set by the enclosing `with_request_id` call, if any.
+pub fn current_request_id() -> Option<String> {
+    return log_mdc::get(REQUEST_ID_KEY, |request_id| request_id.map(|s| s.to_string()));
+}
+
 pub fn init(log_file: Option<String>) {
-    let console_level = LevelFilter::Debug;
+    // The console level can be overridden using e.g. `RUST_LOG=warn`
+    let console_level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::Debug);
     let file_level = LevelFilter::Info;
     let stdout_appender = {
-        let encoder = Box::new(PatternEncoder::new("{h({l})} {d} - {m}{n}"));
+        let encoder = Box::new(PatternEncoder::new(CONSOLE_PATTERN));
         let stdout = ConsoleAppender::builder().encoder(encoder).build();
         let filter = Box::new(ThresholdFilter::new(console_level));
         Appender::builder().filter(filter).build("stdout", Box::new(stdout))
     };
     let mut root = Root::builder().appender("stdout");
-    // Increase SOGS logging level to debug
-    let sogs = Logger::builder().build("session_open_group_server", LevelFilter::Debug);
+    // Log SOGS messages at whichever of the console and file levels is more verbose
+    let sogs_level = std::cmp::max(console_level, file_level);
+    let sogs = Logger::builder().build("session_open_group_server", sogs_level);
     let mut config_builder = log4rs::Config::builder().logger(sogs).appender(stdout_appender);
     if let Some(log_file) = log_file {
         // Rotate log files every ~50MB keeping 1 archived
@@ -30,8 +68,11 @@ pub fn init(log_file: Option<String>) {
             .unwrap();
         let roll_policy = compound::CompoundPolicy::new(Box::new(size_trigger), Box::new(roller));
         // Print to the file at info level
-        let file_appender =
-            RollingFileAppender::builder().build(&log_file, Box::new(roll_policy)).unwrap();
+        let encoder = Box::new(PatternEncoder::new(FILE_PATTERN));
+        let file_appender = RollingFileAppender::builder()
+            .encoder(encoder)
+            .build(&log_file, Box::new(roll_policy))
+            .unwrap();
         let filter = Box::new(ThresholdFilter::new(file_level));
         let file_appender =
             Appender::builder().filter(filter).build("file", Box::new(file_appender));
@@ -2,59 +2,192 @@
 
 use parking_lot::RwLock;
 use std::fs;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::{
     collections::HashMap,
-    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering},
 };
 
 use futures::join;
-use log::info;
+use log::{error, info};
 use structopt::StructOpt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use warp::Filter;
 
+mod config;
+mod content_filtering;
 mod crypto;
 mod errors;
+mod file_scanning;
 mod handlers;
 mod logging;
+mod metrics;
 mod models;
 mod onion_requests;
 mod options;
+mod rate_limiting;
 mod routes;
 mod rpc;
 mod storage;
+mod websockets;
 
 #[cfg(test)]
 mod tests;
 
 const LOCALHOST_PORT: u16 = 3030;
 
+// Exit code used when the database is still unavailable after retrying, so that process supervisors
+// can tell it apart from configuration errors (EX_TEMPFAIL in sysexits.h)
+const DATABASE_UNAVAILABLE_EXIT_CODE: i32 = 75;
+
 lazy_static::lazy_static! {
 
     pub static ref USES_TLS: AtomicBool = AtomicBool::new(false);
+    pub static ref MAINTENANCE_MODE: AtomicBool = AtomicBool::new(false);
+    pub static ref REQUIRE_AUTH_FOR_READS: AtomicBool = AtomicBool::new(false);
+    pub static ref ALLOW_LIST_MODE: AtomicBool = AtomicBool::new(false);
     pub static ref PORT: AtomicU16 = AtomicU16::new(0);
+    pub static ref MAX_FILE_SIZE: AtomicUsize = AtomicUsize::new(10 * 1024 * 1024);
+    pub static ref UPLOAD_EXPIRATION: AtomicU64 = AtomicU64::new(24 * 60 * 60);
+    pub static ref MAX_MESSAGE_SIZE: AtomicUsize = AtomicUsize::new(64 * 1024);
+    pub static ref MAX_REQUEST_SIZE: AtomicU64 = AtomicU64::new(10 * 1024 * 1024);
+    pub static ref MAX_MESSAGES_PER_MINUTE: AtomicU32 = AtomicU32::new(20);
+    pub static ref MAX_REQUESTS_PER_ADDRESS: AtomicU32 = AtomicU32::new(1200);
+    pub static ref ADDRESS_BLOCK_DURATION: AtomicU64 = AtomicU64::new(5 * 60);
+    pub static ref MAX_TIMESTAMP_SKEW: AtomicU64 = AtomicU64::new(24 * 60 * 60);
+    pub static ref MAX_PINNED_MESSAGES: AtomicU32 = AtomicU32::new(3);
+    pub static ref MAX_PAGE_SIZE: AtomicU16 = AtomicU16::new(256);
+    pub static ref DEFAULT_PAGE_SIZE: AtomicU16 = AtomicU16::new(256);
+    pub static ref POLL_INTERVAL: AtomicU64 = AtomicU64::new(0);
+    pub static ref POLL_INTERVAL_LOAD_THRESHOLD: AtomicU64 = AtomicU64::new(0);
+    pub static ref ACTIVE_MEMBER_WINDOW: AtomicU64 = AtomicU64::new(7 * 24 * 60 * 60);
+    pub static ref DUPLICATE_MESSAGE_WINDOW: AtomicU64 = AtomicU64::new(0);
+    pub static ref MESSAGE_RESTORE_WINDOW: AtomicU64 = AtomicU64::new(60 * 60);
+    pub static ref MESSAGE_RETENTION_PERIOD: AtomicU64 = AtomicU64::new(0);
+    pub static ref MESSAGE_RETENTION_SWEEP_INTERVAL: AtomicU64 = AtomicU64::new(60 * 60);
+    pub static ref DB_POOL_SIZE: AtomicU32 = AtomicU32::new(10);
+    pub static ref DB_CONNECTION_TIMEOUT: AtomicU64 = AtomicU64::new(30);
     pub static ref HEX_PUBLIC_KEY: RwLock<String> = RwLock::new("".to_string());
+    pub static ref CORS_ALLOWED_ORIGINS: RwLock<Vec<String>> = RwLock::new(vec![]);
+    pub static ref CAPABILITIES: RwLock<Vec<String>> = RwLock::new(vec![]);
+    pub static ref WELCOME_MESSAGE: RwLock<Option<String>> = RwLock::new(None);
+    pub static ref TRUSTED_PROXY_HEADER: RwLock<Option<String>> = RwLock::new(None);
+    pub static ref TRUSTED_PROXIES: RwLock<Vec<IpAddr>> = RwLock::new(vec![]);
+    pub static ref READ_REPLICA_DIR: RwLock<Option<String>> = RwLock::new(None);
+    pub static ref CONTENT_FILTER_FILE: RwLock<Option<String>> = RwLock::new(None);
+    pub static ref ADMIN_TOKEN_HASH: RwLock<Option<String>> = RwLock::new(None);
 }
 
 #[tokio::main]
 async fn main() {
     // Parse arguments
-    let opt = options::Opt::from_args();
-    if opt.add_room.is_some()
+    let mut opt = options::Opt::from_args();
+    if opt.hash_admin_token {
+        // This doesn't need the server to be running
+        hash_admin_token();
+    } else if opt.add_room.is_some()
         || opt.delete_room.is_some()
         || opt.add_moderator.is_some()
         || opt.delete_moderator.is_some()
         || opt.print_url
+        || opt.enable_maintenance_mode
+        || opt.disable_maintenance_mode
+        || opt.reload_content_filter
     {
         // Run in command mode
         execute_commands(opt).await;
     } else {
-        // Store the port and TLS mode
+        // Load the config file if there is one
+        // Logging isn't set up yet at this point, so errors are printed directly
+        if let Some(path) = opt.config.clone() {
+            match config::Config::load(&path).and_then(|config| config.apply(&mut opt)) {
+                Ok(()) => (),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let Err(e) = config::validate(&opt) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        // Store the port, TLS mode and limits
         PORT.store(opt.port, Ordering::SeqCst);
         USES_TLS.store(opt.tls, Ordering::SeqCst);
+        REQUIRE_AUTH_FOR_READS.store(opt.require_auth_for_reads, Ordering::SeqCst);
+        ALLOW_LIST_MODE.store(opt.allow_list_mode, Ordering::SeqCst);
+        MAX_FILE_SIZE.store(opt.max_file_size, Ordering::SeqCst);
+        UPLOAD_EXPIRATION.store(opt.upload_expiration, Ordering::SeqCst);
+        MAX_MESSAGE_SIZE.store(opt.max_message_size, Ordering::SeqCst);
+        MAX_REQUEST_SIZE.store(opt.max_request_size, Ordering::SeqCst);
+        MAX_MESSAGES_PER_MINUTE.store(opt.max_messages_per_minute, Ordering::SeqCst);
+        MAX_REQUESTS_PER_ADDRESS.store(opt.max_requests_per_address, Ordering::SeqCst);
+        ADDRESS_BLOCK_DURATION.store(opt.address_block_duration, Ordering::SeqCst);
+        MAX_TIMESTAMP_SKEW.store(opt.max_timestamp_skew, Ordering::SeqCst);
+        MAX_PINNED_MESSAGES.store(opt.max_pinned_messages, Ordering::SeqCst);
+        MAX_PAGE_SIZE.store(opt.max_page_size, Ordering::SeqCst);
+        DEFAULT_PAGE_SIZE.store(opt.default_page_size, Ordering::SeqCst);
+        POLL_INTERVAL.store(opt.poll_interval, Ordering::SeqCst);
+        POLL_INTERVAL_LOAD_THRESHOLD.store(opt.poll_interval_load_threshold, Ordering::SeqCst);
+        ACTIVE_MEMBER_WINDOW.store(opt.active_member_window, Ordering::SeqCst);
+        DUPLICATE_MESSAGE_WINDOW.store(opt.duplicate_message_window, Ordering::SeqCst);
+        MESSAGE_RESTORE_WINDOW.store(opt.message_restore_window, Ordering::SeqCst);
+        MESSAGE_RETENTION_PERIOD.store(opt.message_retention_period, Ordering::SeqCst);
+        MESSAGE_RETENTION_SWEEP_INTERVAL
+            .store(opt.message_retention_sweep_interval, Ordering::SeqCst);
+        DB_POOL_SIZE.store(opt.db_pool_size, Ordering::SeqCst);
+        DB_CONNECTION_TIMEOUT.store(opt.db_connection_timeout, Ordering::SeqCst);
+        *CAPABILITIES.write() = config::get_capabilities(&opt);
+        *WELCOME_MESSAGE.write() = opt.welcome_message.clone();
+        *TRUSTED_PROXY_HEADER.write() = opt.trusted_proxy_header.clone();
+        *TRUSTED_PROXIES.write() = opt.trusted_proxies.clone();
+        *READ_REPLICA_DIR.write() = opt.read_replica_dir.clone();
+        *ADMIN_TOKEN_HASH.write() = opt.admin_token_hash.clone();
         // Run in server mode
         logging::init(opt.log_file);
-        let addr = SocketAddr::new(IpAddr::V4(opt.host), opt.port);
+        // Store the CORS configuration
+        if let Some(origin) =
+            opt.cors_allowed_origins.iter().find(|origin| !routes::is_valid_origin(origin))
+        {
+            error!("Invalid CORS origin: {}. Expected e.g. https://example.com.", origin);
+            std::process::exit(1);
+        }
+        *CORS_ALLOWED_ORIGINS.write() = opt.cors_allowed_origins;
+        // Load the content filter
+        if let Some(path) = &opt.content_filter_file {
+            if let Err(e) = content_filtering::reload_content_filter(path) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        *CONTENT_FILTER_FILE.write() = opt.content_filter_file;
+        // Set up the file scanner
+        match file_scanning::create_file_scanner(&opt.file_scanner, &opt.allowed_file_types) {
+            Ok(file_scanner) => *file_scanning::FILE_SCANNER.write() = file_scanner,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        // Check the TLS files up front; warp only reads them when binding and panics if that fails
+        if opt.tls {
+            for path in [&opt.tls_certificate, &opt.tls_private_key].iter() {
+                match fs::read_to_string(path) {
+                    Ok(contents) if contents.contains("-----BEGIN ") => (),
+                    Ok(_) => {
+                        error!("Couldn't load TLS file: {}. Expected a PEM encoded file.", path);
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        error!("Couldn't read TLS file: {} due to error: {}.", path, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        let addr = SocketAddr::new(opt.host, opt.port);
         let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), LOCALHOST_PORT);
         *crypto::PRIVATE_KEY_PATH.lock().unwrap() = opt.x25519_private_key;
         *crypto::PUBLIC_KEY_PATH.lock().unwrap() = opt.x25519_public_key;
@@ -64,72 +197,164 @@ async fn main() {
         info!("Users can join rooms on this open group server using the following URL format:");
         info!("{}", get_url());
         // Create the main database
-        storage::create_main_database_if_needed();
+        if storage::create_main_database_with_retries(opt.db_startup_retries).await.is_err() {
+            error!("Aborting startup because the main database couldn't be opened.");
+            std::process::exit(DATABASE_UNAVAILABLE_EXIT_CODE);
+        }
         // Create required folders
         fs::create_dir_all("./rooms").unwrap();
         fs::create_dir_all("./files").unwrap();
+        // Perform migration
+        // Don't serve requests against databases that are only partially migrated
+        match storage::perform_migration(opt.db_startup_retries).await {
+            Ok(()) => (),
+            Err(errors::Error::DatabaseUnavailable) => {
+                error!("Aborting startup because not all room databases could be opened.");
+                std::process::exit(DATABASE_UNAVAILABLE_EXIT_CODE);
+            }
+            Err(_) => {
+                error!("Aborting startup because not all room databases could be migrated.");
+                std::process::exit(1);
+            }
+        }
         // Create default rooms
         create_default_rooms().await;
-        // Perform migration
-        storage::perform_migration();
         // Set up pruning jobs
-        let prune_pending_tokens_future = storage::prune_pending_tokens_periodically();
-        let prune_tokens_future = storage::prune_tokens_periodically();
-        let prune_files_future = storage::prune_files_periodically();
+        tokio::spawn(storage::prune_pending_tokens_periodically());
+        tokio::spawn(storage::prune_tokens_periodically());
+        tokio::spawn(storage::prune_files_periodically());
+        tokio::spawn(storage::prune_expired_uploads_periodically());
+        tokio::spawn(storage::prune_expired_bans_periodically());
+        tokio::spawn(storage::prune_expired_cooldowns_periodically());
+        tokio::spawn(storage::scrub_deleted_messages_periodically());
+        tokio::spawn(storage::prune_idempotency_keys_periodically());
+        if MESSAGE_RETENTION_PERIOD.load(Ordering::SeqCst) > 0 {
+            tokio::spawn(storage::expire_old_messages_periodically());
+        }
+        tokio::spawn(rate_limiting::prune_message_rate_limiter_periodically());
+        tokio::spawn(rate_limiting::prune_address_rate_limiter_periodically());
+        // Set up graceful shutdown
+        // Both servers stop accepting new connections once a shutdown signal is received, but wait
+        // for in-flight requests to finish before completing
+        let (shutdown_sender, shutdown_receiver) = watch::channel(());
+        tokio::spawn(async move {
+            wait_for_shutdown_signal().await;
+            info!("Shutting down; waiting for in-flight requests to finish.");
+            let _ = shutdown_sender.send(());
+        });
         // Serve routes
-        let public_routes = routes::root().or(routes::fallback()).or(routes::lsrpc());
-        let private_routes = routes::create_room()
-            .or(routes::delete_room())
-            .or(routes::add_moderator())
-            .or(routes::delete_moderator())
-            .or(routes::get_room_stats())
-            .or(routes::get_url());
+        let public_routes = routes::root()
+            .or(routes::health())
+            .or(routes::server_info())
+            .or(routes::metrics())
+            .or(routes::websocket())
+            .or(routes::export())
+            .or(routes::fallback())
+            .or(routes::lsrpc())
+            .with(routes::cors());
+        let private_routes = routes::require_admin_token()
+            .and(
+                routes::create_room()
+                    .or(routes::delete_room())
+                    .or(routes::add_moderator())
+                    .or(routes::delete_moderator())
+                    .or(routes::get_room_stats())
+                    .or(routes::set_maintenance_mode())
+                    .or(routes::reload_content_filter())
+                    .or(routes::get_url()),
+            )
+            .recover(routes::into_response);
+        let (_, serve_private_routes_future) = warp::serve(private_routes)
+            .bind_with_graceful_shutdown(localhost, shutdown(shutdown_receiver.clone()));
+        // Whether binding to [::] also accepts IPv4 connections depends on the OS; on Linux it does
+        // unless net.ipv6.bindv6only is set
+        if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+            info!(
+                "Binding to all IPv6 addresses; IPv4 connections are accepted if the OS allows it."
+            );
+        }
         if opt.tls {
-            info!("Running on {} with TLS.", addr);
-            let serve_public_routes_future = warp::serve(public_routes)
+            let (addr, serve_public_routes_future) = warp::serve(public_routes)
                 .tls()
                 .cert_path(opt.tls_certificate)
                 .key_path(opt.tls_private_key)
-                .run(addr);
-            let serve_private_routes_future = warp::serve(private_routes).run(localhost);
+                .bind_with_graceful_shutdown(addr, shutdown(shutdown_receiver));
+            info!("Running on {} with TLS.", addr);
             // Keep futures alive
-            join!(
-                prune_pending_tokens_future,
-                prune_tokens_future,
-                prune_files_future,
-                serve_public_routes_future,
-                serve_private_routes_future
-            );
+            join!(serve_public_routes_future, serve_private_routes_future);
         } else {
+            let (addr, serve_public_routes_future) = match warp::serve(public_routes)
+                .try_bind_with_graceful_shutdown(addr, shutdown(shutdown_receiver))
+            {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Couldn't bind to {} due to error: {}.", addr, e);
+                    std::process::exit(1);
+                }
+            };
             info!("Running on {}.", addr);
-            let serve_public_routes_future = warp::serve(public_routes).run(addr);
-            let serve_private_routes_future = warp::serve(private_routes).run(localhost);
             // Keep futures alive
-            join!(
-                prune_pending_tokens_future,
-                prune_tokens_future,
-                prune_files_future,
-                serve_public_routes_future,
-                serve_private_routes_future
-            );
+            join!(serve_public_routes_future, serve_private_routes_future);
         }
+        // Close the database connections now that there are no more in-flight requests
+        storage::close_room_pools();
+        info!("Shutdown complete.");
     }
 }
 
+/// Resolves once SIGTERM or SIGINT is received. If listening for a signal fails the server keeps
+/// running, but can only be shut down by the other signal (or not gracefully at all).
+async fn wait_for_shutdown_signal() {
+    let sigterm = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Couldn't listen for SIGTERM due to error: {}.", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    let sigint = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Couldn't listen for SIGINT due to error: {}.", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = sigterm => info!("Received SIGTERM."),
+        _ = sigint => info!("Received SIGINT."),
+    }
+}
+
+async fn shutdown(mut receiver: watch::Receiver<()>) {
+    // This also resolves if the sender is dropped, which is fine
+    let _ = receiver.changed().await;
+}
+
 async fn execute_commands(opt: options::Opt) {
     let client = reqwest::Client::new();
     let localhost = format!("http://127.0.0.1:{}", LOCALHOST_PORT);
+    // Include the admin token in every request if there is one
+    let admin_token = opt.admin_token.clone();
+    let authorize = |request: reqwest::RequestBuilder| match &admin_token {
+        Some(admin_token) => request.bearer_auth(admin_token),
+        None => request,
+    };
     // Add a room
     if let Some(args) = opt.add_room {
         let mut params = HashMap::new();
         params.insert("id", &args[0]);
         params.insert("name", &args[1]);
-        client.post(format!("{}/rooms", localhost)).json(&params).send().await.unwrap();
+        let request = authorize(client.post(format!("{}/rooms", localhost))).json(&params);
+        send_command(request, "add room").await;
         println!("Added room with ID: {}", &args[0]);
     }
     // Delete a room
     if let Some(args) = opt.delete_room {
-        client.delete(format!("{}/rooms/{}", localhost, args)).send().await.unwrap();
+        let request = authorize(client.delete(format!("{}/rooms/{}", localhost, args)));
+        send_command(request, "delete room").await;
         println!("Deleted room with ID: {}", &args);
     }
     // Add a moderator
@@ -137,7 +362,8 @@ async fn execute_commands(opt: options::Opt) {
         let mut params = HashMap::new();
         params.insert("public_key", &args[0]);
         params.insert("room_id", &args[1]);
-        client.post(format!("{}/moderators", localhost)).json(&params).send().await.unwrap();
+        let request = authorize(client.post(format!("{}/moderators", localhost))).json(&params);
+        send_command(request, "add moderator").await;
         println!("Added moderator: {} to room with ID: {}", &args[0], &args[1]);
     }
     // Delete a moderator
@@ -145,22 +371,77 @@ async fn execute_commands(opt: options::Opt) {
         let mut params = HashMap::new();
         params.insert("public_key", &args[0]);
         params.insert("room_id", &args[1]);
-        client.post(format!("{}/delete_moderator", localhost)).json(&params).send().await.unwrap();
+        let request =
+            authorize(client.post(format!("{}/delete_moderator", localhost))).json(&params);
+        send_command(request, "delete moderator").await;
         println!("Deleted moderator: {} from room with ID: {}", &args[0], &args[1]);
     }
+    // Enter or leave maintenance mode
+    if opt.enable_maintenance_mode || opt.disable_maintenance_mode {
+        let mut params = HashMap::new();
+        params.insert("enabled", opt.enable_maintenance_mode);
+        let request = authorize(client.post(format!("{}/maintenance", localhost))).json(&params);
+        send_command(request, "change maintenance mode").await;
+        if opt.enable_maintenance_mode {
+            println!("Enabled maintenance mode");
+        } else {
+            println!("Disabled maintenance mode");
+        }
+    }
+    // Reload the content filter
+    if opt.reload_content_filter {
+        let request = authorize(client.post(format!("{}/content_filter/reload", localhost)));
+        send_command(request, "reload content filter; see the server logs for details").await;
+        println!("Reloaded content filter");
+    }
     // Print URL
     if opt.print_url {
-        let response =
-            client.get(format!("{}/url", localhost)).send().await.unwrap().text().await.unwrap();
+        let request = authorize(client.get(format!("{}/url", localhost)));
+        let response = send_command(request, "get URL").await.text().await.unwrap_or_default();
         println!("Users can join rooms on this open group server using the following URL format:");
         println!("{}", response);
     }
 }
 
+/// Sends a server management request, and exits with a non-zero exit code if the server can't be
+/// reached or rejects the request, so that scripts can tell whether a command succeeded.
+async fn send_command(request: reqwest::RequestBuilder, description: &str) -> reqwest::Response {
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Couldn't {} due to error: {}.", description, e);
+            std::process::exit(1);
+        }
+    };
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        eprintln!("Couldn't {} (status code {}): {}", description, status.as_u16(), body);
+        std::process::exit(1);
+    }
+    return response;
+}
+
+/// Reads an admin token from standard input and prints its hash.
+fn hash_admin_token() {
+    eprintln!("Enter the admin token:");
+    let mut admin_token = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut admin_token) {
+        eprintln!("Couldn't read admin token due to error: {}.", e);
+        std::process::exit(1);
+    }
+    let admin_token = admin_token.trim_end_matches(&['\r', '\n'][..]);
+    if admin_token.is_empty() {
+        eprintln!("The admin token can't be empty.");
+        std::process::exit(1);
+    }
+    println!("{}", crypto::hash_admin_token(admin_token));
+}
+
 fn get_url() -> String {
     let uses_tls: bool = USES_TLS.load(Ordering::SeqCst);
     let port: u16 = PORT.load(Ordering::SeqCst);
-    let hex_public_key: &str = &*HEX_PUBLIC_KEY.read();
+    let hex_public_key: &str = &HEX_PUBLIC_KEY.read();
     let protocol = if uses_tls { "https" } else { "http" };
     let is_port_implicit = (port == 80 && !uses_tls) || (port == 443 && uses_tls);
     return format!(
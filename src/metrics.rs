@@ -0,0 +1,108 @@
+use log::error;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use super::crypto;
+
+/// The normalized endpoints of all routes. Calls to anything else are labeled `unknown`, because
+/// otherwise anyone could create arbitrarily many time series.
+#[rustfmt::skip]
+const ENDPOINTS: [&str; 62] = [
+    "auth_token_challenge", "rooms", "rooms/:room_id", "rooms/:room_id/image", "health",
+    "server_info", "capabilities", "session_version", "files", "files/:id", "files/init",
+    "files/:upload_id/progress", "files/:upload_id/complete", "files/:upload_id/chunk", "whoami",
+    "profile", "profile/:public_key", "block_list", "block_list/:public_key", "mute_list",
+    "mute_list/:public_key", "allow_list", "allow_list/:public_key", "cooldowns",
+    "cooldowns/:public_key", "invites", "invites/:code", "invites/:code/redeem", "moderators",
+    "moderators/bulk", "moderators/:public_key", "messages", "messages/batch", "messages/missing",
+    "messages/:id", "messages/:id/thread", "messages/:id/context", "messages/:id/reactions",
+    "messages/:id/reactions/:emoji", "messages/:id/report", "messages/:id/pin",
+    "messages/:id/restore", "messages/:id/read", "messages/by_user/:public_key",
+    "messages/count_by_user/:public_key", "search", "deleted_messages", "member_count",
+    "active_members", "reports", "message_count", "message_ids", "last_server_id",
+    "pinned_messages", "room_info", "room", "auth_token", "compact_poll", "batch",
+    "ban_and_delete_all", "claim_auth_token", "delete_messages",
+];
+
+lazy_static::lazy_static! {
+
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref RPC_CALLS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("rpc_calls_total", "Number of RPC calls handled, by method and endpoint."),
+        &["method", "endpoint"]
+    ).unwrap());
+
+    pub static ref RPC_CALL_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("rpc_call_duration_seconds", "Time spent handling RPC calls, by method and endpoint."),
+        &["method", "endpoint"]
+    ).unwrap());
+
+    pub static ref MESSAGES_INSERTED: IntCounter = register(IntCounter::new(
+        "messages_inserted_total", "Number of messages stored."
+    ).unwrap());
+
+    pub static ref BANS: IntCounter = register(IntCounter::new(
+        "bans_total", "Number of public keys banned."
+    ).unwrap());
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).unwrap(); // Only fails if metric names clash
+    return collector;
+}
+
+/// Returns all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    // Make sure all metrics are listed, even before they've first been used
+    lazy_static::initialize(&RPC_CALLS);
+    lazy_static::initialize(&RPC_CALL_DURATION);
+    lazy_static::initialize(&MESSAGES_INSERTED);
+    lazy_static::initialize(&BANS);
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        error!("Couldn't encode metrics due to error: {}.", e);
+    }
+    return String::from_utf8(buffer).unwrap_or_default();
+}
+
+/// Turns e.g. `messages/123/reactions/%F0%9F%91%8D` into `messages/:id/reactions/:emoji`, so that
+/// endpoints can be used as metric labels without creating a separate time series per message,
+/// user or emoji.
+pub fn normalize_endpoint(path: &str) -> String {
+    let mut components: Vec<&str> = vec![];
+    for component in path.split('/') {
+        let previous_component = components.last().cloned();
+        if previous_component == Some("rooms") {
+            components.push(":room_id");
+        } else if component.parse::<i64>().is_ok() {
+            components.push(":id");
+        } else if crypto::is_valid_public_key(component) {
+            components.push(":public_key");
+        } else if previous_component == Some("reactions") {
+            components.push(":emoji");
+        } else if previous_component == Some("invites") {
+            components.push(":code");
+        } else if previous_component == Some("files") && component != "init" {
+            components.push(":upload_id");
+        } else {
+            components.push(component);
+        }
+    }
+    return components.join("/");
+}
+
+/// Returns the normalized endpoint if it's that of a route, or `unknown` otherwise.
+pub fn get_endpoint_label(path: &str) -> String {
+    let endpoint = normalize_endpoint(path);
+    if ENDPOINTS.contains(&endpoint.as_str()) {
+        return endpoint;
+    }
+    return "unknown".to_string();
+}
+
+/// Returns whether there's a route for `path` (with any HTTP method).
+pub fn is_route(path: &str) -> bool {
+    return ENDPOINTS.contains(&normalize_endpoint(path).as_str());
+}
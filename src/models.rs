@@ -1,5 +1,27 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+
+use chrono::TimeZone;
 use serde::{Deserialize, Serialize};
 
+use super::crypto;
+
+// Timestamps before this are almost certainly in seconds rather than milliseconds
+const MIN_MESSAGE_TIMESTAMP: i64 = 1_000_000_000_000;
+
+/// Formats the given Unix timestamp as an ISO 8601 UTC string (e.g. `2021-05-01T12:00:00.000Z`).
+/// Message timestamps are in milliseconds, but other timestamps (and those of some very old
+/// messages) are in seconds, so which one it is is inferred from the magnitude.
+pub fn format_timestamp(timestamp: i64) -> Option<String> {
+    let timestamp_millis = if timestamp.abs() < MIN_MESSAGE_TIMESTAMP {
+        timestamp.checked_mul(1000)?
+    } else {
+        timestamp
+    };
+    let date_time = chrono::Utc.timestamp_millis_opt(timestamp_millis).single()?;
+    return Some(date_time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Message {
     pub server_id: Option<i64>,
@@ -7,11 +29,53 @@ pub struct Message {
     pub timestamp: i64,
     pub data: String,
     pub signature: String,
+    /// The server ID of the message this message is a reply to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    /// Maps each emoji to the number of users that reacted with it. Only present if requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<HashMap<String, u32>>,
+    /// The number of users that marked the message as read. Only present if requested.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub read_count: Option<u32>,
+    /// The current display name of the sender. Only present if requested and the sender set one.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Set by clients to make retrying an insert safe. Never included in responses.
+    #[serde(default, skip_serializing)]
+    pub idempotency_key: Option<String>,
 }
 
 impl Message {
-    pub fn is_valid(&self) -> bool {
-        return self.timestamp > 0 && !self.data.is_empty() && !self.signature.is_empty();
+    /// Returns a description of what's wrong with the message, naming the offending field, if it's
+    /// malformed. Attachments are part of the serialized protobuf, so they're covered by `data`.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.data.is_empty() {
+            return Err("The data field must not be empty.");
+        }
+        if base64::decode(&self.data).is_err() {
+            return Err("The data field must be base64 encoded.");
+        }
+        match base64::decode(&self.signature) {
+            Ok(signature) if signature.len() == 64 => (),
+            _ => return Err("The signature field must be a base64 encoded 64 byte signature."),
+        }
+        if let Some(public_key) = &self.public_key {
+            if !crypto::is_valid_public_key(public_key) {
+                return Err("The public_key field must be a 66 character hex encoded public key.");
+            }
+        }
+        if self.timestamp < MIN_MESSAGE_TIMESTAMP {
+            return Err("The timestamp field must be a Unix timestamp in milliseconds.");
+        }
+        // Allow for clients with clocks that are somewhat ahead
+        let now = chrono::Utc::now().timestamp_millis();
+        let max_skew = super::MAX_TIMESTAMP_SKEW.load(Ordering::SeqCst).min(i64::MAX as u64) as i64;
+        let max_skew = max_skew.saturating_mul(1000);
+        if self.timestamp > now.saturating_add(max_skew) {
+            return Err("The timestamp field must not be in the future.");
+        }
+        return Ok(());
     }
 }
 
@@ -21,18 +85,123 @@ pub struct DeletedMessage {
     pub deleted_message_id: i64,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Report {
+    pub id: i64,
+    pub message_id: i64,
+    pub reporter: String,
+    pub reason: Option<String>,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Ban {
+    pub public_key: String,
+    /// When the ban was created as a Unix timestamp in seconds. `None` for bans that were created
+    /// before this was recorded.
+    pub banned_at: Option<i64>,
+    /// When the ban expires as a Unix timestamp in seconds, or `None` if it's permanent.
+    pub banned_until: Option<i64>,
+    pub reason: Option<String>,
+    /// The public key of the moderator that created the ban. `None` for bans that were created
+    /// before this was recorded.
+    pub banned_by: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Cooldown {
+    pub public_key: String,
+    /// When the cooldown expires as a Unix timestamp in seconds.
+    pub expires_at: i64,
+}
+
+/// A range of bytes of a resumable upload that has been received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ByteRange {
+    pub start: u64,
+    /// Exclusive.
+    pub end: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Room {
     pub id: String,
     pub name: String,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub description: String,
+    pub image_url: String,
+    /// Whether only moderators and users that redeemed an invite can post messages.
+    pub invite_only: bool,
+    /// The maximum size of the decoded data of messages posted in the room, in bytes, if it's
+    /// lower than the server wide maximum message size.
+    pub max_message_length: Option<u32>,
+    /// The maximum number of messages a user can post in the room per minute, if it overrides the
+    /// server wide maximum.
+    pub max_messages_per_minute: Option<u32>,
+}
+
+impl Default for RoomInfo {
+    fn default() -> RoomInfo {
+        return RoomInfo {
+            name: String::new(),
+            description: String::new(),
+            image_url: String::new(),
+            invite_only: false,
+            max_message_length: None,
+            max_messages_per_minute: None,
+        };
+    }
+}
+
+/// Fields that are left out are left unchanged.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RoomInfoUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub invite_only: Option<bool>,
+    /// 0 removes the room's maximum, so that the server wide maximum applies again.
+    #[serde(default)]
+    pub max_message_length: Option<u32>,
+    /// 0 removes the room's override, so that the server wide maximum applies again.
+    #[serde(default)]
+    pub max_messages_per_minute: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Profile {
+    pub public_key: String,
+    pub display_name: String,
+    /// A reference to the user's avatar (e.g. a file ID or URL) that's opaque to the server.
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Invite {
+    pub code: String,
+    /// The number of times the invite can be redeemed, or `None` if there's no limit.
+    pub max_redemptions: Option<u32>,
+    pub redemption_count: u32,
+    /// The Unix timestamp (in seconds) after which the invite can't be redeemed anymore, if any.
+    pub expires_at: Option<i64>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChangeModeratorRequestBody {
     pub public_key: String,
     pub room_id: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MaintenanceModeRequestBody {
+    pub enabled: bool,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CompactPollRequestBody {
     pub room_id: String,
@@ -1,7 +1,8 @@
 use std::convert::TryInto;
 
-use log::warn;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
+use warp::http::header::CONTENT_ENCODING;
 use warp::{http::StatusCode, reply::Reply, reply::Response, Rejection};
 
 use super::crypto;
@@ -9,6 +10,9 @@ use super::errors::Error;
 use super::models;
 use super::rpc;
 
+/// The version of the onion request protocol this server speaks (i.e. the `v3` in `/loki/v3/lsrpc`).
+pub const PROTOCOL_VERSION: u16 = 3;
+
 #[derive(Deserialize, Serialize, Debug)]
 struct OnionRequestPayload {
     pub ciphertext: Vec<u8>,
@@ -20,9 +24,19 @@ struct OnionRequestPayloadMetadata {
     pub ephemeral_key: String,
 }
 
+/// Rejects onion requests to versions of the onion request protocol other than the one this server
+/// speaks (e.g. `/loki/v2/lsrpc`), so that clients can tell those apart from malformed requests.
+/// These aren't logged, because clients that still use an old version would flood the logs.
+pub async fn check_protocol_version(version: String) -> Result<(), Rejection> {
+    if version != format!("v{}", PROTOCOL_VERSION) {
+        return Err(warp::reject::custom(Error::UnsupportedProtocolVersion));
+    }
+    return Ok(());
+}
+
 pub async fn handle_onion_request(blob: warp::hyper::body::Bytes) -> Result<Response, Rejection> {
-    let payload = parse_onion_request_payload(blob)?;
-    let (plaintext, symmetric_key) = decrypt_onion_request_payload(payload)?;
+    let payload = parse_onion_request_payload(blob).map_err(log_failure)?;
+    let (plaintext, symmetric_key) = decrypt_onion_request_payload(payload).map_err(log_failure)?;
     // From this point on we can wrap any error that occurs in a HTTP response that's
     // encrypted with the given symmetric key, so that the error that occurred is
     // propagated back to the client that made the onion request.
@@ -38,13 +52,23 @@ pub async fn handle_onion_request(blob: warp::hyper::body::Bytes) -> Result<Resp
 async fn handle_decrypted_onion_request(
     plaintext: &[u8], symmetric_key: &[u8],
 ) -> Result<Response, Rejection> {
-    let rpc_call = match serde_json::from_slice(plaintext) {
+    let rpc_call = match serde_json::from_slice::<rpc::RpcCall>(plaintext) {
         Ok(rpc_call) => rpc_call,
         Err(e) => {
-            warn!("Couldn't parse RPC call from JSON due to error: {}.", e);
-            return Err(warp::reject::custom(Error::InvalidOnionRequest));
+            // The error itself can contain parts of the plaintext
+            warn!(
+                "Couldn't parse RPC call from JSON due to {:?} error at line {}, column {}.",
+                e.classify(),
+                e.line(),
+                e.column()
+            );
+            return Err(log_failure(warp::reject::custom(Error::InvalidOnionRequest)));
         }
     };
+    // Onion responses don't include headers, so clients can't tell from the `Content-Encoding`
+    // header whether the body is compressed. Instead, the body of a GET call that accepts gzip is
+    // always compressed.
+    let compress = rpc_call.method == "GET" && rpc::accepts_gzip(&rpc_call);
     // Perform the RPC call
     let result = rpc::handle_rpc_call(rpc_call)
         .await
@@ -52,7 +76,7 @@ async fn handle_decrypted_onion_request(
         // Unwrapping is safe because at this point any error should be caught and turned into an HTTP response (i.e. an OK result)
         .or_else(super::errors::into_response)?;
     // Encrypt the HTTP response so that it's propagated back to the client that made the onion request
-    return encrypt_response(result, symmetric_key).await;
+    return encrypt_response(result, compress, symmetric_key).await;
 }
 
 fn parse_onion_request_payload(
@@ -72,8 +96,8 @@ fn parse_onion_request_payload(
         return Err(warp::reject::custom(Error::InvalidOnionRequest));
     }
 
-    let ciphertext: Vec<u8> = blob[4..(4 + size)].try_into().unwrap();
-    let utf8_json: Vec<u8> = blob[(4 + size)..].try_into().unwrap();
+    let ciphertext: Vec<u8> = blob[4..(4 + size)].into();
+    let utf8_json: Vec<u8> = blob[(4 + size)..].into();
     // Parse JSON
     let json = match String::from_utf8(utf8_json) {
         Ok(json) => json,
@@ -90,11 +114,23 @@ fn parse_onion_request_payload(
             return Err(warp::reject::custom(Error::InvalidOnionRequest));
         }
     };
-    // Check that the ephemeral public key is valid hex
-    if hex::decode(&metadata.ephemeral_key).is_err() {
-        warn!("Ignoring non hex encoded onion request payload ephemeral key.");
-        return Err(warp::reject::custom(Error::InvalidOnionRequest));
+    // Check that the ephemeral public key is a valid hex encoded X25519 public key
+    match hex::decode(&metadata.ephemeral_key) {
+        Ok(ephemeral_key) if ephemeral_key.len() == 32 => (),
+        Ok(_) => {
+            warn!("Ignoring onion request payload ephemeral key of invalid length.");
+            return Err(warp::reject::custom(Error::InvalidOnionRequest));
+        }
+        Err(_) => {
+            warn!("Ignoring non hex encoded onion request payload ephemeral key.");
+            return Err(warp::reject::custom(Error::InvalidOnionRequest));
+        }
     };
+    // Check that the ciphertext is long enough to contain an IV
+    if ciphertext.len() < crypto::IV_SIZE {
+        warn!("Ignoring onion request payload ciphertext of invalid size.");
+        return Err(warp::reject::custom(Error::InvalidOnionRequest));
+    }
     // Return
     return Ok(OnionRequestPayload { ciphertext, metadata });
 }
@@ -109,16 +145,45 @@ fn decrypt_onion_request_payload(
     return Ok((plaintext, symmetric_key));
 }
 
-async fn encrypt_response(response: Response, symmetric_key: &[u8]) -> Result<Response, Rejection> {
-    let bytes: Vec<u8>;
-    if response.status().is_success() {
-        let (_, body) = response.into_parts();
-        bytes = warp::hyper::body::to_bytes(body).await.unwrap().to_vec();
-    } else {
-        let error = models::StatusCode { status_code: response.status().as_u16() };
+/// Logs the category of an onion request that couldn't be handled, so that failures caused by the
+/// client's encryption can be told apart from malformed requests. Nothing derived from the
+/// plaintext is logged.
+fn log_failure(e: Rejection) -> Rejection {
+    let category = match e.find::<Error>() {
+        Some(Error::DecryptionFailed) => "decryption failure",
+        Some(Error::InvalidOnionRequest) => "malformed payload",
+        _ => "other error",
+    };
+    warn!("Rejected onion request due to {}.", category);
+    return e;
+}
+
+/// Encrypts the body of the given response, gzip compressing it first if `compress` is set and
+/// it isn't compressed yet.
+async fn encrypt_response(
+    response: Response, compress: bool, symmetric_key: &[u8],
+) -> Result<Response, Rejection> {
+    let status = response.status();
+    let (parts, body) = response.into_parts();
+    let mut bytes = warp::hyper::body::to_bytes(body).await.unwrap().to_vec();
+    // Error responses normally describe the error that occurred, but fall back on just the
+    // status code if they don't
+    if !status.is_success() && bytes.is_empty() {
+        let error = models::StatusCode { status_code: status.as_u16() };
         bytes = serde_json::to_vec(&error).unwrap();
     }
-    let ciphertext = crypto::encrypt_aes_gcm(&bytes, symmetric_key).unwrap();
+    let is_compressed = parts.headers.get(CONTENT_ENCODING).map(|encoding| encoding.as_bytes())
+        == Some(b"gzip".as_ref());
+    if compress && !is_compressed {
+        bytes = match rpc::gzip(&bytes) {
+            Ok(compressed_bytes) => compressed_bytes,
+            Err(e) => {
+                error!("Couldn't compress response body due to error: {}.", e);
+                return Err(warp::reject::custom(Error::DatabaseFailedInternally));
+            }
+        };
+    }
+    let ciphertext = crypto::encrypt_aes_gcm(&bytes, symmetric_key)?;
     let json = base64::encode(&ciphertext);
     let response =
         warp::http::Response::builder().status(StatusCode::OK.as_u16()).body(json).into_response();
@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{AddrParseError, IpAddr};
 
 use structopt::StructOpt;
 
@@ -8,6 +8,11 @@ use structopt::StructOpt;
 #[derive(StructOpt)]
 #[structopt(name = "Session Open Group Server")]
 pub struct Opt {
+    /// Path to a TOML configuration file. Settings in the file take precedence over their command
+    /// line counterparts.
+    #[structopt(long = "config")]
+    pub config: Option<String>,
+
     /// Path to X25519 public key.
     #[structopt(long = "x25519-public-key", default_value = "x25519_public_key.pem")]
     pub x25519_public_key: String,
@@ -20,9 +25,9 @@ pub struct Opt {
     #[structopt(short = "P", long = "port", default_value = "80")]
     pub port: u16,
 
-    /// IP to bind to.
-    #[structopt(short = "H", long = "host", default_value = "0.0.0.0")]
-    pub host: Ipv4Addr,
+    /// IP to bind to. Can be an IPv4 or IPv6 address; use [::] to accept both.
+    #[structopt(short = "H", long = "host", default_value = "0.0.0.0", parse(try_from_str = parse_host))]
+    pub host: IpAddr,
 
     /// Path to the log file. If not provided, logs are only printed to stdout.
     #[structopt(long = "log-file")]
@@ -40,6 +45,170 @@ pub struct Opt {
     #[structopt(long = "tls-private-key", default_value = "tls_private_key.pem")]
     pub tls_private_key: String,
 
+    /// Maximum size of an uploaded file in bytes.
+    #[structopt(long = "max-file-size", default_value = "10485760")]
+    pub max_file_size: usize,
+
+    /// Number of seconds after which resumable uploads that haven't been completed are discarded,
+    /// counted from the last chunk that was received.
+    #[structopt(long = "upload-expiration", default_value = "86400")]
+    pub upload_expiration: u64,
+
+    /// Maximum size of the data of a message in bytes.
+    #[structopt(long = "max-message-size", default_value = "65536")]
+    pub max_message_size: usize,
+
+    /// Maximum size of an onion request in bytes. Larger requests are rejected before they're read.
+    /// The default matches that of the storage server.
+    #[structopt(long = "max-request-size", default_value = "10485760")]
+    pub max_request_size: u64,
+
+    /// Maximum number of messages a user can send per minute.
+    #[structopt(long = "max-messages-per-minute", default_value = "20")]
+    pub max_messages_per_minute: u32,
+
+    /// Maximum number of onion requests a single remote address can make per minute before it's
+    /// blocked. Addresses aren't limited if this is 0.
+    #[structopt(long = "max-requests-per-address", default_value = "1200")]
+    pub max_requests_per_address: u32,
+
+    /// Number of seconds a remote address stays blocked after exceeding the maximum number of
+    /// requests.
+    #[structopt(long = "address-block-duration", default_value = "300")]
+    pub address_block_duration: u64,
+
+    /// Header (e.g. X-Forwarded-For) from which to read the client's address when running behind a
+    /// trusted reverse proxy. The header is only used for requests from one of the trusted proxies.
+    #[structopt(long = "trusted-proxy-header")]
+    pub trusted_proxy_header: Option<String>,
+
+    /// Comma separated list of the IP addresses of the reverse proxies that are trusted to set the
+    /// header given by `--trusted-proxy-header`.
+    #[structopt(long = "trusted-proxies", use_delimiter = true, parse(try_from_str = parse_host))]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Number of seconds a message's timestamp can be ahead of the server's clock before the message
+    /// is rejected.
+    #[structopt(long = "max-timestamp-skew", default_value = "86400")]
+    pub max_timestamp_skew: u64,
+
+    /// Maximum number of connections per database connection pool.
+    #[structopt(long = "db-pool-size", default_value = "10")]
+    pub db_pool_size: u32,
+
+    /// Number of seconds to wait for a database connection before giving up on a request.
+    #[structopt(long = "db-connection-timeout", default_value = "30")]
+    pub db_connection_timeout: u64,
+
+    /// Number of times to retry opening the database at startup, with exponential backoff,
+    /// before giving up.
+    #[structopt(long = "db-startup-retries", default_value = "5")]
+    pub db_startup_retries: u32,
+
+    /// Directory with read-only copies of the room databases (e.g. kept up to date by a replication
+    /// tool), which are used for reading messages, deletions and message counts. All reads go to
+    /// the primary databases if this isn't provided.
+    #[structopt(long = "read-replica-dir")]
+    pub read_replica_dir: Option<String>,
+
+    /// Number of seconds since their last message within which a member counts as active.
+    #[structopt(long = "active-member-window", default_value = "604800")]
+    pub active_member_window: u64,
+
+    /// Number of seconds within which a message that's identical to the sender's previous message
+    /// is treated as a duplicate, in which case the previous message is returned instead of a new one
+    /// being stored. Duplicates are stored as usual if this is 0.
+    #[structopt(long = "duplicate-message-window", default_value = "0")]
+    pub duplicate_message_window: u64,
+
+    /// Number of seconds after deletion within which a message can still be restored.
+    #[structopt(long = "message-restore-window", default_value = "3600")]
+    pub message_restore_window: u64,
+
+    /// Number of seconds after which messages are deleted automatically. Messages are kept forever
+    /// if this is 0.
+    #[structopt(long = "message-retention-period", default_value = "0")]
+    pub message_retention_period: u64,
+
+    /// Number of seconds between sweeps for messages that are past the retention period.
+    #[structopt(long = "message-retention-sweep-interval", default_value = "3600")]
+    pub message_retention_sweep_interval: u64,
+
+    /// Number of seconds clients are recommended to wait between polls. No recommendation is
+    /// included in responses if this is 0.
+    #[structopt(long = "poll-interval", default_value = "0")]
+    pub poll_interval: u64,
+
+    /// Number of requests per minute above which the recommended poll interval is increased in
+    /// proportion to the load (up to 10 times the poll interval). It's never increased if this is 0.
+    #[structopt(long = "poll-interval-load-threshold", default_value = "0")]
+    pub poll_interval_load_threshold: u64,
+
+    /// Maximum number of items returned by a single paginated request. Larger limits are clamped.
+    #[structopt(long = "max-page-size", default_value = "256")]
+    pub max_page_size: u16,
+
+    /// Number of items returned by a paginated request that doesn't specify a limit. This is
+    /// clamped to the maximum page size.
+    #[structopt(long = "default-page-size", default_value = "256")]
+    pub default_page_size: u16,
+
+    /// Maximum number of messages that can be pinned in a room at once.
+    #[structopt(long = "max-pinned-messages", default_value = "3")]
+    pub max_pinned_messages: u32,
+
+    /// Text of a welcome message that clients can request to show at the top of a room. There's no
+    /// welcome message if this isn't provided.
+    #[structopt(long = "welcome-message")]
+    pub welcome_message: Option<String>,
+
+    /// Path to a file with patterns (regular expressions, one per line) that messages aren't
+    /// allowed to match, ignoring case. Messages aren't filtered if this isn't provided.
+    #[structopt(long = "content-filter-file")]
+    pub content_filter_file: Option<String>,
+
+    /// How uploaded files are scanned before they're stored: `none` accepts every file and
+    /// `mime-allow-list` only accepts the content types given by `--allowed-file-types`. Files
+    /// uploaded without a content type (which includes every file uploaded by current Session
+    /// clients) count as application/octet-stream.
+    #[structopt(long = "file-scanner", default_value = "none")]
+    pub file_scanner: String,
+
+    /// Comma separated list of content types (e.g. image/png or image/*) that uploaded files can
+    /// have if `--file-scanner` is `mime-allow-list`.
+    #[structopt(long = "allowed-file-types", use_delimiter = true)]
+    pub allowed_file_types: Vec<String>,
+
+    /// Require a valid auth token of a user that isn't banned for reading rooms (including room
+    /// info, images and files), rather than allowing anyone to read them.
+    #[structopt(long = "require-auth-for-reads")]
+    pub require_auth_for_reads: bool,
+
+    /// Only allow users on the allow list of a room (and moderators) to post in it, as if every
+    /// room were invite only. The block list isn't used in this mode.
+    #[structopt(long = "allow-list-mode")]
+    pub allow_list_mode: bool,
+
+    /// Hash (as printed by `--hash-admin-token`) of the token that server management requests, such
+    /// as the commands below, have to include. They don't need a token if this isn't provided.
+    #[structopt(long = "admin-token-hash")]
+    pub admin_token_hash: Option<String>,
+
+    /// Comma separated list of origins (e.g. https://example.com) browser-based clients are allowed
+    /// to make requests from. No cross-origin requests are allowed by default.
+    #[structopt(long = "cors-allowed-origins", use_delimiter = true)]
+    pub cors_allowed_origins: Vec<String>,
+
+    /// Reads an admin token from standard input and prints its hash, to be used as
+    /// `--admin-token-hash`. The token isn't taken as an argument so that it doesn't end up in the
+    /// process list or shell history.
+    #[structopt(long = "hash-admin-token")]
+    pub hash_admin_token: bool,
+
+    /// Admin token to include in the commands below if the server requires one.
+    #[structopt(long = "admin-token", env = "SOGS_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
     /// Add a room with the given ID and name.
     #[structopt(long = "add-room")]
     pub add_room: Option<Vec<String>>,
@@ -56,7 +225,28 @@ pub struct Opt {
     #[structopt(long = "delete-moderator")]
     pub delete_moderator: Option<Vec<String>>,
 
+    /// Makes the server reject writes until maintenance mode is disabled again.
+    #[structopt(long = "enable-maintenance-mode", conflicts_with = "disable-maintenance-mode")]
+    pub enable_maintenance_mode: bool,
+
+    /// Makes the server accept writes again after maintenance.
+    #[structopt(long = "disable-maintenance-mode")]
+    pub disable_maintenance_mode: bool,
+
+    /// Makes the server reload the content filter file without restarting.
+    #[structopt(long = "reload-content-filter")]
+    pub reload_content_filter: bool,
+
     /// Prints the URL format users can use to join rooms on this open group server.
     #[structopt(long = "print-url")]
     pub print_url: bool,
 }
+
+/// Parses an IPv4 or IPv6 address. IPv6 addresses can optionally be enclosed in square brackets,
+/// as in URLs.
+pub fn parse_host(host: &str) -> Result<IpAddr, AddrParseError> {
+    match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(host) => return host.parse(),
+        None => return host.parse(),
+    }
+}
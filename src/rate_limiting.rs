@@ -0,0 +1,303 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+
+// Limits the memory used by the address rate limiter, so that it can't be exhausted by requests
+// from many different addresses
+const MAX_TRACKED_ADDRESSES: usize = 100_000;
+
+lazy_static::lazy_static! {
+
+    pub static ref MESSAGE_RATE_LIMITER: RateLimiter = RateLimiter::new();
+    pub static ref ADDRESS_RATE_LIMITER: AddressRateLimiter =
+        AddressRateLimiter::new(MAX_TRACKED_ADDRESSES);
+    pub static ref REQUEST_COUNTER: RequestCounter = RequestCounter::new();
+}
+
+/// An in-memory token bucket rate limiter keyed by public key.
+///
+/// Each key gets a bucket that holds at most `limit` tokens and refills at a rate of `limit`
+/// tokens per minute. Every action consumes a token, and actions are rejected while the bucket is
+/// empty.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        return RateLimiter { buckets: Mutex::new(HashMap::new()) };
+    }
+
+    /// Consumes `count` tokens (one per action) from the bucket for `key` if it holds that many.
+    /// Otherwise no tokens are consumed and `false` is returned, meaning the actions should be rate
+    /// limited.
+    pub fn try_acquire(&self, key: &str, count: u32, limit_per_minute: u32) -> bool {
+        let count = count as f64;
+        let now = Instant::now();
+        let capacity = limit_per_minute as f64;
+        let mut buckets = self.buckets.lock();
+        let bucket =
+            buckets.entry(key.to_string()).or_insert(Bucket { tokens: capacity, last_refill: now });
+        // Refill the bucket based on how much time has passed since the last refill
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.last_refill = now;
+        if bucket.tokens < count {
+            return false;
+        }
+        bucket.tokens -= count;
+        return true;
+    }
+
+    /// Returns the number of seconds until the bucket for `key` holds `count` tokens again, rounded
+    /// up. Returns 0 if they're available right away.
+    pub fn seconds_until_available(&self, key: &str, count: u32, limit_per_minute: u32) -> u64 {
+        let count = count as f64;
+        let capacity = limit_per_minute as f64;
+        let buckets = self.buckets.lock();
+        let bucket = match buckets.get(key) {
+            Some(bucket) => bucket,
+            None => return 0,
+        };
+        let elapsed = bucket.last_refill.elapsed().as_secs_f64();
+        let tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        if tokens >= count {
+            return 0;
+        }
+        return ((count - tokens) * 60.0 / capacity).ceil() as u64;
+    }
+
+    /// Removes the buckets of keys that haven't been active for at least a minute. Their buckets
+    /// will have been refilled completely by now, so this doesn't affect rate limiting.
+    pub fn prune(&self) {
+        let mut buckets = self.buckets.lock();
+        buckets.retain(|_, bucket| bucket.last_refill.elapsed() < Duration::from_secs(60));
+    }
+}
+
+/// An in-memory rate limiter keyed by remote address, which protects the server before anything is
+/// known about the sender of a request.
+///
+/// Requests are counted per address in fixed windows of a minute. An address that makes more than
+/// `limit` requests within a window is blocked for the given duration. IPv6 addresses are counted
+/// per /64 network, because a single client usually has a whole /64 to pick addresses from. At most
+/// `max_tracked_addresses` addresses are tracked at once; beyond that the least recently active
+/// address that isn't blocked is forgotten to make room for new ones.
+pub struct AddressRateLimiter {
+    addresses: Mutex<TrackedAddresses>,
+    max_tracked_addresses: usize,
+}
+
+struct TrackedAddresses {
+    states: HashMap<IpAddr, AddressState>,
+    // Ordered by last request, so that the least recently active address can be found quickly
+    by_last_request: BTreeSet<(Instant, IpAddr)>,
+}
+
+struct AddressState {
+    window_start: Instant,
+    request_count: u32,
+    blocked_until: Option<Instant>,
+    last_request: Instant,
+}
+
+impl AddressRateLimiter {
+    pub fn new(max_tracked_addresses: usize) -> AddressRateLimiter {
+        let addresses =
+            TrackedAddresses { states: HashMap::new(), by_last_request: BTreeSet::new() };
+        return AddressRateLimiter { addresses: Mutex::new(addresses), max_tracked_addresses };
+    }
+
+    /// Counts a request from `address`. Returns `false` if the address is blocked, either because
+    /// it already was or because this request exceeded the limit.
+    pub fn try_acquire(
+        &self, address: IpAddr, limit_per_minute: u32, block_duration: Duration,
+    ) -> bool {
+        let now = Instant::now();
+        let address = get_key(address);
+        let mut addresses = self.addresses.lock();
+        let addresses = &mut *addresses;
+        if !addresses.states.contains_key(&address)
+            && addresses.states.len() >= self.max_tracked_addresses
+        {
+            addresses.evict_least_recently_active(now);
+        }
+        let state = addresses.states.entry(address).or_insert(AddressState {
+            window_start: now,
+            request_count: 0,
+            blocked_until: None,
+            last_request: now,
+        });
+        addresses.by_last_request.remove(&(state.last_request, address));
+        state.last_request = now;
+        addresses.by_last_request.insert((now, address));
+        if let Some(blocked_until) = state.blocked_until {
+            if now < blocked_until {
+                return false;
+            }
+            state.blocked_until = None;
+        }
+        // Start a new window if the current one is over (or the address was just unblocked)
+        if state.request_count > limit_per_minute
+            || now.duration_since(state.window_start) >= Duration::from_secs(60)
+        {
+            state.window_start = now;
+            state.request_count = 0;
+        }
+        state.request_count += 1;
+        if state.request_count > limit_per_minute {
+            warn!(
+                "Blocking {} for {} seconds after too many requests.",
+                address,
+                block_duration.as_secs()
+            );
+            state.blocked_until = Some(now + block_duration);
+            return false;
+        }
+        return true;
+    }
+
+    /// Returns the number of seconds until `address` is unblocked, rounded up. Returns 0 if it
+    /// isn't blocked.
+    pub fn seconds_until_unblocked(&self, address: IpAddr) -> u64 {
+        let addresses = self.addresses.lock();
+        let state = addresses.states.get(&get_key(address));
+        let blocked_until = match state.and_then(|state| state.blocked_until) {
+            Some(blocked_until) => blocked_until,
+            None => return 0,
+        };
+        return blocked_until.saturating_duration_since(Instant::now()).as_secs_f64().ceil() as u64;
+    }
+
+    /// Removes the addresses that aren't blocked and haven't made any requests for at least a
+    /// minute. Their counts would be reset by their next request anyway.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut addresses = self.addresses.lock();
+        let addresses = &mut *addresses;
+        let by_last_request = &mut addresses.by_last_request;
+        addresses.states.retain(|address, state| {
+            let is_active = match state.blocked_until {
+                Some(blocked_until) => now < blocked_until,
+                None => now.duration_since(state.window_start) < Duration::from_secs(60),
+            };
+            if !is_active {
+                by_last_request.remove(&(state.last_request, *address));
+            }
+            return is_active;
+        });
+    }
+}
+
+impl TrackedAddresses {
+    /// Forgets the least recently active address that isn't blocked, or the least recently active
+    /// address if they're all blocked.
+    fn evict_least_recently_active(&mut self, now: Instant) {
+        let states = &self.states;
+        let is_blocked = |address: &IpAddr| {
+            let blocked_until = states.get(address).and_then(|state| state.blocked_until);
+            return matches!(blocked_until, Some(blocked_until) if now < blocked_until);
+        };
+        let entry = match self.by_last_request.iter().find(|(_, address)| !is_blocked(address)) {
+            Some(entry) => *entry,
+            None => match self.by_last_request.iter().next() {
+                Some(entry) => *entry,
+                None => return,
+            },
+        };
+        self.by_last_request.remove(&entry);
+        self.states.remove(&entry.1);
+    }
+}
+
+/// Returns the address that requests from `address` are counted under.
+fn get_key(address: IpAddr) -> IpAddr {
+    return match address {
+        IpAddr::V4(_) => address,
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => IpAddr::V4(address),
+            None => {
+                let network = u128::from(address) & !(u64::MAX as u128);
+                IpAddr::V6(Ipv6Addr::from(network))
+            }
+        },
+    };
+}
+
+/// Counts requests in fixed windows of a minute, to find out how busy the server is.
+pub struct RequestCounter {
+    state: Mutex<RequestCounterState>,
+}
+
+struct RequestCounterState {
+    window_start: Instant,
+    request_count: u64,
+    previous_request_count: u64,
+}
+
+impl RequestCounter {
+    pub fn new() -> RequestCounter {
+        let state = RequestCounterState {
+            window_start: Instant::now(),
+            request_count: 0,
+            previous_request_count: 0,
+        };
+        return RequestCounter { state: Mutex::new(state) };
+    }
+
+    /// Counts a request.
+    pub fn record(&self) {
+        let mut state = self.state.lock();
+        RequestCounter::advance(&mut state, Instant::now());
+        state.request_count += 1;
+    }
+
+    /// Returns the number of requests made in the previous window, or in the current window if
+    /// that's more already, so that a sudden spike is noticed before the window is over.
+    pub fn requests_per_minute(&self) -> u64 {
+        let mut state = self.state.lock();
+        RequestCounter::advance(&mut state, Instant::now());
+        return state.request_count.max(state.previous_request_count);
+    }
+
+    fn advance(state: &mut RequestCounterState, now: Instant) {
+        let elapsed = now.duration_since(state.window_start);
+        if elapsed >= Duration::from_secs(2 * 60) {
+            // Nothing happened during the previous window
+            state.window_start = now;
+            state.previous_request_count = 0;
+            state.request_count = 0;
+        } else if elapsed >= Duration::from_secs(60) {
+            state.window_start += Duration::from_secs(60);
+            state.previous_request_count = state.request_count;
+            state.request_count = 0;
+        }
+    }
+}
+
+pub async fn prune_message_rate_limiter_periodically() {
+    let mut timer = tokio::time::interval(chrono::Duration::minutes(10).to_std().unwrap());
+    loop {
+        timer.tick().await;
+        MESSAGE_RATE_LIMITER.prune();
+        info!("Pruned message rate limiter.");
+    }
+}
+
+pub async fn prune_address_rate_limiter_periodically() {
+    let mut timer = tokio::time::interval(chrono::Duration::minutes(1).to_std().unwrap());
+    loop {
+        timer.tick().await;
+        ADDRESS_RATE_LIMITER.prune();
+        // This happens often enough that logging it at the info level would be noisy
+        debug!("Pruned address rate limiter.");
+    }
+}
@@ -1,15 +1,62 @@
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use log::warn;
+use warp::http::HeaderMap;
 use warp::{reply::Reply, reply::Response, Filter, Rejection};
 
+use super::crypto;
 use super::errors;
 use super::handlers;
+use super::metrics;
 use super::onion_requests;
+use super::rate_limiting;
+use super::storage;
+use super::websockets;
 
 /// GET /
 pub fn root() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
     return warp::get().and(warp::path::end()).and_then(root_html);
 }
 
+/// GET /health
+pub fn health() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+    return warp::get().and(warp::path("health")).and(warp::path::end()).and_then(health_json);
+}
+
+/// GET /server_info
+pub fn server_info() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+    return warp::get()
+        .and(warp::path("server_info"))
+        .and(warp::path::end())
+        .and_then(server_info_json);
+}
+
+/// GET /metrics
+pub fn metrics() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+    return warp::get().and(warp::path("metrics")).and(warp::path::end()).and_then(metrics_text);
+}
+
+/// GET /ws?room=:room_id
+pub fn websocket() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+    return warp::path("ws")
+        .and(warp::path::end())
+        .and(warp::filters::query::query())
+        .and(warp::ws())
+        .and_then(websocket_upgrade);
+}
+
+/// GET /export?room=:room_id&auth_token=:auth_token&from_server_id=:from_server_id
+pub fn export() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+    return warp::get()
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and(warp::filters::query::query())
+        .and_then(export_ndjson);
+}
+
 /// GET /:room_id?public_key=:public_key
 pub fn fallback() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
     return warp::get()
@@ -19,12 +66,18 @@ pub fn fallback() -> impl Filter<Extract = impl warp::Reply, Error = Rejection>
 }
 
 /// POST /loki/v3/lsrpc
+///
+/// Other versions of the onion request protocol (e.g. `/loki/v2/lsrpc`) are rejected with status
+/// code 501.
 pub fn lsrpc() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
     return warp::post()
         .and(warp::path("loki"))
-        .and(warp::path("v3"))
+        .and(warp::path::param::<String>())
         .and(warp::path("lsrpc"))
-        .and(warp::body::content_length_limit(10 * 1024 * 1024)) // Match storage server
+        .and(limit_requests_per_address())
+        .and_then(onion_requests::check_protocol_version)
+        .untuple_one()
+        .and(warp::body::content_length_limit(super::MAX_REQUEST_SIZE.load(Ordering::SeqCst)))
         .and(warp::body::bytes()) // Expect bytes
         .and_then(onion_requests::handle_onion_request)
         // It's possible for an error to occur before we have the symmetric key needed
@@ -33,6 +86,130 @@ pub fn lsrpc() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + C
         .recover(into_response);
 }
 
+/// Rejects requests from remote addresses that made too many requests recently. This happens
+/// before the request body is read, so the sender doesn't have to be known yet.
+fn limit_requests_per_address() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    return warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .and_then(|remote: Option<SocketAddr>, headers: HeaderMap| async move {
+            let limit = super::MAX_REQUESTS_PER_ADDRESS.load(Ordering::SeqCst);
+            if limit == 0 {
+                return Ok(());
+            }
+            let trusted_proxy_header = super::TRUSTED_PROXY_HEADER.read().clone();
+            let trusted_proxies = super::TRUSTED_PROXIES.read().clone();
+            let address = match get_client_address(
+                remote,
+                &headers,
+                trusted_proxy_header.as_deref(),
+                &trusted_proxies,
+            ) {
+                Some(address) => address,
+                None => return Ok(()),
+            };
+            let block_duration =
+                Duration::from_secs(super::ADDRESS_BLOCK_DURATION.load(Ordering::SeqCst));
+            let rate_limiter = &rate_limiting::ADDRESS_RATE_LIMITER;
+            if !rate_limiter.try_acquire(address, limit, block_duration) {
+                let retry_after = rate_limiter.seconds_until_unblocked(address).max(1);
+                return Err(warp::reject::custom(errors::Error::RateLimited(retry_after)));
+            }
+            return Ok(());
+        })
+        .untuple_one();
+}
+
+/// Rejects server management requests that don't include the admin token in an
+/// `Authorization: Bearer <token>` header, if the server requires one.
+pub fn require_admin_token() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    return warp::header::optional::<String>("Authorization")
+        .and_then(|authorization: Option<String>| async move {
+            let admin_token_hash = super::ADMIN_TOKEN_HASH.read().clone();
+            if !is_authorized_admin(authorization.as_deref(), admin_token_hash.as_deref()) {
+                warn!("Ignoring server management request without a valid admin token.");
+                return Err(warp::reject::custom(errors::Error::InvalidAdminToken));
+            }
+            return Ok(());
+        })
+        .untuple_one();
+}
+
+/// Returns whether the given `Authorization` header contains the admin token with the given hash.
+/// Anything goes if there's no hash, because then the server doesn't require an admin token.
+pub fn is_authorized_admin(authorization: Option<&str>, admin_token_hash: Option<&str>) -> bool {
+    let admin_token_hash = match admin_token_hash {
+        Some(admin_token_hash) => admin_token_hash,
+        None => return true,
+    };
+    return match authorization.and_then(|authorization| authorization.strip_prefix("Bearer ")) {
+        Some(admin_token) => crypto::verify_admin_token(admin_token, admin_token_hash),
+        None => false,
+    };
+}
+
+/// Returns the address of the client that made a request. If the request comes from one of the
+/// `trusted_proxies` and the proxy put the client's address in `trusted_proxy_header`, the address
+/// is read from there. If there are several (like in `X-Forwarded-For`), they're read from last to
+/// first and the first one that isn't a trusted proxy is used, because every proxy appends the
+/// address it got the request from while the ones before could've been made up by the client.
+/// Otherwise the remote address of the connection is used, so that clients that connect directly
+/// can't pick their own address.
+pub fn get_client_address(
+    remote: Option<SocketAddr>, headers: &HeaderMap, trusted_proxy_header: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    // IPv4 addresses show up as IPv4-mapped IPv6 addresses when binding to [::]
+    let is_trusted = |address: IpAddr| trusted_proxies.contains(&address.to_canonical());
+    let mut address = remote?.ip().to_canonical();
+    let trusted_proxy_header = match trusted_proxy_header {
+        Some(trusted_proxy_header) if is_trusted(address) => trusted_proxy_header,
+        _ => return Some(address),
+    };
+    for value in headers.get_all(trusted_proxy_header).iter().rev() {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => return Some(address),
+        };
+        for hop in value.rsplit(',') {
+            // Stop at the first invalid address, because it can't be trusted to be the client's
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => address = hop.to_canonical(),
+                Err(_) => return Some(address),
+            }
+            if !is_trusted(address) {
+                return Some(address);
+            }
+        }
+    }
+    return Some(address);
+}
+
+/// Answers CORS preflight requests and adds CORS headers to responses for the configured origins.
+/// Requests from other origins are rejected; requests without an `Origin` header (i.e. requests
+/// that don't come from a browser) aren't affected.
+pub fn cors() -> warp::cors::Builder {
+    let origins = super::CORS_ALLOWED_ORIGINS.read().clone();
+    // Note that the list of allowed origins must always be set, because warp allows any origin
+    // if it isn't
+    return warp::cors()
+        .allow_origins(origins.iter().map(|origin| origin.as_str()))
+        .allow_methods(vec!["GET", "POST", "DELETE", "PUT"])
+        .allow_headers(vec!["Content-Type", "Authorization", "Room"]);
+}
+
+/// Checks that `origin` has the form `scheme://host[:port]`, as expected by `cors()`.
+pub fn is_valid_origin(origin: &str) -> bool {
+    let uri: warp::http::Uri = match origin.parse() {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    return uri.scheme().is_some()
+        && uri.authority().is_some()
+        && !origin.ends_with('/')
+        && uri.path() == "/"
+        && uri.query().is_none();
+}
+
 /// POST /rooms
 ///
 /// Not publicly exposed.
@@ -77,6 +254,27 @@ pub fn get_url() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> +
     return warp::get().and(warp::path("url")).and_then(handlers::get_url);
 }
 
+/// POST /maintenance
+///
+/// Not publicly exposed.
+pub fn set_maintenance_mode() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone
+{
+    return warp::post()
+        .and(warp::path("maintenance"))
+        .and(warp::body::json())
+        .and_then(handlers::set_maintenance_mode);
+}
+
+/// POST /content_filter/reload
+///
+/// Not publicly exposed.
+pub fn reload_content_filter() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone
+{
+    return warp::post()
+        .and(warp::path!("content_filter" / "reload"))
+        .and_then(handlers::reload_content_filter);
+}
+
 /// GET /stats/:room_id?window=:seconds
 ///
 /// Not publicly exposed
@@ -103,10 +301,80 @@ pub async fn root_html() -> Result<Response, Rejection> {
     return Ok(warp::reply::html(body).into_response());
 }
 
+pub async fn health_json() -> Result<Response, Rejection> {
+    return handlers::check_health(&storage::MAIN_POOL);
+}
+
+pub async fn server_info_json() -> Result<Response, Rejection> {
+    return handlers::get_server_info();
+}
+
+pub async fn metrics_text() -> Result<Response, Rejection> {
+    let body = metrics::render();
+    return Ok(
+        warp::reply::with_header(body, "Content-Type", "text/plain; version=0.0.4").into_response()
+    );
+}
+
+pub async fn websocket_upgrade(
+    query_map: HashMap<String, String>, ws: warp::ws::Ws,
+) -> Result<Response, Rejection> {
+    // This route isn't called through an onion request, so turn any error into a response here
+    let room_id = match get_subscription_room_id(&query_map) {
+        Ok(room_id) => room_id,
+        Err(e) => return errors::into_response(e),
+    };
+    return Ok(ws
+        .on_upgrade(move |socket| websockets::handle_subscriber(socket, room_id))
+        .into_response());
+}
+
+/// Returns the room to subscribe to. The subscriber itself is authorized once the connection is
+/// upgraded, with the auth token it sends as its first message.
+fn get_subscription_room_id(query_map: &HashMap<String, String>) -> Result<String, Rejection> {
+    let room_id =
+        query_map.get("room").ok_or_else(|| warp::reject::custom(errors::Error::InvalidQuery))?;
+    // Don't silently create a database for a room that doesn't exist
+    if !storage::room_exists(room_id)? {
+        return Err(warp::reject::custom(errors::Error::NoSuchRoom));
+    }
+    return Ok(room_id.to_string());
+}
+
+pub async fn export_ndjson(query_map: HashMap<String, String>) -> Result<Response, Rejection> {
+    // This route isn't called through an onion request, so turn any error into a response here
+    let (pool, from_server_id) = match authorize_exporter(&query_map) {
+        Ok(result) => result,
+        Err(e) => return errors::into_response(e),
+    };
+    return Ok(handlers::export_messages(from_server_id, pool));
+}
+
+fn authorize_exporter(
+    query_map: &HashMap<String, String>,
+) -> Result<(storage::DatabaseConnectionPool, i64), Rejection> {
+    let room_id =
+        query_map.get("room").ok_or_else(|| warp::reject::custom(errors::Error::InvalidQuery))?;
+    let auth_token = query_map
+        .get("auth_token")
+        .ok_or_else(|| warp::reject::custom(errors::Error::NoAuthToken))?;
+    let from_server_id: i64 = match query_map.get("from_server_id") {
+        Some(str) => str.parse().map_err(|_| warp::reject::custom(errors::Error::InvalidQuery))?,
+        None => 0,
+    };
+    // Don't silently create a database for a room that doesn't exist
+    if !storage::room_exists(room_id)? {
+        return Err(warp::reject::custom(errors::Error::NoSuchRoom));
+    }
+    let pool = storage::pool_by_room_id(room_id);
+    handlers::check_export_authorization(auth_token, &pool)?;
+    return Ok((pool, from_server_id));
+}
+
 pub async fn fallback_html(
     room: String, query_map: HashMap<String, String>,
 ) -> Result<Response, Rejection> {
-    if !query_map.contains_key("public_key") || room == "" {
+    if !query_map.contains_key("public_key") || room.is_empty() {
         return fallback_nopubkey_html().await;
     }
     let body = r#"
@@ -1,12 +1,27 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
-use log::warn;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, warn};
+use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
+use warp::http::header::{HeaderMap, HeaderValue, CONTENT_ENCODING};
 use warp::{http::StatusCode, reply::Reply, reply::Response, Rejection};
 
+use super::crypto;
+use super::errors;
 use super::errors::Error;
 use super::handlers;
+use super::logging;
+use super::metrics;
 use super::models;
+use super::rate_limiting;
 use super::storage;
 
 #[allow(dead_code)]
@@ -25,7 +40,51 @@ pub struct RpcCall {
 
 pub const MODE: Mode = Mode::OpenGroupServer;
 
+// Response bodies smaller than this aren't compressed, because the gzip overhead would outweigh the gains
+const MIN_COMPRESSED_BODY_SIZE: usize = 1024;
+
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+const MAX_BATCH_SIZE: usize = 16;
+
+// Under load the recommended poll interval grows to at most this many times the configured one
+const MAX_POLL_INTERVAL_FACTOR: u64 = 10;
+
+// Endpoints that can be read without an auth token even if `--require-auth-for-reads` is set, because
+// clients need them to get an auth token or to find out what the server supports
+const PUBLIC_READ_ENDPOINTS: [&str; 5] =
+    ["auth_token_challenge", "capabilities", "health", "server_info", "whoami"];
+
+/// The header in which `HEAD /messages` returns the server ID of the most recent message.
+pub const LAST_SERVER_ID_HEADER: &str = "X-Last-Server-Id";
+
+// POST endpoints that only read, and so keep working in maintenance mode. The calls in a batch
+// are checked individually.
+const READ_ONLY_POST_ENDPOINTS: [&str; 3] = ["compact_poll", "batch", "messages/missing"];
+
 pub async fn handle_rpc_call(rpc_call: RpcCall) -> Result<Response, Rejection> {
+    // Tag every log line produced while handling the call with the same request ID, so that the
+    // full lifecycle of a call can be found in the logs
+    let request_id = get_request_id(&rpc_call);
+    rate_limiting::REQUEST_COUNTER.record();
+    let method = match rpc_call.method.as_ref() {
+        "GET" | "HEAD" | "POST" | "DELETE" | "PUT" | "PATCH" => rpc_call.method.clone(),
+        _ => "other".to_string(),
+    };
+    let endpoint = rpc_call.endpoint.clone();
+    let start = Instant::now();
+    let result = logging::with_request_id(&request_id, dispatch_rpc_call(rpc_call)).await;
+    // Update the metrics
+    let endpoint = get_endpoint_label(&endpoint);
+    metrics::RPC_CALLS.with_label_values(&[&method, &endpoint]).inc();
+    metrics::RPC_CALL_DURATION
+        .with_label_values(&[&method, &endpoint])
+        .observe(start.elapsed().as_secs_f64());
+    return result;
+}
+
+async fn dispatch_rpc_call(rpc_call: RpcCall) -> Result<Response, Rejection> {
+    debug!("Handling {} RPC call to: {}.", rpc_call.method, rpc_call.endpoint);
     // Check that the endpoint is a valid URI and deconstruct it into a path
     // and query parameters.
     // Adding "http://placeholder.io" in front of the endpoint is a workaround
@@ -36,33 +95,86 @@ pub async fn handle_rpc_call(rpc_call: RpcCall) -> Result<Response, Rejection> {
         Ok(uri) => uri.path().trim_start_matches('/').to_string(),
         Err(e) => {
             warn!("Couldn't parse URI from: {} due to error: {}.", &raw_uri, e);
-            return Err(warp::reject::custom(Error::InvalidRpcCall));
+            return Err(warp::reject::custom(Error::InvalidUri));
         }
     };
-    let query_params: HashMap<String, String> = match url::Url::parse(&raw_uri) {
-        Ok(url) => url.query_pairs().into_owned().collect(),
+    let query_params = match url::Url::parse(&raw_uri) {
+        Ok(url) => parse_query_params(&url)?,
         Err(e) => {
             warn!("Couldn't parse URL from: {} due to error: {}.", &raw_uri, e);
-            return Err(warp::reject::custom(Error::InvalidRpcCall));
+            return Err(warp::reject::custom(Error::InvalidUri));
         }
     };
     // Get the auth token if possible
     let auth_token = get_auth_token(&rpc_call);
     // Get the room ID
-    let room_id = get_room_id(&rpc_call);
+    // The room can either be specified using the `Room` header or by prefixing the endpoint
+    // with `rooms/:room_id/`, in which case the prefix takes precedence.
+    let (room_id, path) = match split_room_prefix(&path) {
+        Some((room_id, path)) => (Some(room_id), path),
+        None => (get_room_id(&rpc_call), path),
+    };
+    // Only reads are possible in maintenance mode
+    if super::MAINTENANCE_MODE.load(Ordering::SeqCst) && is_write(&rpc_call.method, &path) {
+        warn!("Ignoring {} RPC call to: {} in maintenance mode.", rpc_call.method, path);
+        return Err(warp::reject::custom(Error::Maintenance));
+    }
+    // Record activity for authenticated calls
+    // This happens here rather than in the individual handlers so that no endpoint is missed, and
+    // after the maintenance mode check so that rejected calls don't write anything. We want to fail
+    // silently if any of this goes wrong.
+    if let (Some(auth_token), Some(room_id)) = (&auth_token, &room_id) {
+        if let Ok(true) = storage::room_exists(room_id) {
+            let pool = storage::pool_by_room_id(room_id);
+            if handlers::update_last_active(auth_token, &pool).is_err() {
+                debug!("Couldn't update last active timestamp.");
+            }
+        }
+    }
+    // Timestamps are integers by default, but web clients can ask for ISO 8601 strings as well
+    let include_iso_timestamps =
+        query_params.get("include_iso_timestamps").map(|str| str.as_str()) == Some("true");
     // Switch on the HTTP method
     match rpc_call.method.as_ref() {
         "GET" => {
-            return handle_get_request(room_id, rpc_call, &path, auth_token, query_params).await
+            let accepts_gzip = accepts_gzip(&rpc_call);
+            let response =
+                handle_get_request(room_id, rpc_call, &path, auth_token, query_params).await?;
+            let mut response = add_poll_interval(response, get_current_poll_interval()).await?;
+            if include_iso_timestamps {
+                response = add_iso_timestamps(response).await?;
+            }
+            if accepts_gzip {
+                return compress_response(response).await;
+            }
+            return Ok(response);
+        }
+        "HEAD" => {
+            return handle_head_request(room_id, rpc_call, &path, auth_token, query_params).await
+        }
+        "POST" => {
+            let response = handle_post_request(room_id, rpc_call, &path, auth_token).await?;
+            if include_iso_timestamps {
+                return add_iso_timestamps(response).await;
+            }
+            return Ok(response);
         }
-        "POST" => return handle_post_request(room_id, rpc_call, &path, auth_token).await,
         "DELETE" => {
-            let pool = get_pool_for_room(&rpc_call)?;
-            return handle_delete_request(rpc_call, &path, auth_token, &pool).await;
+            let pool = get_pool_for_room(&room_id)?;
+            return handle_delete_request(room_id, &path, auth_token, query_params, &pool).await;
+        }
+        "PUT" => {
+            let pool = get_pool_for_room(&room_id)?;
+            return handle_put_request(room_id, rpc_call, &path, auth_token, query_params, &pool)
+                .await;
+        }
+        "PATCH" => {
+            let pool = get_pool_for_room(&room_id)?;
+            return handle_patch_request(room_id, rpc_call, &path, auth_token, &pool).await;
         }
         _ => {
             warn!("Ignoring RPC call with invalid or unused HTTP method: {}.", rpc_call.method);
-            return Err(warp::reject::custom(Error::InvalidRpcCall));
+            return Err(warp::reject::custom(Error::MethodNotAllowed));
         }
     }
 }
@@ -71,10 +183,19 @@ async fn handle_get_request(
     room_id: Option<String>, rpc_call: RpcCall, path: &str, auth_token: Option<String>,
     query_params: HashMap<String, String>,
 ) -> Result<Response, Rejection> {
+    // Private communities can require auth for everything except what's needed to get an auth token
+    if !PUBLIC_READ_ENDPOINTS.contains(&path) && matches!(MODE, Mode::OpenGroupServer) {
+        // The room of `GET /rooms/:room_id` is part of the path
+        let room_id = match path.strip_prefix("rooms/") {
+            Some(rest) => rest.split('/').next(),
+            None => room_id.as_deref(),
+        };
+        handlers::check_read_authorization(room_id, auth_token.as_deref())?;
+    }
     // Handle routes that don't require authorization first
     if path == "auth_token_challenge" {
         reject_if_file_server_mode(path)?;
-        let pool = get_pool_for_room(&rpc_call)?;
+        let pool = get_pool_for_room(&room_id)?;
         let challenge = handlers::get_auth_token_challenge(query_params, &pool)?;
         #[derive(Debug, Deserialize, Serialize)]
         struct Response {
@@ -90,25 +211,32 @@ async fn handle_get_request(
             return handlers::get_all_rooms();
         } else if components.len() == 2 {
             let room_id = components[1];
-            return handlers::get_room(&room_id);
+            return handlers::get_room(room_id);
         } else if components.len() == 3 && components[2] == "image" {
             let room_id = components[1];
-            return handlers::get_group_image(&room_id).await;
+            return handlers::get_group_image(room_id).await;
         } else {
             warn!("Invalid endpoint: {}.", rpc_call.endpoint);
             return Err(warp::reject::custom(Error::InvalidRpcCall));
         }
+    } else if path == "health" {
+        return handlers::check_health(&storage::MAIN_POOL);
+    } else if path == "server_info" {
+        return handlers::get_server_info();
+    } else if path == "capabilities" {
+        reject_if_file_server_mode(path)?;
+        return handlers::get_capabilities();
     } else if path.starts_with("session_version") {
         match MODE {
             Mode::OpenGroupServer => {
                 warn!("Ignoring RPC call with invalid or unused endpoint: {}.", path);
-                return Err(warp::reject::custom(Error::InvalidRpcCall));
+                return Err(warp::reject::custom(Error::NoSuchEndpoint));
             }
             Mode::FileServer => (),
         }
         let platform = query_params
             .get("platform")
-            .ok_or_else(|| warp::reject::custom(Error::InvalidRpcCall))?;
+            .ok_or_else(|| warp::reject::custom(Error::InvalidQuery))?;
         let version = handlers::get_session_version(platform).await?;
         let response = handlers::GenericStringResponse {
             status_code: StatusCode::OK.as_u16(),
@@ -117,9 +245,14 @@ async fn handle_get_request(
         return Ok(warp::reply::json(&response).into_response());
     }
     // This route requires auth in open group server mode, but not in file server mode
-    let pool = get_pool_for_room(&rpc_call)?;
+    let pool = get_pool_for_room(&room_id)?;
+    // GET /files/:upload_id/progress
+    // GET /files/:id
     if path.starts_with("files") {
         let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() == 3 && components[2] == "progress" {
+            return handlers::get_upload_progress(components[1], auth_token, &pool);
+        }
         if components.len() != 2 {
             warn!("Invalid endpoint: {}.", rpc_call.endpoint);
             return Err(warp::reject::custom(Error::InvalidRpcCall));
@@ -135,12 +268,140 @@ async fn handle_get_request(
             .await
             .map(|json| warp::reply::json(&json).into_response());
     }
+    // Callers without a valid auth token get an anonymous result
+    if path == "whoami" {
+        reject_if_file_server_mode(path)?;
+        return handlers::whoami(auth_token.as_deref(), &pool);
+    }
     // Handle routes that require authorization
     let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
+    // GET /profile/:public_key
+    if path.starts_with("profile/") {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 2 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        reject_if_invalid_public_key(components[1])?;
+        return handlers::get_profile(components[1], &auth_token, &pool);
+    }
+    // GET /block_list/:public_key
+    if path.starts_with("block_list/") {
+        reject_if_file_server_mode(path)?;
+        reject_if_allow_list_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 2 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        reject_if_invalid_public_key(components[1])?;
+        return handlers::get_ban_status(components[1], &auth_token, &pool);
+    }
+    // GET /messages/count_by_user/:public_key
+    if path.starts_with("messages/count_by_user/") {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 3 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        reject_if_invalid_public_key(components[2])?;
+        return handlers::get_user_message_count(components[2], query_params, &auth_token, &pool);
+    }
+    // GET /messages/:server_id and GET /messages/:server_id/thread
+    if path.starts_with("messages/") {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        let is_thread = components.len() == 3 && components[2] == "thread";
+        let is_context = components.len() == 3 && components[2] == "context";
+        if components.len() != 2 && !is_thread && !is_context {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let server_id: i64 = match components[1].parse() {
+            Ok(server_id) => server_id,
+            Err(_) => {
+                warn!("Invalid endpoint: {}.", path);
+                return Err(warp::reject::custom(Error::InvalidRpcCall));
+            }
+        };
+        if is_thread {
+            return handlers::get_thread(server_id, query_params, &auth_token, &pool);
+        }
+        if is_context {
+            return handlers::get_message_context(server_id, query_params, &auth_token, &pool);
+        }
+        return handlers::get_message(server_id, &auth_token, &pool);
+    }
     match path {
         "messages" => {
             reject_if_file_server_mode(path)?;
-            let messages = handlers::get_messages(query_params, &auth_token, &pool)?;
+            // Clients that are already caught up get a 304 without any messages being fetched
+            if let Some(str) = query_params.get("if_newer_than") {
+                let server_id: i64 = match str.parse() {
+                    Ok(server_id) => server_id,
+                    Err(_) => {
+                        warn!("Ignoring get messages request with invalid if_newer_than: {}.", str);
+                        return Err(warp::reject::custom(Error::InvalidQuery));
+                    }
+                };
+                if !handlers::has_messages_after(server_id, &auth_token, &pool)? {
+                    return Ok(StatusCode::NOT_MODIFIED.into_response());
+                }
+            }
+            let fields = query_params.get("fields").cloned();
+            let include_meta =
+                query_params.get("include_meta").map(|str| str.as_str()) == Some("true");
+            let read_pool = get_read_pool_for_room(&room_id)?;
+            let page = handlers::get_message_page(query_params, &auth_token, &pool, &read_pool)?;
+            let messages = page.messages;
+            // Tell clients that are paging through messages whether there are more
+            if include_meta {
+                #[derive(Debug, Deserialize, Serialize)]
+                struct Response {
+                    status_code: u16,
+                    messages: Vec<serde_json::Value>,
+                    has_more: bool,
+                    next_from_server_id: Option<i64>,
+                }
+                let messages = match fields {
+                    Some(fields) => handlers::select_message_fields(messages, &fields),
+                    None => messages
+                        .into_iter()
+                        .filter_map(|message| serde_json::to_value(message).ok())
+                        .collect(),
+                };
+                let response = Response {
+                    status_code: StatusCode::OK.as_u16(),
+                    messages,
+                    has_more: page.has_more,
+                    next_from_server_id: page.next_from_server_id,
+                };
+                return Ok(warp::reply::json(&response).into_response());
+            }
+            // Only include the requested fields if the client asked for a subset
+            if let Some(fields) = fields {
+                #[derive(Debug, Deserialize, Serialize)]
+                struct Response {
+                    status_code: u16,
+                    messages: Vec<serde_json::Value>,
+                }
+                let messages = handlers::select_message_fields(messages, &fields);
+                let response = Response { status_code: StatusCode::OK.as_u16(), messages };
+                return Ok(warp::reply::json(&response).into_response());
+            }
+            #[derive(Debug, Deserialize, Serialize)]
+            struct Response {
+                status_code: u16,
+                messages: Vec<models::Message>,
+            }
+            let response = Response { status_code: StatusCode::OK.as_u16(), messages };
+            return Ok(warp::reply::json(&response).into_response());
+        }
+        "search" => {
+            reject_if_file_server_mode(path)?;
+            let messages = handlers::search_messages(query_params, &auth_token, &pool)?;
             #[derive(Debug, Deserialize, Serialize)]
             struct Response {
                 status_code: u16,
@@ -151,7 +412,9 @@ async fn handle_get_request(
         }
         "deleted_messages" => {
             reject_if_file_server_mode(path)?;
-            let deletions = handlers::get_deleted_messages(query_params, &auth_token, &pool)?;
+            let read_pool = get_read_pool_for_room(&room_id)?;
+            let deletions =
+                handlers::get_deleted_messages(query_params, &auth_token, &pool, &read_pool)?;
             #[derive(Debug, Deserialize, Serialize)]
             struct Response {
                 status_code: u16,
@@ -174,17 +437,120 @@ async fn handle_get_request(
         }
         "block_list" => {
             reject_if_file_server_mode(path)?;
-            return handlers::get_banned_public_keys(&auth_token, &pool);
+            reject_if_allow_list_mode(path)?;
+            return handlers::get_banned_public_keys(query_params, &auth_token, &pool);
+        }
+        "allow_list" => {
+            reject_if_file_server_mode(path)?;
+            return handlers::get_allowed_public_keys(&auth_token, &pool);
+        }
+        "cooldowns" => {
+            reject_if_file_server_mode(path)?;
+            return handlers::get_cooldowns(&auth_token, &pool);
+        }
+        "mute_list" => {
+            reject_if_file_server_mode(path)?;
+            return handlers::get_muted_public_keys(&auth_token, &pool);
+        }
+        "invites" => {
+            reject_if_file_server_mode(path)?;
+            return handlers::get_invites(&auth_token, &pool);
         }
         "member_count" => {
             reject_if_file_server_mode(path)?;
-            return handlers::get_member_count(&auth_token, &pool);
+            return handlers::get_member_count(query_params, &auth_token, &pool);
+        }
+        "active_members" => {
+            reject_if_file_server_mode(path)?;
+            return handlers::get_active_members(query_params, &auth_token, &pool);
+        }
+        "reports" => {
+            reject_if_file_server_mode(path)?;
+            return handlers::get_reports(query_params, &auth_token, &pool);
+        }
+        "message_count" => {
+            reject_if_file_server_mode(path)?;
+            let read_pool = get_read_pool_for_room(&room_id)?;
+            return handlers::get_message_count(query_params, &auth_token, &pool, &read_pool);
+        }
+        "message_ids" => {
+            reject_if_file_server_mode(path)?;
+            let read_pool = get_read_pool_for_room(&room_id)?;
+            return handlers::get_message_ids(query_params, &auth_token, &pool, &read_pool);
+        }
+        "pinned_messages" => {
+            reject_if_file_server_mode(path)?;
+            let messages = handlers::get_pinned_messages(&auth_token, &pool)?;
+            #[derive(Debug, Deserialize, Serialize)]
+            struct Response {
+                status_code: u16,
+                messages: Vec<models::Message>,
+            }
+            let response = Response { status_code: StatusCode::OK.as_u16(), messages };
+            return Ok(warp::reply::json(&response).into_response());
+        }
+        "room_info" => {
+            reject_if_file_server_mode(path)?;
+            let room_id = get_room_id_or_reject(&room_id)?;
+            return handlers::get_room_info(room_id, &auth_token, &pool);
+        }
+        "last_server_id" => {
+            reject_if_file_server_mode(path)?;
+            let last_server_id = handlers::get_last_server_id(&auth_token, &pool)?;
+            #[derive(Debug, Deserialize, Serialize)]
+            struct Response {
+                status_code: u16,
+                last_server_id: Option<i64>,
+            }
+            let response = Response { status_code: StatusCode::OK.as_u16(), last_server_id };
+            return Ok(warp::reply::json(&response).into_response());
         }
         _ => {
             warn!("Ignoring RPC call with invalid or unused endpoint: {}.", rpc_call.endpoint);
-            return Err(warp::reject::custom(Error::InvalidRpcCall));
+            return Err(warp::reject::custom(Error::NoSuchEndpoint));
+        }
+    }
+}
+
+/// HEAD requests are handled like GET requests, except that only the status code and headers are
+/// returned.
+async fn handle_head_request(
+    room_id: Option<String>, rpc_call: RpcCall, path: &str, auth_token: Option<String>,
+    query_params: HashMap<String, String>,
+) -> Result<Response, Rejection> {
+    // HEAD /messages
+    // Pollers only need to know whether there's anything new, so there's no need to get the
+    // messages themselves
+    if path == "messages" {
+        reject_if_file_server_mode(path)?;
+        handlers::check_read_authorization(room_id.as_deref(), auth_token.as_deref())?;
+        let pool = get_pool_for_room(&room_id)?;
+        let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
+        let mut response = StatusCode::OK.into_response();
+        // The header is left out if the room doesn't have any messages yet
+        if let Some(last_server_id) = handlers::get_last_server_id(&auth_token, &pool)? {
+            let value = HeaderValue::from(last_server_id);
+            response.headers_mut().insert(LAST_SERVER_ID_HEADER, value);
         }
+        return Ok(response);
     }
+    // Only GET routes support HEAD requests, so endpoints that don't have one but do exist for
+    // other methods get a 405 rather than a 404
+    let response = match handle_get_request(room_id, rpc_call, path, auth_token, query_params).await
+    {
+        Ok(response) => response,
+        Err(e) => match e.find::<Error>() {
+            Some(Error::InvalidRpcCall) | Some(Error::NoSuchEndpoint)
+                if metrics::is_route(path) =>
+            {
+                warn!("Ignoring HEAD request to endpoint without a GET route: {}.", path);
+                return Err(warp::reject::custom(Error::MethodNotAllowed));
+            }
+            _ => return Err(e),
+        },
+    };
+    let (parts, _) = response.into_parts();
+    return Ok(Response::from_parts(parts, warp::hyper::Body::empty()));
 }
 
 async fn handle_post_request(
@@ -206,26 +572,70 @@ async fn handle_post_request(
                     "Couldn't parse compact poll request body wrapper from: {} due to error: {}.",
                     rpc_call.body, e
                 );
-                return Err(warp::reject::custom(Error::InvalidRpcCall));
+                return Err(warp::reject::custom(Error::InvalidBody));
             }
         };
-        return handlers::compact_poll(wrapper.requests);
+        let response = handlers::compact_poll(wrapper.requests)?;
+        return add_poll_interval(response, get_current_poll_interval()).await;
+    }
+    // Batched calls carry their own headers, so authorization is handled per call
+    if path == "batch" {
+        return handle_batch_request(&rpc_call.body).await;
     }
     // This route requires auth in open group server mode, but not in file server mode
-    let pool = get_pool_for_room(&rpc_call)?;
+    let pool = get_pool_for_room(&room_id)?;
     if path == "files" {
         #[derive(Debug, Deserialize)]
         struct JSON {
             file: String,
+            metadata: Option<String>,
+            content_type: Option<String>,
         }
         let json: JSON = match serde_json::from_str(&rpc_call.body) {
             Ok(json) => json,
             Err(e) => {
                 warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
-                return Err(warp::reject::custom(Error::InvalidRpcCall));
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+        };
+        let metadata = json.metadata.as_deref();
+        let content_type = json.content_type.as_deref();
+        return handlers::store_file(
+            room_id,
+            &json.file,
+            metadata,
+            content_type,
+            auth_token,
+            &pool,
+        )
+        .await;
+    }
+    if path == "files/init" {
+        #[derive(Debug, Deserialize)]
+        struct JSON {
+            size: u64,
+            metadata: Option<String>,
+            content_type: Option<String>,
+        }
+        let json: JSON = match serde_json::from_str(&rpc_call.body) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                return Err(warp::reject::custom(Error::InvalidBody));
             }
         };
-        return handlers::store_file(room_id, &json.file, auth_token, &pool).await;
+        let metadata = json.metadata.as_deref();
+        let content_type = json.content_type.as_deref();
+        return handlers::init_upload(json.size, metadata, content_type, auth_token, &pool);
+    }
+    // POST /files/:upload_id/complete
+    if path.starts_with("files/") {
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 3 || components[2] != "complete" {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        return handlers::complete_upload(room_id, components[1], auth_token, &pool).await;
     }
     // Handle routes that require authorization
     let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
@@ -241,16 +651,141 @@ async fn handle_post_request(
                 Ok(json) => json,
                 Err(e) => {
                     warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
-                    return Err(warp::reject::custom(Error::InvalidRpcCall));
+                    return Err(warp::reject::custom(Error::InvalidBody));
                 }
             };
             let room_id = components[1];
-            return handlers::set_group_image(&json.file, &room_id, &auth_token, &pool).await;
+            return handlers::set_group_image(&json.file, room_id, &auth_token, &pool).await;
         } else {
             warn!("Invalid endpoint: {}.", rpc_call.endpoint);
             return Err(warp::reject::custom(Error::InvalidRpcCall));
         }
     }
+    // POST /invites/:code/redeem
+    if path.starts_with("invites/") {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 3 || components[2] != "redeem" {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        return handlers::redeem_invite(components[1], &auth_token, &pool);
+    }
+    // POST /messages/batch
+    if path == "messages/batch" {
+        reject_if_file_server_mode(path)?;
+        #[derive(Debug, Deserialize)]
+        struct JSON {
+            messages: Vec<models::Message>,
+        }
+        let json: JSON = match serde_json::from_str(&rpc_call.body) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Couldn't parse messages from: {} due to error: {}.", rpc_call.body, e);
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+        };
+        let room_id = get_room_id_or_reject(&room_id)?;
+        return handlers::insert_messages(room_id, json.messages, &auth_token, &pool);
+    }
+    // POST /messages/missing
+    if path == "messages/missing" {
+        reject_if_file_server_mode(path)?;
+        // This is a read, even though it's a POST request
+        handlers::check_read_authorization(room_id.as_deref(), Some(&auth_token))?;
+        #[derive(Debug, Deserialize)]
+        struct JSON {
+            from_server_id: i64,
+            #[serde(default)]
+            seen_server_ids: Vec<i64>,
+            limit: Option<u16>,
+        }
+        let json: JSON = match serde_json::from_str(&rpc_call.body) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+        };
+        let read_pool = get_read_pool_for_room(&room_id)?;
+        return handlers::get_missing_messages(
+            json.from_server_id,
+            json.seen_server_ids,
+            json.limit,
+            &auth_token,
+            &pool,
+            &read_pool,
+        );
+    }
+    // POST /messages/:server_id/reactions, POST /messages/:server_id/report,
+    // POST /messages/:server_id/pin, POST /messages/:server_id/restore and
+    // POST /messages/:server_id/read
+    if path.starts_with("messages/") {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 3 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let server_id: i64 = match components[1].parse() {
+            Ok(server_id) => server_id,
+            Err(_) => {
+                warn!("Invalid endpoint: {}.", path);
+                return Err(warp::reject::custom(Error::InvalidRpcCall));
+            }
+        };
+        match components[2] {
+            "reactions" => {
+                #[derive(Debug, Deserialize)]
+                struct JSON {
+                    emoji: String,
+                }
+                let json: JSON = match serde_json::from_str(&rpc_call.body) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                        return Err(warp::reject::custom(Error::InvalidBody));
+                    }
+                };
+                return handlers::add_reaction(server_id, &json.emoji, &auth_token, &pool);
+            }
+            "report" => {
+                #[derive(Debug, Deserialize)]
+                struct JSON {
+                    reason: Option<String>,
+                }
+                // The body is optional for this route
+                let json: JSON = if rpc_call.body.is_empty() {
+                    JSON { reason: None }
+                } else {
+                    match serde_json::from_str(&rpc_call.body) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            warn!(
+                                "Couldn't parse JSON from: {} due to error: {}.",
+                                rpc_call.body, e
+                            );
+                            return Err(warp::reject::custom(Error::InvalidBody));
+                        }
+                    }
+                };
+                return handlers::report_message(server_id, json.reason, &auth_token, &pool);
+            }
+            "pin" => {
+                return handlers::pin_message(server_id, &auth_token, &pool);
+            }
+            "restore" => {
+                return handlers::restore_message(server_id, &auth_token, &pool);
+            }
+            "read" => {
+                return handlers::mark_message_as_read(server_id, &auth_token, &pool);
+            }
+            _ => {
+                warn!("Invalid endpoint: {}.", path);
+                return Err(warp::reject::custom(Error::InvalidRpcCall));
+            }
+        }
+    }
     match path {
         "messages" => {
             reject_if_file_server_mode(path)?;
@@ -258,28 +793,100 @@ async fn handle_post_request(
                 Ok(message) => message,
                 Err(e) => {
                     warn!("Couldn't parse message from: {} due to error: {}.", rpc_call.body, e);
-                    return Err(warp::reject::custom(Error::InvalidRpcCall));
+                    return Err(warp::reject::custom(Error::InvalidBody));
                 }
             };
-            return handlers::insert_message(message, &auth_token, &pool);
+            let room_id = get_room_id_or_reject(&room_id)?;
+            return handlers::insert_message(room_id, message, &auth_token, &pool);
         }
         "block_list" => {
             reject_if_file_server_mode(path)?;
+            reject_if_allow_list_mode(path)?;
             #[derive(Debug, Deserialize)]
             struct JSON {
                 public_key: String,
+                duration: Option<i64>,
+                reason: Option<String>,
             }
             let json: JSON = match serde_json::from_str(&rpc_call.body) {
                 Ok(json) => json,
                 Err(e) => {
                     warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
-                    return Err(warp::reject::custom(Error::InvalidRpcCall));
+                    return Err(warp::reject::custom(Error::InvalidBody));
                 }
             };
-            return handlers::ban(&json.public_key, &auth_token, &pool);
+            return handlers::ban(&json.public_key, json.duration, json.reason, &auth_token, &pool);
+        }
+        "mute_list" => {
+            reject_if_file_server_mode(path)?;
+            #[derive(Debug, Deserialize)]
+            struct JSON {
+                public_key: String,
+            }
+            let json: JSON = match serde_json::from_str(&rpc_call.body) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                    return Err(warp::reject::custom(Error::InvalidBody));
+                }
+            };
+            return handlers::mute(&json.public_key, &auth_token, &pool);
+        }
+        "allow_list" => {
+            reject_if_file_server_mode(path)?;
+            #[derive(Debug, Deserialize)]
+            struct JSON {
+                public_key: String,
+            }
+            let json: JSON = match serde_json::from_str(&rpc_call.body) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                    return Err(warp::reject::custom(Error::InvalidBody));
+                }
+            };
+            return handlers::allow(&json.public_key, &auth_token, &pool);
+        }
+        "cooldowns" => {
+            reject_if_file_server_mode(path)?;
+            #[derive(Debug, Deserialize)]
+            struct JSON {
+                public_key: String,
+                duration: i64,
+            }
+            let json: JSON = match serde_json::from_str(&rpc_call.body) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                    return Err(warp::reject::custom(Error::InvalidBody));
+                }
+            };
+            return handlers::set_cooldown(&json.public_key, json.duration, &auth_token, &pool);
+        }
+        "invites" => {
+            reject_if_file_server_mode(path)?;
+            #[derive(Debug, Deserialize)]
+            struct JSON {
+                max_redemptions: Option<u32>,
+                duration: Option<i64>,
+            }
+            let json: JSON = match serde_json::from_str(&rpc_call.body) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                    return Err(warp::reject::custom(Error::InvalidBody));
+                }
+            };
+            return handlers::create_invite(
+                json.max_redemptions,
+                json.duration,
+                &auth_token,
+                &pool,
+            );
         }
         "ban_and_delete_all" => {
             reject_if_file_server_mode(path)?;
+            reject_if_allow_list_mode(path)?;
             #[derive(Debug, Deserialize)]
             struct JSON {
                 public_key: String,
@@ -288,7 +895,7 @@ async fn handle_post_request(
                 Ok(json) => json,
                 Err(e) => {
                     warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
-                    return Err(warp::reject::custom(Error::InvalidRpcCall));
+                    return Err(warp::reject::custom(Error::InvalidBody));
                 }
             };
             return handlers::ban_and_delete_all_messages(&json.public_key, &auth_token, &pool);
@@ -303,7 +910,7 @@ async fn handle_post_request(
                 Ok(json) => json,
                 Err(e) => {
                     warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
-                    return Err(warp::reject::custom(Error::InvalidRpcCall));
+                    return Err(warp::reject::custom(Error::InvalidBody));
                 }
             };
             return handlers::claim_auth_token(&json.public_key, &auth_token, &pool);
@@ -315,11 +922,42 @@ async fn handle_post_request(
                     Ok(body) => body,
                     Err(e) => {
                         warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
-                        return Err(warp::reject::custom(Error::InvalidRpcCall));
+                        return Err(warp::reject::custom(Error::InvalidBody));
                     }
                 };
             return handlers::add_moderator_public(body, &auth_token).await;
         }
+        "profile" => {
+            reject_if_file_server_mode(path)?;
+            #[derive(Debug, Deserialize)]
+            struct JSON {
+                display_name: String,
+                avatar: Option<String>,
+            }
+            let json: JSON = match serde_json::from_str(&rpc_call.body) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                    return Err(warp::reject::custom(Error::InvalidBody));
+                }
+            };
+            return handlers::set_profile(&json.display_name, json.avatar, &auth_token, &pool);
+        }
+        "moderators/bulk" => {
+            reject_if_file_server_mode(path)?;
+            #[derive(Debug, Deserialize)]
+            struct JSON {
+                public_keys: Vec<String>,
+            }
+            let json: JSON = match serde_json::from_str(&rpc_call.body) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                    return Err(warp::reject::custom(Error::InvalidBody));
+                }
+            };
+            return handlers::add_moderators(json.public_keys, &auth_token, &pool);
+        }
         "delete_messages" => {
             reject_if_file_server_mode(path)?;
             #[derive(Debug, Deserialize)]
@@ -330,29 +968,49 @@ async fn handle_post_request(
                 Ok(json) => json,
                 Err(e) => {
                     warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
-                    return Err(warp::reject::custom(Error::InvalidRpcCall));
+                    return Err(warp::reject::custom(Error::InvalidBody));
                 }
             };
             return handlers::delete_messages(json.ids, &auth_token, &pool);
         }
         _ => {
             warn!("Ignoring RPC call with invalid or unused endpoint: {}.", path);
-            return Err(warp::reject::custom(Error::InvalidRpcCall));
+            return Err(warp::reject::custom(Error::NoSuchEndpoint));
         }
     }
 }
 
 async fn handle_delete_request(
-    rpc_call: RpcCall, path: &str, auth_token: Option<String>,
-    pool: &storage::DatabaseConnectionPool,
+    room_id: Option<String>, path: &str, auth_token: Option<String>,
+    query_params: HashMap<String, String>, pool: &storage::DatabaseConnectionPool,
 ) -> Result<Response, Rejection> {
     // Check that the auth token is present
     let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
-    // DELETE /messages/:server_id
-    if path.starts_with("messages") {
+    // Treat e.g. `messages/5/` the same as `messages/5`
+    let path = path.strip_suffix('/').unwrap_or(path);
+    // Route on the first path component rather than on a prefix, so that e.g. `messagesfoo/5`
+    // doesn't end up being treated as `messages/5`
+    let resource = path.split('/').next().unwrap_or("");
+    // DELETE /messages/by_user/:public_key
+    if path.starts_with("messages/by_user/") {
         reject_if_file_server_mode(path)?;
         let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
-        if components.len() != 2 {
+        if components.len() != 3 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let public_key = components[2].to_string();
+        reject_if_invalid_public_key(&public_key)?;
+        return handlers::delete_messages_for_user(&public_key, &auth_token, pool);
+    }
+    // DELETE /messages/:server_id, DELETE /messages/:server_id/reactions/:emoji and
+    // DELETE /messages/:server_id/pin
+    if resource == "messages" {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        let is_reaction = components.len() == 4 && components[2] == "reactions";
+        let is_pin = components.len() == 3 && components[2] == "pin";
+        if components.len() != 2 && !is_reaction && !is_pin {
             warn!("Invalid endpoint: {}.", path);
             return Err(warp::reject::custom(Error::InvalidRpcCall));
         }
@@ -363,26 +1021,113 @@ async fn handle_delete_request(
                 return Err(warp::reject::custom(Error::InvalidRpcCall));
             }
         };
+        if is_reaction {
+            // The emoji will usually be percent encoded
+            let emoji = match percent_decode_str(components[3]).decode_utf8() {
+                Ok(emoji) => emoji,
+                Err(_) => {
+                    warn!("Invalid endpoint: {}.", path);
+                    return Err(warp::reject::custom(Error::InvalidRpcCall));
+                }
+            };
+            return handlers::remove_reaction(server_id, &emoji, &auth_token, pool);
+        }
+        if is_pin {
+            return handlers::unpin_message(server_id, &auth_token, pool);
+        }
         return handlers::delete_message(server_id, &auth_token, pool);
     }
     // DELETE /block_list/:public_key
-    if path.starts_with("block_list") {
+    if resource == "block_list" {
         reject_if_file_server_mode(path)?;
+        reject_if_allow_list_mode(path)?;
         let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
         if components.len() != 2 {
             warn!("Invalid endpoint: {}.", path);
             return Err(warp::reject::custom(Error::InvalidRpcCall));
         }
         let public_key = components[1].to_string();
+        reject_if_invalid_public_key(&public_key)?;
         return handlers::unban(&public_key, &auth_token, pool);
     }
+    // DELETE /mute_list/:public_key
+    if resource == "mute_list" {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 2 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let public_key = components[1].to_string();
+        reject_if_invalid_public_key(&public_key)?;
+        return handlers::unmute(&public_key, &auth_token, pool);
+    }
+    // DELETE /allow_list/:public_key
+    if resource == "allow_list" {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 2 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let public_key = components[1].to_string();
+        reject_if_invalid_public_key(&public_key)?;
+        return handlers::disallow(&public_key, &auth_token, pool);
+    }
+    // DELETE /cooldowns/:public_key
+    if resource == "cooldowns" {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 2 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let public_key = components[1].to_string();
+        reject_if_invalid_public_key(&public_key)?;
+        return handlers::remove_cooldown(&public_key, &auth_token, pool);
+    }
+    // DELETE /invites/:code
+    if resource == "invites" {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 2 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        return handlers::revoke_invite(components[1], &auth_token, pool);
+    }
+    // DELETE /deleted_messages
+    if path == "deleted_messages" {
+        reject_if_file_server_mode(path)?;
+        let before: i64 = match query_params.get("before").map(|str| str.parse()) {
+            Some(Ok(before)) => before,
+            _ => {
+                warn!("Ignoring prune deleted messages request without a valid before parameter.");
+                return Err(warp::reject::custom(Error::InvalidQuery));
+            }
+        };
+        return handlers::prune_deleted_messages(before, &auth_token, pool);
+    }
+    // DELETE /room
+    if path == "room" {
+        reject_if_file_server_mode(path)?;
+        let tombstone = match query_params.get("tombstone").map(|str| str.as_str()) {
+            Some("true") | None => true,
+            Some("false") => false,
+            Some(str) => {
+                warn!("Ignoring purge room request with invalid tombstone: {}.", str);
+                return Err(warp::reject::custom(Error::InvalidQuery));
+            }
+        };
+        return handlers::purge_room(tombstone, &auth_token, pool);
+    }
     // DELETE /auth_token
     if path == "auth_token" {
         reject_if_file_server_mode(path)?;
         return handlers::delete_auth_token(&auth_token, pool);
     }
     // DELETE /moderators/:public_key
-    if path.starts_with("moderators") {
+    if resource == "moderators" {
         reject_if_file_server_mode(path)?;
         let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
         if components.len() != 2 {
@@ -390,7 +1135,8 @@ async fn handle_delete_request(
             return Err(warp::reject::custom(Error::InvalidRpcCall));
         }
         let public_key = components[1].to_string();
-        let room_id = match get_room_id(&rpc_call) {
+        reject_if_invalid_public_key(&public_key)?;
+        let room_id = match room_id {
             Some(room_id) => room_id,
             None => {
                 warn!("Missing room ID.");
@@ -402,20 +1148,229 @@ async fn handle_delete_request(
     }
     // Unrecognized endpoint
     warn!("Ignoring RPC call with invalid or unused endpoint: {}.", path);
-    return Err(warp::reject::custom(Error::InvalidRpcCall));
+    return Err(warp::reject::custom(Error::NoSuchEndpoint));
+}
+
+async fn handle_put_request(
+    room_id: Option<String>, rpc_call: RpcCall, path: &str, auth_token: Option<String>,
+    query_params: HashMap<String, String>, pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // PUT /files/:upload_id/chunk
+    // This route requires auth in open group server mode, but not in file server mode
+    if path.starts_with("files/") {
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 3 || components[2] != "chunk" {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let offset: u64 = match query_params.get("offset").map(|offset| offset.parse()) {
+            Some(Ok(offset)) => offset,
+            _ => return Err(warp::reject::custom(Error::InvalidQuery)),
+        };
+        #[derive(Debug, Deserialize)]
+        struct JSON {
+            chunk: String,
+        }
+        let json: JSON = match serde_json::from_str(&rpc_call.body) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+        };
+        return handlers::store_upload_chunk(
+            room_id,
+            components[1],
+            offset,
+            &json.chunk,
+            auth_token,
+            pool,
+        )
+        .await;
+    }
+    // Check that the auth token is present
+    let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
+    // PUT /messages/:server_id
+    if path.starts_with("messages") {
+        reject_if_file_server_mode(path)?;
+        let components: Vec<&str> = path.split('/').collect(); // Split on subsequent slashes
+        if components.len() != 2 {
+            warn!("Invalid endpoint: {}.", path);
+            return Err(warp::reject::custom(Error::InvalidRpcCall));
+        }
+        let server_id: i64 = match components[1].parse() {
+            Ok(server_id) => server_id,
+            Err(_) => {
+                warn!("Invalid endpoint: {}.", path);
+                return Err(warp::reject::custom(Error::InvalidRpcCall));
+            }
+        };
+        let message = match serde_json::from_str(&rpc_call.body) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Couldn't parse message from: {} due to error: {}.", rpc_call.body, e);
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+        };
+        let room_id = get_room_id_or_reject(&room_id)?;
+        return handlers::update_message(room_id, server_id, message, &auth_token, pool);
+    }
+    // Unrecognized endpoint
+    warn!("Ignoring RPC call with invalid or unused endpoint: {}.", path);
+    return Err(warp::reject::custom(Error::NoSuchEndpoint));
+}
+
+async fn handle_patch_request(
+    room_id: Option<String>, rpc_call: RpcCall, path: &str, auth_token: Option<String>,
+    pool: &storage::DatabaseConnectionPool,
+) -> Result<Response, Rejection> {
+    // Check that the auth token is present
+    let auth_token = auth_token.ok_or_else(|| warp::reject::custom(Error::NoAuthToken))?;
+    // PATCH /room_info
+    if path == "room_info" {
+        reject_if_file_server_mode(path)?;
+        let update = match serde_json::from_str(&rpc_call.body) {
+            Ok(update) => update,
+            Err(e) => {
+                warn!("Couldn't parse room info from: {} due to error: {}.", rpc_call.body, e);
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+        };
+        let room_id = get_room_id_or_reject(&room_id)?;
+        return handlers::update_room_info(room_id, update, &auth_token, pool);
+    }
+    // PATCH /block_list/:public_key
+    if let Some(public_key) = path.strip_prefix("block_list/") {
+        reject_if_file_server_mode(path)?;
+        reject_if_allow_list_mode(path)?;
+        reject_if_invalid_public_key(public_key)?;
+        #[derive(Debug, Deserialize)]
+        struct JSON {
+            reason: Option<String>,
+        }
+        let json: JSON = match serde_json::from_str(&rpc_call.body) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Couldn't parse JSON from: {} due to error: {}.", rpc_call.body, e);
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+        };
+        return handlers::update_ban_reason(public_key, json.reason, &auth_token, pool);
+    }
+    // Unrecognized endpoint
+    warn!("Ignoring RPC call with invalid or unused endpoint: {}.", path);
+    return Err(warp::reject::custom(Error::NoSuchEndpoint));
 }
 
 // Utilities
 
-fn get_pool_for_room(rpc_call: &RpcCall) -> Result<storage::DatabaseConnectionPool, Rejection> {
-    let room_id = match get_room_id(&rpc_call) {
-        Some(room_id) => room_id,
+fn get_room_id_or_reject(room_id: &Option<String>) -> Result<&str, Rejection> {
+    match room_id {
+        Some(room_id) => return Ok(room_id),
         None => {
             warn!("Missing room ID.");
             return Err(warp::reject::custom(Error::InvalidRpcCall));
         }
+    }
+}
+
+fn get_pool_for_room(
+    room_id: &Option<String>,
+) -> Result<storage::DatabaseConnectionPool, Rejection> {
+    let room_id = get_room_id_or_reject(room_id)?;
+    // Don't silently create a database for a room that doesn't exist
+    if !storage::room_exists(room_id)? {
+        warn!("Ignoring RPC call for nonexistent room: {}.", room_id);
+        return Err(warp::reject::custom(Error::NoSuchRoom));
+    }
+    return Ok(storage::pool_by_room_id(room_id));
+}
+
+/// Like `get_pool_for_room`, but returns the pool to use for reads that can tolerate replication lag.
+fn get_read_pool_for_room(
+    room_id: &Option<String>,
+) -> Result<storage::DatabaseConnectionPool, Rejection> {
+    // This also checks that the room exists
+    get_pool_for_room(room_id)?;
+    return Ok(storage::read_pool_by_room_id(get_room_id_or_reject(room_id)?));
+}
+
+/// Returns the query parameters of the given URL. These are normally URL-encoded (e.g.
+/// `?limit=10&from_server_id=5`), but clients can also pass a JSON object as the whole query
+/// string (e.g. `?{"limit":10,"from_server_id":5}`), whose values are then used as if they were
+/// URL-encoded. JSON that isn't an object, or that has values other than strings, numbers and
+/// booleans, is rejected. Null values are ignored.
+pub fn parse_query_params(url: &url::Url) -> Result<HashMap<String, String>, Rejection> {
+    let query = match url.query() {
+        Some(query) => percent_decode_str(query).decode_utf8_lossy(),
+        None => return Ok(HashMap::new()),
+    };
+    let query = query.trim();
+    if !query.starts_with('{') && !query.starts_with('[') {
+        return Ok(url.query_pairs().into_owned().collect());
+    }
+    let object = match serde_json::from_str(query) {
+        Ok(serde_json::Value::Object(object)) => object,
+        Ok(_) => {
+            warn!("Ignoring RPC call with JSON query that isn't an object: {}.", query);
+            return Err(warp::reject::custom(Error::InvalidQuery));
+        }
+        Err(e) => {
+            warn!("Couldn't parse JSON query from: {} due to error: {}.", query, e);
+            return Err(warp::reject::custom(Error::InvalidQuery));
+        }
     };
-    return Ok(storage::pool_by_room_id(&room_id));
+    let mut query_params = HashMap::with_capacity(object.len());
+    for (key, value) in object {
+        let value = match value {
+            serde_json::Value::String(value) => value,
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => value.to_string(),
+            serde_json::Value::Null => continue,
+            _ => {
+                warn!("Ignoring RPC call with invalid JSON query parameter: {}.", key);
+                return Err(warp::reject::custom(Error::InvalidQuery));
+            }
+        };
+        query_params.insert(key, value);
+    }
+    return Ok(query_params);
+}
+
+/// Splits e.g. `rooms/:room_id/messages` into `:room_id` and `messages`.
+///
+/// `rooms/:room_id/image` is left alone because it's an endpoint in its own right.
+fn split_room_prefix(path: &str) -> Option<(String, String)> {
+    match MODE {
+        Mode::FileServer => return None,
+        Mode::OpenGroupServer => (),
+    }
+    let components: Vec<&str> = path.splitn(3, '/').collect();
+    if components.len() != 3 || components[0] != "rooms" || components[2] == "image" {
+        return None;
+    }
+    return Some((components[1].to_string(), components[2].to_string()));
+}
+
+fn get_endpoint_label(endpoint: &str) -> String {
+    let path = endpoint.split('?').next().unwrap_or("").trim_start_matches('/');
+    return match split_room_prefix(path) {
+        Some((_, path)) => metrics::get_endpoint_label(&path),
+        None => metrics::get_endpoint_label(path),
+    };
+}
+
+/// Returns the client-supplied request ID if there is a valid one, or a newly generated one otherwise.
+fn get_request_id(rpc_call: &RpcCall) -> String {
+    if let Some(request_id) = rpc_call.headers.get("X-Request-ID") {
+        // Only accept request IDs that can't mess up the logs
+        let is_valid = !request_id.is_empty()
+            && request_id.len() <= MAX_REQUEST_ID_LENGTH
+            && request_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if is_valid {
+            return request_id.to_string();
+        }
+    }
+    return uuid::Uuid::new_v4().to_string();
 }
 
 fn get_auth_token(rpc_call: &RpcCall) -> Option<String> {
@@ -425,6 +1380,221 @@ fn get_auth_token(rpc_call: &RpcCall) -> Option<String> {
     return rpc_call.headers.get("Authorization").map(|s| s.to_string());
 }
 
+/// Returns whether the `Accept-Encoding` header of the given call accepts gzip.
+pub fn accepts_gzip(rpc_call: &RpcCall) -> bool {
+    return match rpc_call.headers.get("Accept-Encoding") {
+        Some(encodings) => encodings.split(',').any(|encoding| {
+            let mut parameters = encoding.split(';');
+            let name = parameters.next().unwrap_or("").trim();
+            // A quality of 0 means that the encoding isn't acceptable
+            let is_refused = parameters.any(|parameter| {
+                let quality = parameter.trim().strip_prefix("q=");
+                return quality.and_then(|quality| quality.parse::<f32>().ok()) == Some(0.0);
+            });
+            return name == "gzip" && !is_refused;
+        }),
+        None => false,
+    };
+}
+
+/// Gzip compresses the body of the given response, unless it's so small that compressing it
+/// wouldn't be worth it or it's already encoded.
+async fn compress_response(response: Response) -> Result<Response, Rejection> {
+    if response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Couldn't read response body due to error: {}.", e);
+            return Err(warp::reject::custom(Error::InvalidBody));
+        }
+    };
+    if bytes.len() < MIN_COMPRESSED_BODY_SIZE {
+        return Ok(Response::from_parts(parts, bytes.into()));
+    }
+    let compressed_bytes = match gzip(&bytes) {
+        Ok(compressed_bytes) => compressed_bytes,
+        Err(e) => {
+            error!("Couldn't compress response body due to error: {}.", e);
+            return Ok(Response::from_parts(parts, bytes.into()));
+        }
+    };
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    return Ok(Response::from_parts(parts, compressed_bytes.into()));
+}
+
+pub fn gzip(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    return encoder.finish();
+}
+
+/// Returns the number of seconds clients are currently recommended to wait between polls, or 0 if
+/// the server doesn't recommend anything.
+fn get_current_poll_interval() -> u64 {
+    let poll_interval = super::POLL_INTERVAL.load(Ordering::SeqCst);
+    let load_threshold = super::POLL_INTERVAL_LOAD_THRESHOLD.load(Ordering::SeqCst);
+    let requests_per_minute = rate_limiting::REQUEST_COUNTER.requests_per_minute();
+    return get_poll_interval(poll_interval, load_threshold, requests_per_minute);
+}
+
+/// Returns `poll_interval`, increased in proportion to how far `requests_per_minute` exceeds
+/// `load_threshold` (if that isn't 0), so that clients that follow it spread out the load.
+pub fn get_poll_interval(poll_interval: u64, load_threshold: u64, requests_per_minute: u64) -> u64 {
+    if load_threshold == 0 || requests_per_minute <= load_threshold {
+        return poll_interval;
+    }
+    // Round up, so that any load above the threshold increases the interval
+    let scaled = poll_interval.saturating_mul(requests_per_minute).div_ceil(load_threshold);
+    return scaled.min(poll_interval.saturating_mul(MAX_POLL_INTERVAL_FACTOR));
+}
+
+/// Adds `poll_interval` to the JSON object in the body of `response`, unless it's 0.
+pub async fn add_poll_interval(
+    response: Response, poll_interval: u64,
+) -> Result<Response, Rejection> {
+    if poll_interval == 0 {
+        return Ok(response);
+    }
+    return map_json_body(response, |json| {
+        if let Some(object) = json.as_object_mut() {
+            object.insert("poll_interval".to_string(), poll_interval.into());
+        }
+    })
+    .await;
+}
+
+/// Adds an ISO 8601 version of every `timestamp` in the body of `response` (e.g. those of
+/// messages, wherever they are in the body) as `timestamp_iso`, next to the original.
+pub async fn add_iso_timestamps(response: Response) -> Result<Response, Rejection> {
+    return map_json_body(response, add_iso_timestamps_to_json).await;
+}
+
+fn add_iso_timestamps_to_json(json: &mut serde_json::Value) {
+    match json {
+        serde_json::Value::Array(values) => values.iter_mut().for_each(add_iso_timestamps_to_json),
+        serde_json::Value::Object(object) => {
+            object.values_mut().for_each(add_iso_timestamps_to_json);
+            let timestamp = object.get("timestamp").and_then(|timestamp| timestamp.as_i64());
+            if let Some(timestamp_iso) = timestamp.and_then(models::format_timestamp) {
+                object.insert("timestamp_iso".to_string(), timestamp_iso.into());
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Applies `transform` to the JSON in the body of `response` if it was successful. Responses
+/// without JSON in their body (e.g. files or 304s) are returned as is.
+async fn map_json_body<F: FnOnce(&mut serde_json::Value)>(
+    response: Response, transform: F,
+) -> Result<Response, Rejection> {
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = read_body(&mut parts.headers, body).await?;
+    let mut json: serde_json::Value = match serde_json::from_slice(&bytes) {
+        Ok(json) => json,
+        Err(_) => return Ok(Response::from_parts(parts, bytes.into())),
+    };
+    transform(&mut json);
+    // Safe because the value was just deserialized
+    let bytes = serde_json::to_vec(&json).unwrap();
+    return Ok(Response::from_parts(parts, bytes.into()));
+}
+
+/// Reads the given response body, decompressing it if `headers` say it's gzip compressed. The
+/// `Content-Encoding` header is removed in that case, so that `headers` match the returned body.
+async fn read_body(headers: &mut HeaderMap, body: warp::hyper::Body) -> Result<Vec<u8>, Rejection> {
+    let bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Couldn't read response body due to error: {}.", e);
+            return Err(warp::reject::custom(Error::InvalidBody));
+        }
+    };
+    match headers.get(CONTENT_ENCODING).map(|encoding| encoding.as_bytes()) {
+        Some(b"gzip") => {
+            let mut decompressed_bytes: Vec<u8> = vec![];
+            if let Err(e) = GzDecoder::new(&bytes[..]).read_to_end(&mut decompressed_bytes) {
+                error!("Couldn't decompress response body due to error: {}.", e);
+                return Err(warp::reject::custom(Error::InvalidBody));
+            }
+            headers.remove(CONTENT_ENCODING);
+            return Ok(decompressed_bytes);
+        }
+        Some(_) => {
+            error!("Couldn't read response body with unsupported encoding.");
+            return Err(warp::reject::custom(Error::InvalidBody));
+        }
+        None => return Ok(bytes.to_vec()),
+    }
+}
+
+/// Performs the RPC calls in the given batch one after the other. A call that fails doesn't affect
+/// the others; its error response is included in the results like any other response.
+async fn handle_batch_request(body: &str) -> Result<Response, Rejection> {
+    #[derive(Debug, Deserialize)]
+    struct JSON {
+        requests: Vec<RpcCall>,
+    }
+    let json: JSON = match serde_json::from_str(body) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("Couldn't parse batch request body from: {} due to error: {}.", body, e);
+            return Err(warp::reject::custom(Error::InvalidBody));
+        }
+    };
+    if json.requests.len() > MAX_BATCH_SIZE {
+        warn!("Ignoring batch request with {} calls.", json.requests.len());
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    #[derive(Debug, Deserialize, Serialize)]
+    struct BatchResult {
+        status_code: u16,
+        body: serde_json::Value,
+    }
+    let mut results: Vec<BatchResult> = vec![];
+    for mut rpc_call in json.requests {
+        let result = if get_endpoint_label(&rpc_call.endpoint) == "batch" {
+            warn!("Ignoring nested batch request.");
+            Err(warp::reject::custom(Error::InvalidRpcCall))
+        } else {
+            // Log batched calls under the request ID of the batch unless they have their own
+            if let Some(request_id) = logging::current_request_id() {
+                rpc_call.headers.entry("X-Request-ID".to_string()).or_insert(request_id);
+            }
+            handle_batched_rpc_call(rpc_call).await
+        };
+        let response = result.or_else(errors::into_response)?;
+        let (mut parts, body) = response.into_parts();
+        let status_code = parts.status.as_u16();
+        // The results are returned in a single body, so calls that asked for a compressed response
+        // get an uncompressed one
+        let bytes = read_body(&mut parts.headers, body).await?;
+        // Some endpoints respond with an empty body
+        let body = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+        results.push(BatchResult { status_code, body });
+    }
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Response {
+        status_code: u16,
+        results: Vec<BatchResult>,
+    }
+    let response = Response { status_code: StatusCode::OK.as_u16(), results };
+    return Ok(warp::reply::json(&response).into_response());
+}
+
+/// `handle_rpc_call` indirectly calls itself for batched calls, so its future needs to be boxed.
+fn handle_batched_rpc_call(
+    rpc_call: RpcCall,
+) -> Pin<Box<dyn Future<Output = Result<Response, Rejection>> + Send>> {
+    return Box::pin(handle_rpc_call(rpc_call));
+}
+
 fn get_room_id(rpc_call: &RpcCall) -> Option<String> {
     match MODE {
         // In file server mode we don't have a concept of rooms, but for convenience (i.e. so
@@ -439,11 +1609,38 @@ fn get_room_id(rpc_call: &RpcCall) -> Option<String> {
     }
 }
 
+/// Returns whether a call with the given method to the given path (without a `rooms/:room_id/`
+/// prefix) can change what's stored on the server.
+pub fn is_write(method: &str, path: &str) -> bool {
+    match method {
+        "POST" => return !READ_ONLY_POST_ENDPOINTS.contains(&path),
+        "DELETE" | "PUT" | "PATCH" => return true,
+        _ => return false,
+    }
+}
+
+fn reject_if_invalid_public_key(public_key: &str) -> Result<(), Rejection> {
+    if !crypto::is_valid_public_key(public_key) {
+        warn!("Ignoring RPC call with invalid public key: {}.", public_key);
+        return Err(warp::reject::custom(Error::ValidationFailed));
+    }
+    return Ok(());
+}
+
+/// Rejects calls to block list endpoints in allow list mode, in which the block list isn't used.
+fn reject_if_allow_list_mode(path: &str) -> Result<(), Rejection> {
+    if super::ALLOW_LIST_MODE.load(Ordering::SeqCst) {
+        warn!("Ignoring RPC call with invalid or unused endpoint: {}.", path);
+        return Err(warp::reject::custom(Error::NoSuchEndpoint));
+    }
+    return Ok(());
+}
+
 fn reject_if_file_server_mode(path: &str) -> Result<(), Rejection> {
     match MODE {
         Mode::FileServer => {
             warn!("Ignoring RPC call with invalid or unused endpoint: {}.", path);
-            return Err(warp::reject::custom(Error::InvalidRpcCall));
+            return Err(warp::reject::custom(Error::NoSuchEndpoint));
         }
         Mode::OpenGroupServer => return Ok(()),
     }
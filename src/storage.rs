@@ -1,17 +1,115 @@
 use std::collections::HashMap;
 use std::fs;
+use std::ops::Deref;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use log::{error, info};
+use log::{error, info, warn};
+use parking_lot::RwLock;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::params;
+use rusqlite::{params, OpenFlags};
 use rusqlite_migration::{Migrations, M};
 
 use super::errors::Error;
 
-pub type DatabaseConnection = r2d2::PooledConnection<SqliteConnectionManager>;
-pub type DatabaseConnectionPool = r2d2::Pool<SqliteConnectionManager>;
+pub type DatabaseConnection = r2d2::PooledConnection<ConnectionManager>;
+
+/// A connection pool along with an in-memory copy of the database's block list, so that checking
+/// whether a user is banned (which happens for nearly every request) doesn't need a query. Clones
+/// share the same connections and copy.
+#[derive(Clone)]
+pub struct DatabaseConnectionPool {
+    pool: r2d2::Pool<ConnectionManager>,
+    block_list: Arc<RwLock<BlockListCache>>,
+}
+
+#[derive(Default)]
+struct BlockListCache {
+    /// Public keys mapped to when their ban expires (if ever). `None` if the copy needs to be
+    /// (re)loaded from the database.
+    bans: Option<HashMap<String, Option<i64>>>,
+    /// Incremented whenever the copy is invalidated, so that a copy loaded from the database
+    /// before the block list changed isn't stored afterwards.
+    generation: u64,
+}
+
+impl DatabaseConnectionPool {
+    pub fn new(pool: r2d2::Pool<ConnectionManager>) -> DatabaseConnectionPool {
+        let block_list = Arc::new(RwLock::new(BlockListCache::default()));
+        return DatabaseConnectionPool { pool, block_list };
+    }
+
+    /// Returns whether `public_key` is banned at `now` according to the copy of the block list, or
+    /// the current generation as an error if the copy needs to be loaded.
+    pub fn get_cached_ban_status(&self, public_key: &str, now: i64) -> Result<bool, u64> {
+        let block_list = self.block_list.read();
+        match &block_list.bans {
+            Some(bans) => match bans.get(public_key) {
+                Some(banned_until) => return Ok(banned_until.is_none_or(|t| t > now)),
+                None => return Ok(false),
+            },
+            None => return Err(block_list.generation),
+        }
+    }
+
+    /// Stores a copy of the block list that was loaded from the database, unless it was
+    /// invalidated since `generation` was returned by `get_cached_ban_status`.
+    pub fn cache_block_list(&self, bans: HashMap<String, Option<i64>>, generation: u64) {
+        let mut block_list = self.block_list.write();
+        if block_list.generation == generation {
+            block_list.bans = Some(bans);
+        }
+    }
+
+    /// Should be called after every change to the block list.
+    pub fn invalidate_block_list(&self) {
+        let mut block_list = self.block_list.write();
+        block_list.bans = None;
+        block_list.generation += 1;
+    }
+}
+
+impl Deref for DatabaseConnectionPool {
+    type Target = r2d2::Pool<ConnectionManager>;
+
+    fn deref(&self) -> &r2d2::Pool<ConnectionManager> {
+        return &self.pool;
+    }
+}
+
+/// Wraps `SqliteConnectionManager` so that connections that stopped working (e.g. because the
+/// database file became unavailable) are replaced instead of being handed out again.
+#[derive(Debug)]
+pub struct ConnectionManager(SqliteConnectionManager);
+
+impl ConnectionManager {
+    pub fn new(db_manager: SqliteConnectionManager) -> ConnectionManager {
+        return ConnectionManager(db_manager);
+    }
+}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = rusqlite::Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<rusqlite::Connection, rusqlite::Error> {
+        return self.0.connect();
+    }
+
+    /// Called whenever a connection is checked out. Unlike `SqliteConnectionManager`, this
+    /// actually reads from the database, so that connections that can't are discarded.
+    fn is_valid(&self, conn: &mut rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        return conn.query_row("PRAGMA schema_version", params![], |_| Ok(()));
+    }
+
+    /// Connections that are returned while still in a transaction (e.g. because committing or
+    /// rolling back failed) are discarded, so that the transaction doesn't leak into other requests.
+    fn has_broken(&self, conn: &mut rusqlite::Connection) -> bool {
+        return !conn.is_autocommit();
+    }
+}
 
 // Main
 
@@ -21,15 +119,130 @@ lazy_static::lazy_static! {
 
     pub static ref MAIN_POOL: DatabaseConnectionPool = {
         let file_name = "database.db";
-        let db_manager = r2d2_sqlite::SqliteConnectionManager::file(file_name);
-        return r2d2::Pool::new(db_manager).unwrap();
+        let db_manager = SqliteConnectionManager::file(file_name);
+        return create_pool(db_manager);
     };
 }
 
-pub fn create_main_database_if_needed() {
+/// Creates a connection pool with the configured size and connection timeout. Requests that can't
+/// get a connection within the timeout are rejected rather than waiting indefinitely.
+fn create_pool(db_manager: SqliteConnectionManager) -> DatabaseConnectionPool {
+    let max_size = super::DB_POOL_SIZE.load(Ordering::SeqCst);
+    let timeout = Duration::from_secs(super::DB_CONNECTION_TIMEOUT.load(Ordering::SeqCst));
+    // Don't block on opening connections here; a database that can't be opened is reported
+    // when a connection is requested instead
+    let pool = r2d2::Pool::builder()
+        .max_size(max_size)
+        .connection_timeout(timeout)
+        .build_unchecked(ConnectionManager::new(db_manager));
+    return DatabaseConnectionPool::new(pool);
+}
+
+/// Returns whether `e` might not occur again if the query is retried, e.g. because the database
+/// was locked by another connection or its file was briefly unavailable. Errors caused by the query
+/// itself or by the contents of the database are permanent.
+pub fn is_transient_error(e: &rusqlite::Error) -> bool {
+    match e {
+        rusqlite::Error::SqliteFailure(e, _) => match e.code {
+            rusqlite::ErrorCode::DatabaseBusy
+            | rusqlite::ErrorCode::DatabaseLocked
+            | rusqlite::ErrorCode::SystemIOFailure
+            | rusqlite::ErrorCode::CannotOpen
+            | rusqlite::ErrorCode::FileLockingProtocolFailed
+            | rusqlite::ErrorCode::SchemaChanged => return true,
+            _ => return false,
+        },
+        _ => return false,
+    }
+}
+
+/// Runs `query` on a connection from `pool`. If it fails with a transient error, it's retried once
+/// on a freshly checked out connection, which replaces the previous connection if that one stopped
+/// working. Permanent errors aren't retried. This is only used for the auth token and ban checks;
+/// other queries rely on SQLite's busy timeout alone.
+pub fn query_with_retry<T, F>(pool: &DatabaseConnectionPool, mut query: F) -> Result<T, Error>
+where
+    F: FnMut(&rusqlite::Connection) -> rusqlite::Result<T>,
+{
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    match query(&conn) {
+        Ok(result) => return Ok(result),
+        Err(e) if is_transient_error(&e) => warn!("Retrying query after error: {}.", e),
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(Error::DatabaseFailedInternally);
+        }
+    }
+    // Return the connection to the pool first, so that it can be checked again
+    drop(conn);
+    let conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    match query(&conn) {
+        Ok(result) => return Ok(result),
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            if is_transient_error(&e) {
+                return Err(Error::DatabaseUnavailable);
+            }
+            return Err(Error::DatabaseFailedInternally);
+        }
+    }
+}
+
+// Retries of opening the main database at startup are spaced out exponentially up to this
+const MAX_DB_STARTUP_RETRY_DELAY: u64 = 60;
+
+pub fn create_main_database_if_needed() -> Result<(), r2d2::Error> {
     let pool = &MAIN_POOL;
-    let conn = pool.get().unwrap();
+    let conn = pool.get()?;
     create_main_tables_if_needed(&conn);
+    return Ok(());
+}
+
+/// Like `create_main_database_if_needed`, but retries up to `max_retries` times with exponential
+/// backoff if the database can't be opened, e.g. because the volume it's on isn't mounted yet.
+pub async fn create_main_database_with_retries(max_retries: u32) -> Result<(), Error> {
+    let open = || {
+        return create_main_database_if_needed().map_err(|e| {
+            error!("Couldn't open main database due to error: {}.", e);
+            return Error::DatabaseUnavailable;
+        });
+    };
+    return retry_at_startup("main database", max_retries, open).await;
+}
+
+/// Runs `operation` and retries it up to `max_retries` times with exponential backoff for as long
+/// as it fails with `Error::DatabaseUnavailable`. Any other error is returned right away.
+pub async fn retry_at_startup<F>(
+    description: &str, max_retries: u32, mut operation: F,
+) -> Result<(), Error>
+where
+    F: FnMut() -> Result<(), Error>,
+{
+    let mut attempt: u32 = 0;
+    loop {
+        match operation() {
+            Ok(()) => return Ok(()),
+            Err(Error::DatabaseUnavailable) if attempt < max_retries => {
+                attempt += 1;
+                let delay = get_db_startup_retry_delay(attempt);
+                warn!(
+                    "Couldn't open {}. Retrying in {} seconds (attempt {} of {}).",
+                    description,
+                    delay.as_secs(),
+                    attempt,
+                    max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns how long to wait before the given retry (starting at 1) of opening the database.
+pub fn get_db_startup_retry_delay(attempt: u32) -> Duration {
+    let seconds = 2u64.saturating_pow(attempt.saturating_sub(1)).min(MAX_DB_STARTUP_RETRY_DELAY);
+    return Duration::from_secs(seconds);
 }
 
 fn create_main_tables_if_needed(conn: &DatabaseConnection) {
@@ -49,19 +262,39 @@ fn create_main_tables_if_needed(conn: &DatabaseConnection) {
 pub const PENDING_TOKEN_EXPIRATION: i64 = 10 * 60;
 pub const TOKEN_EXPIRATION: i64 = 7 * 24 * 60 * 60;
 pub const FILE_EXPIRATION: i64 = 15 * 24 * 60 * 60;
+pub const IDEMPOTENCY_KEY_EXPIRATION: i64 = 24 * 60 * 60;
+
+// Expired messages are deleted in batches of this size, so that no single transaction holds the
+// database lock for long
+const MESSAGE_EXPIRATION_BATCH_SIZE: i64 = 500;
 
 pub const MESSAGES_TABLE: &str = "messages";
 pub const DELETED_MESSAGES_TABLE: &str = "deleted_messages";
 pub const MODERATORS_TABLE: &str = "moderators";
 pub const BLOCK_LIST_TABLE: &str = "block_list";
+pub const MUTE_LIST_TABLE: &str = "mute_list";
 pub const PENDING_TOKENS_TABLE: &str = "pending_tokens";
 pub const TOKENS_TABLE: &str = "tokens";
 pub const FILES_TABLE: &str = "files";
 pub const USER_ACTIVITY_TABLE: &str = "user_activity";
+pub const REACTIONS_TABLE: &str = "reactions";
+pub const REPORTS_TABLE: &str = "reports";
+pub const ROOM_INFO_TABLE: &str = "room_info";
+pub const PINNED_MESSAGES_TABLE: &str = "pinned_messages";
+pub const IDEMPOTENCY_KEYS_TABLE: &str = "idempotency_keys";
+pub const INVITES_TABLE: &str = "invites";
+pub const ALLOW_LIST_TABLE: &str = "allow_list";
+pub const PROFILES_TABLE: &str = "profiles";
+pub const READ_RECEIPTS_TABLE: &str = "read_receipts";
+pub const COOLDOWNS_TABLE: &str = "cooldowns";
+pub const UPLOADS_TABLE: &str = "uploads";
+pub const UPLOAD_CHUNKS_TABLE: &str = "upload_chunks";
 
 lazy_static::lazy_static! {
 
     static ref POOLS: Mutex<HashMap<String, DatabaseConnectionPool>> = Mutex::new(HashMap::new());
+    static ref READ_REPLICA_POOLS: Mutex<HashMap<String, DatabaseConnectionPool>> =
+        Mutex::new(HashMap::new());
 }
 
 pub fn pool_by_room_id(room_id: &str) -> DatabaseConnectionPool {
@@ -71,20 +304,73 @@ pub fn pool_by_room_id(room_id: &str) -> DatabaseConnectionPool {
     } else {
         let raw_path = format!("rooms/{}.db", room_id);
         let path = Path::new(&raw_path);
-        let db_manager = r2d2_sqlite::SqliteConnectionManager::file(path);
-        let pool = r2d2::Pool::new(db_manager).unwrap();
+        let db_manager = SqliteConnectionManager::file(path);
+        let pool = create_pool(db_manager);
         pools.insert(room_id.to_string(), pool);
         return pools[room_id].clone();
     }
 }
 
-pub fn create_database_if_needed(room_id: &str) {
+/// Returns the connection pool to use for reads from the given room that can tolerate a bit of
+/// replication lag. If a read replica directory is configured, connections are opened read-only
+/// to the room's copy in there; otherwise (or if the room hasn't been replicated yet) this is the
+/// same as `pool_by_room_id`.
+pub fn read_pool_by_room_id(room_id: &str) -> DatabaseConnectionPool {
+    let read_replica_dir = match super::READ_REPLICA_DIR.read().clone() {
+        Some(read_replica_dir) => read_replica_dir,
+        None => return pool_by_room_id(room_id),
+    };
+    let mut pools = READ_REPLICA_POOLS.lock().unwrap();
+    if let Some(pool) = pools.get(room_id) {
+        return pool.clone();
+    }
+    let path = Path::new(&read_replica_dir).join(format!("{}.db", room_id));
+    if !path.exists() {
+        return pool_by_room_id(room_id);
+    }
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let db_manager = SqliteConnectionManager::file(path).with_flags(flags);
+    let pool = create_pool(db_manager);
+    pools.insert(room_id.to_string(), pool);
+    return pools[room_id].clone();
+}
+
+/// Drops the connection pools of all rooms, closing their connections once they're no longer in use.
+pub fn close_room_pools() {
+    POOLS.lock().unwrap().clear();
+    READ_REPLICA_POOLS.lock().unwrap().clear();
+}
+
+pub fn create_database_if_needed(room_id: &str) -> Result<(), Error> {
     let pool = pool_by_room_id(room_id);
-    let conn = pool.get().unwrap();
-    create_room_tables_if_needed(&conn);
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Couldn't open database for room: {} due to error: {}.", room_id, e);
+            return Err(Error::DatabaseUnavailable);
+        }
+    };
+    if let Err(e) = create_room_tables_if_needed(&conn) {
+        error!("Couldn't create tables for room: {} due to error: {}.", room_id, e);
+        return Err(Error::DatabaseFailedInternally);
+    }
+    // Pending migrations are applied in a single transaction, so a failed migration leaves the
+    // database as it was
+    if let Err(e) = migrate(&mut conn) {
+        error!("Couldn't migrate database for room: {} due to error: {}.", room_id, e);
+        return Err(Error::DatabaseFailedInternally);
+    }
+    // Messages sent before the search text column was added don't have search text yet
+    if let Err(e) = fill_in_search_text(&mut conn) {
+        error!("Couldn't fill in search text for room: {} due to error: {}.", room_id, e);
+        return Err(Error::DatabaseFailedInternally);
+    }
+    return Ok(());
 }
 
-fn create_room_tables_if_needed(conn: &DatabaseConnection) {
+fn create_room_tables_if_needed(conn: &DatabaseConnection) -> rusqlite::Result<()> {
     // Messages
     // The `id` field is needed to make `rowid` stable, which is important because otherwise
     // the `id`s in this table won't correspond to those in the deleted messages table
@@ -99,8 +385,10 @@ fn create_room_tables_if_needed(conn: &DatabaseConnection) {
     )",
         MESSAGES_TABLE
     );
-    conn.execute(&messages_table_cmd, params![]).expect("Couldn't create messages table.");
+    conn.execute(&messages_table_cmd, params![])?;
     // Deleted messages
+    // The `timestamp` and `is_restored` columns are added through migrations; `timestamp` is null
+    // for messages deleted before messages could be restored
     let deleted_messages_table_cmd = format!(
         "CREATE TABLE IF NOT EXISTS {} (
         id INTEGER PRIMARY KEY,
@@ -108,8 +396,7 @@ fn create_room_tables_if_needed(conn: &DatabaseConnection) {
     )",
         DELETED_MESSAGES_TABLE
     );
-    conn.execute(&deleted_messages_table_cmd, params![])
-        .expect("Couldn't create deleted messages table.");
+    conn.execute(&deleted_messages_table_cmd, params![])?;
     // Moderators
     let moderators_table_cmd = format!(
         "CREATE TABLE IF NOT EXISTS {} (
@@ -117,15 +404,25 @@ fn create_room_tables_if_needed(conn: &DatabaseConnection) {
     )",
         MODERATORS_TABLE
     );
-    conn.execute(&moderators_table_cmd, params![]).expect("Couldn't create moderators table.");
+    conn.execute(&moderators_table_cmd, params![])?;
     // Block list
+    // The `banned_until` column is added through a migration; it's null for permanent bans
+    // The `reason` column is added through a migration as well
     let block_list_table_cmd = format!(
         "CREATE TABLE IF NOT EXISTS {} (
         public_key TEXT
     )",
         BLOCK_LIST_TABLE
     );
-    conn.execute(&block_list_table_cmd, params![]).expect("Couldn't create block list table.");
+    conn.execute(&block_list_table_cmd, params![])?;
+    // Mute list
+    let mute_list_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        public_key TEXT PRIMARY KEY
+    )",
+        MUTE_LIST_TABLE
+    );
+    conn.execute(&mute_list_table_cmd, params![])?;
     // Pending tokens
     // Note that a given public key can have multiple pending tokens
     let pending_tokens_table_cmd = format!(
@@ -136,8 +433,7 @@ fn create_room_tables_if_needed(conn: &DatabaseConnection) {
     )",
         PENDING_TOKENS_TABLE
     );
-    conn.execute(&pending_tokens_table_cmd, params![])
-        .expect("Couldn't create pending tokens table.");
+    conn.execute(&pending_tokens_table_cmd, params![])?;
     // Tokens
     // The token is stored as hex here (rather than as bytes) because it's more convenient for lookup
     let tokens_table_cmd = format!(
@@ -148,7 +444,7 @@ fn create_room_tables_if_needed(conn: &DatabaseConnection) {
     )",
         TOKENS_TABLE
     );
-    conn.execute(&tokens_table_cmd, params![]).expect("Couldn't create tokens table.");
+    conn.execute(&tokens_table_cmd, params![])?;
     // Files
     let files_table_cmd = format!(
         "CREATE TABLE IF NOT EXISTS {} (
@@ -157,7 +453,7 @@ fn create_room_tables_if_needed(conn: &DatabaseConnection) {
     )",
         FILES_TABLE
     );
-    conn.execute(&files_table_cmd, params![]).expect("Couldn't create files table.");
+    conn.execute(&files_table_cmd, params![])?;
     // User activity table
     let user_activity_table_cmd = format!(
         "CREATE TABLE IF NOT EXISTS {} (
@@ -166,8 +462,177 @@ fn create_room_tables_if_needed(conn: &DatabaseConnection) {
     )",
         USER_ACTIVITY_TABLE,
     );
-    conn.execute(&user_activity_table_cmd, params![])
-        .expect("Couldn't create user activity table.");
+    conn.execute(&user_activity_table_cmd, params![])?;
+    // Reactions
+    // The primary key ensures a given user can only react with a given emoji once per message
+    let reactions_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        message_id INTEGER NOT NULL,
+        public_key TEXT NOT NULL,
+        emoji TEXT NOT NULL,
+        PRIMARY KEY (message_id, public_key, emoji)
+    )",
+        REACTIONS_TABLE
+    );
+    conn.execute(&reactions_table_cmd, params![])?;
+    // Reports
+    // The unique constraint ensures a given user can only report a given message once
+    let reports_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        id INTEGER PRIMARY KEY,
+        message_id INTEGER NOT NULL,
+        reporter TEXT NOT NULL,
+        reason TEXT,
+        timestamp INTEGER NOT NULL,
+        UNIQUE (message_id, reporter)
+    )",
+        REPORTS_TABLE
+    );
+    conn.execute(&reports_table_cmd, params![])?;
+    // Room info
+    // The check constraint ensures there's only ever a single row
+    let room_info_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        name TEXT NOT NULL,
+        description TEXT NOT NULL,
+        image_url TEXT NOT NULL
+    )",
+        ROOM_INFO_TABLE
+    );
+    conn.execute(&room_info_table_cmd, params![])?;
+    // Pinned messages
+    // The `id` field keeps track of the order in which messages were pinned
+    let pinned_messages_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        id INTEGER PRIMARY KEY,
+        message_id INTEGER NOT NULL UNIQUE,
+        timestamp INTEGER NOT NULL
+    )",
+        PINNED_MESSAGES_TABLE
+    );
+    conn.execute(&pinned_messages_table_cmd, params![])?;
+    // Idempotency keys
+    // Keys are scoped per sender, so different users can't collide
+    let idempotency_keys_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        public_key TEXT NOT NULL,
+        key TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (public_key, key)
+    )",
+        IDEMPOTENCY_KEYS_TABLE
+    );
+    conn.execute(&idempotency_keys_table_cmd, params![])?;
+    // Invites
+    let invites_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        code TEXT PRIMARY KEY,
+        max_redemptions INTEGER,
+        redemption_count INTEGER NOT NULL DEFAULT 0,
+        expires_at INTEGER
+    )",
+        INVITES_TABLE
+    );
+    conn.execute(&invites_table_cmd, params![])?;
+    // Allow list
+    // Users on the allow list can post in the room if it's invite only
+    let allow_list_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        public_key TEXT PRIMARY KEY
+    )",
+        ALLOW_LIST_TABLE
+    );
+    conn.execute(&allow_list_table_cmd, params![])?;
+    // Profiles
+    let profiles_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        public_key TEXT PRIMARY KEY,
+        display_name TEXT NOT NULL,
+        avatar TEXT,
+        timestamp INTEGER NOT NULL
+    )",
+        PROFILES_TABLE
+    );
+    conn.execute(&profiles_table_cmd, params![])?;
+    // Read receipts
+    // The primary key ensures a given user can only mark a given message as read once
+    let read_receipts_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        message_id INTEGER NOT NULL,
+        public_key TEXT NOT NULL,
+        PRIMARY KEY (message_id, public_key)
+    )",
+        READ_RECEIPTS_TABLE
+    );
+    conn.execute(&read_receipts_table_cmd, params![])?;
+    // Cooldowns
+    let cooldowns_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        public_key TEXT PRIMARY KEY,
+        expires_at INTEGER NOT NULL
+    )",
+        COOLDOWNS_TABLE
+    );
+    conn.execute(&cooldowns_table_cmd, params![])?;
+    // Resumable uploads
+    // `public_key` is null in file server mode, where uploads don't require auth
+    let uploads_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        id TEXT PRIMARY KEY,
+        public_key TEXT,
+        size INTEGER NOT NULL,
+        metadata BLOB,
+        content_type TEXT,
+        updated_at INTEGER NOT NULL
+    )",
+        UPLOADS_TABLE
+    );
+    conn.execute(&uploads_table_cmd, params![])?;
+    // The byte ranges of resumable uploads that have been received, with exclusive ends. Ranges
+    // that overlap or touch are merged, so there are only ever a few per upload.
+    let upload_chunks_table_cmd = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+        upload_id TEXT NOT NULL,
+        start_offset INTEGER NOT NULL,
+        end_offset INTEGER NOT NULL
+    )",
+        UPLOAD_CHUNKS_TABLE
+    );
+    conn.execute(&upload_chunks_table_cmd, params![])?;
+    let upload_chunks_index_cmd =
+        format!("CREATE INDEX IF NOT EXISTS {0}_upload_id ON {0} (upload_id)", UPLOAD_CHUNKS_TABLE);
+    conn.execute(&upload_chunks_index_cmd, params![])?;
+    return Ok(());
+}
+
+/// Returns the text that the message with the given base64 encoded `data` is searched by. Text
+/// fields are stored verbatim in the protobuf, so it's enough to look at the decoded bytes. The
+/// text is lowercased so that searching can ignore case.
+pub fn get_search_text(data: &str) -> String {
+    return match base64::decode(data) {
+        Ok(data) => String::from_utf8_lossy(&data).to_lowercase(),
+        Err(_) => String::new(),
+    };
+}
+
+fn fill_in_search_text(conn: &mut DatabaseConnection) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    let raw_query = format!(
+        "SELECT id, data FROM {} WHERE search_text IS NULL AND data != 'deleted'",
+        MESSAGES_TABLE
+    );
+    let rows: Vec<(i64, String)> = {
+        let mut query = tx.prepare(&raw_query)?;
+        let rows = query.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    let stmt = format!("UPDATE {} SET search_text = (?1) WHERE id = (?2)", MESSAGES_TABLE);
+    for (id, data) in rows {
+        tx.execute(&stmt, params![get_search_text(&data), id])?;
+    }
+    return tx.commit();
 }
 
 // Pruning
@@ -202,6 +667,133 @@ pub async fn prune_files_periodically() {
     }
 }
 
+pub async fn prune_expired_bans_periodically() {
+    let mut timer = tokio::time::interval(chrono::Duration::minutes(10).to_std().unwrap());
+    loop {
+        timer.tick().await;
+        tokio::spawn(async {
+            prune_expired_bans().await;
+        });
+    }
+}
+
+pub async fn prune_expired_cooldowns_periodically() {
+    let mut timer = tokio::time::interval(chrono::Duration::minutes(10).to_std().unwrap());
+    loop {
+        timer.tick().await;
+        tokio::spawn(async {
+            prune_expired_cooldowns().await;
+        });
+    }
+}
+
+pub async fn prune_expired_uploads_periodically() {
+    let mut timer = tokio::time::interval(chrono::Duration::minutes(10).to_std().unwrap());
+    loop {
+        timer.tick().await;
+        tokio::spawn(async {
+            let upload_expiration = super::UPLOAD_EXPIRATION.load(Ordering::SeqCst) as i64;
+            prune_expired_uploads(upload_expiration).await;
+        });
+    }
+}
+
+pub async fn prune_idempotency_keys_periodically() {
+    let mut timer = tokio::time::interval(chrono::Duration::hours(1).to_std().unwrap());
+    loop {
+        timer.tick().await;
+        tokio::spawn(async {
+            prune_idempotency_keys().await;
+        });
+    }
+}
+
+pub async fn scrub_deleted_messages_periodically() {
+    let mut timer = tokio::time::interval(chrono::Duration::minutes(10).to_std().unwrap());
+    loop {
+        timer.tick().await;
+        tokio::spawn(async {
+            scrub_deleted_messages().await;
+        });
+    }
+}
+
+pub async fn expire_old_messages_periodically() {
+    let interval = super::MESSAGE_RETENTION_SWEEP_INTERVAL.load(Ordering::SeqCst);
+    let mut timer = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        timer.tick().await;
+        tokio::spawn(async {
+            let retention_period = super::MESSAGE_RETENTION_PERIOD.load(Ordering::SeqCst) as i64;
+            let rooms = match get_all_room_ids() {
+                Ok(rooms) => rooms,
+                Err(_) => return,
+            };
+            for room in rooms {
+                // It's not catastrophic if we fail to expire messages for a given room
+                let pool = pool_by_room_id(&room);
+                if let Ok(count) = expire_old_messages(retention_period, &pool) {
+                    info!("Expired {} messages in room: {}.", count, room);
+                }
+            }
+        });
+    }
+}
+
+/// Deletes the messages in the given room that were sent more than `retention_period` seconds ago,
+/// and returns how many were deleted. Like with other deletions, tombstones are added so that
+/// clients can sync the removal.
+pub fn expire_old_messages(
+    retention_period: i64, pool: &DatabaseConnectionPool,
+) -> Result<usize, Error> {
+    let mut conn = pool.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Message timestamps are in milliseconds
+    let cutoff = chrono::Utc::now().timestamp_millis() - retention_period.saturating_mul(1000);
+    let mut count = 0;
+    loop {
+        let batch_count = match expire_old_messages_batch(cutoff, &mut conn) {
+            Ok(batch_count) => batch_count,
+            Err(e) => {
+                error!("Couldn't expire messages due to error: {}.", e);
+                return Err(Error::DatabaseFailedInternally);
+            }
+        };
+        if batch_count == 0 {
+            break;
+        }
+        count += batch_count;
+    }
+    return Ok(count);
+}
+
+fn expire_old_messages_batch(
+    cutoff: i64, conn: &mut rusqlite::Connection,
+) -> Result<usize, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let raw_query = format!(
+        "SELECT id FROM {} WHERE is_deleted = 0 AND timestamp < (?1) ORDER BY id LIMIT (?2)",
+        MESSAGES_TABLE
+    );
+    let ids: Vec<i64> = {
+        let mut query = tx.prepare(&raw_query)?;
+        let rows =
+            query.query_map(params![cutoff, MESSAGE_EXPIRATION_BATCH_SIZE], |row| row.get(0))?;
+        rows.filter_map(|result| result.ok()).collect()
+    };
+    let now = chrono::Utc::now().timestamp();
+    for id in ids.iter() {
+        let stmt = format!("UPDATE {} SET is_deleted = 1 WHERE id = (?1)", MESSAGES_TABLE);
+        tx.execute(&stmt, params![id])?;
+        let stmt = format!(
+            "INSERT INTO {} (deleted_message_id, timestamp) VALUES (?1, ?2)",
+            DELETED_MESSAGES_TABLE
+        );
+        tx.execute(&stmt, params![id, now])?;
+    }
+    tx.commit()?;
+    return Ok(ids.len());
+}
+
 async fn prune_tokens() {
     let rooms = match get_all_room_ids() {
         Ok(rooms) => rooms,
@@ -248,6 +840,105 @@ async fn prune_pending_tokens() {
     info!("Pruned pending tokens.");
 }
 
+async fn prune_idempotency_keys() {
+    let rooms = match get_all_room_ids() {
+        Ok(rooms) => rooms,
+        Err(_) => return,
+    };
+    for room in rooms {
+        let pool = pool_by_room_id(&room);
+        // It's not catastrophic if we fail to prune the database for a given room
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => return error!("Couldn't prune idempotency keys due to error: {}.", e),
+        };
+        let stmt = format!("DELETE FROM {} WHERE timestamp < (?1)", IDEMPOTENCY_KEYS_TABLE);
+        let now = chrono::Utc::now().timestamp();
+        let expiration = now - IDEMPOTENCY_KEY_EXPIRATION;
+        match conn.execute(&stmt, params![expiration]) {
+            Ok(_) => (),
+            Err(e) => return error!("Couldn't prune idempotency keys due to error: {}.", e),
+        };
+    }
+    info!("Pruned idempotency keys.");
+}
+
+pub async fn prune_expired_bans() {
+    let rooms = match get_all_room_ids() {
+        Ok(rooms) => rooms,
+        Err(_) => return,
+    };
+    for room in rooms {
+        let pool = pool_by_room_id(&room);
+        // It's not catastrophic if we fail to prune the database for a given room
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => return error!("Couldn't prune expired bans due to error: {}.", e),
+        };
+        // Permanent bans have a null `banned_until` and are never pruned
+        let stmt = format!("DELETE FROM {} WHERE banned_until <= (?1)", BLOCK_LIST_TABLE);
+        let now = chrono::Utc::now().timestamp();
+        match conn.execute(&stmt, params![now]) {
+            Ok(_) => (),
+            Err(e) => return error!("Couldn't prune expired bans due to error: {}.", e),
+        };
+        pool.invalidate_block_list();
+    }
+    info!("Pruned expired bans.");
+}
+
+async fn prune_expired_cooldowns() {
+    let rooms = match get_all_room_ids() {
+        Ok(rooms) => rooms,
+        Err(_) => return,
+    };
+    for room in rooms {
+        let pool = pool_by_room_id(&room);
+        // It's not catastrophic if we fail to prune the database for a given room
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => return error!("Couldn't prune expired cooldowns due to error: {}.", e),
+        };
+        let stmt = format!("DELETE FROM {} WHERE expires_at <= (?1)", COOLDOWNS_TABLE);
+        let now = chrono::Utc::now().timestamp();
+        match conn.execute(&stmt, params![now]) {
+            Ok(_) => (),
+            Err(e) => return error!("Couldn't prune expired cooldowns due to error: {}.", e),
+        };
+    }
+    info!("Pruned expired cooldowns.");
+}
+
+/// Erases the content of deleted messages once they can no longer be restored.
+async fn scrub_deleted_messages() {
+    let rooms = match get_all_room_ids() {
+        Ok(rooms) => rooms,
+        Err(_) => return,
+    };
+    for room in rooms {
+        let pool = pool_by_room_id(&room);
+        // It's not catastrophic if we fail to scrub the database for a given room
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => return error!("Couldn't scrub deleted messages due to error: {}.", e),
+        };
+        let stmt = format!(
+            "UPDATE {} SET public_key = 'deleted', timestamp = 0, data = 'deleted', signature = 'deleted',
+            search_text = NULL
+            WHERE is_deleted = 1 AND data != 'deleted'
+            AND id NOT IN (SELECT deleted_message_id FROM {} WHERE timestamp > (?1))",
+            MESSAGES_TABLE, DELETED_MESSAGES_TABLE
+        );
+        let now = chrono::Utc::now().timestamp();
+        let window = super::MESSAGE_RESTORE_WINDOW.load(Ordering::SeqCst) as i64;
+        match conn.execute(&stmt, params![now - window]) {
+            Ok(_) => (),
+            Err(e) => return error!("Couldn't scrub deleted messages due to error: {}.", e),
+        };
+    }
+    info!("Scrubbed deleted messages.");
+}
+
 pub async fn prune_files(file_expiration: i64) {
     // The expiration setting is passed in for testing purposes
     let rooms = match get_all_room_ids() {
@@ -317,15 +1008,99 @@ pub async fn prune_files(file_expiration: i64) {
     }
 }
 
+/// Discards resumable uploads that haven't received a chunk in the given number of seconds, along
+/// with what was received of them so far.
+pub async fn prune_expired_uploads(upload_expiration: i64) {
+    // The expiration setting is passed in for testing purposes
+    let rooms = match get_all_room_ids() {
+        Ok(rooms) => rooms,
+        Err(_) => return,
+    };
+    for room in rooms {
+        // It's not catastrophic if we fail to prune the database for a given room
+        let pool = pool_by_room_id(&room);
+        let expiration = chrono::Utc::now().timestamp() - upload_expiration;
+        let mut conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Couldn't prune expired uploads due to error: {}.", e);
+                continue;
+            }
+        };
+        // Remove the upload records from the database
+        // This happens * before * deleting what was received so far, in an immediate transaction,
+        // so that a chunk can't be stored for an upload in between (see
+        // `handlers::store_upload_chunk`), which would leave its file behind. The records are
+        // removed regardless of whether there's a file, because otherwise uploads that never
+        // received a chunk would never be pruned.
+        let result = conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .and_then(|tx| {
+                let raw_query = format!("SELECT id FROM {} WHERE updated_at < (?1)", UPLOADS_TABLE);
+                let ids: Vec<String> = {
+                    let mut query = tx.prepare(&raw_query)?;
+                    let rows = query.query_map(params![expiration], |row| row.get(0))?;
+                    rows.filter_map(|result| result.ok()).collect()
+                };
+                for id in &ids {
+                    let stmt =
+                        format!("DELETE FROM {} WHERE upload_id = (?1)", UPLOAD_CHUNKS_TABLE);
+                    tx.execute(&stmt, params![id])?;
+                    let stmt = format!("DELETE FROM {} WHERE id = (?1)", UPLOADS_TABLE);
+                    tx.execute(&stmt, params![id])?;
+                }
+                tx.commit()?;
+                return Ok(ids);
+            });
+        let ids = match result {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Couldn't prune expired uploads due to error: {}.", e);
+                continue;
+            }
+        };
+        if ids.is_empty() {
+            continue;
+        }
+        // Delete what was received so far
+        for id in &ids {
+            if let Err(e) = fs::remove_file(format!("files/{}_uploads/{}", room, id)) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    error!(
+                        "Couldn't delete upload: {} from room: {} due to error: {}.",
+                        id, room, e
+                    );
+                }
+            }
+        }
+        info!("Pruned {} expired uploads for room: {}.", ids.len(), room);
+    }
+}
+
 // Migration
 
-pub fn perform_migration() {
+/// Brings the databases of all rooms up to date. Stops at the first room that can't be migrated.
+/// Databases that can't be opened are retried up to `max_retries` times with exponential backoff
+/// (see `retry_at_startup`).
+pub async fn perform_migration(max_retries: u32) -> Result<(), Error> {
     let rooms = match get_all_room_ids() {
         Ok(ids) => ids,
-        Err(_e) => {
-            return error!("Couldn't get all room IDs.");
+        Err(e) => {
+            error!("Couldn't get all room IDs.");
+            return Err(e);
         }
     };
+    // Migrations are run as part of creating the database
+    for room in rooms {
+        let description = format!("database for room: {}", room);
+        retry_at_startup(&description, max_retries, || create_database_if_needed(&room)).await?;
+    }
+    return Ok(());
+}
+
+fn migrate(conn: &mut DatabaseConnection) -> Result<(), rusqlite_migration::Error> {
+    // Migrations must only ever be appended to this list, because the number of migrations that
+    // have already been applied is tracked per database
     let create_tokens_table_cmd = format!(
         "CREATE TABLE IF NOT EXISTS {} (
         public_key TEXT,
@@ -334,21 +1109,79 @@ pub fn perform_migration() {
     )",
         TOKENS_TABLE
     );
-    let migrations =
-        Migrations::new(vec![M::up("DROP TABLE tokens"), M::up(&create_tokens_table_cmd)]);
-    for room in rooms {
-        create_database_if_needed(&room);
-        let pool = pool_by_room_id(&room);
-        let mut conn = pool.get().unwrap();
-        migrations.to_latest(&mut conn).unwrap();
-    }
+    let add_banned_until_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN banned_until INTEGER", BLOCK_LIST_TABLE);
+    let add_deletion_timestamp_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN timestamp INTEGER", DELETED_MESSAGES_TABLE);
+    let add_is_restored_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN is_restored INTEGER", DELETED_MESSAGES_TABLE);
+    let add_reply_to_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN reply_to INTEGER", MESSAGES_TABLE);
+    let create_reply_to_index_cmd =
+        format!("CREATE INDEX IF NOT EXISTS {0}_reply_to ON {0} (reply_to)", MESSAGES_TABLE);
+    let add_invite_only_column_cmd = format!(
+        "ALTER TABLE {} ADD COLUMN invite_only INTEGER NOT NULL DEFAULT 0",
+        ROOM_INFO_TABLE
+    );
+    let add_file_metadata_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN metadata BLOB", FILES_TABLE);
+    let create_public_key_index_cmd =
+        format!("CREATE INDEX IF NOT EXISTS {0}_public_key ON {0} (public_key)", MESSAGES_TABLE);
+    let add_max_message_length_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN max_message_length INTEGER", ROOM_INFO_TABLE);
+    let add_ban_reason_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN reason TEXT", BLOCK_LIST_TABLE);
+    let add_banned_at_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN banned_at INTEGER", BLOCK_LIST_TABLE);
+    let add_banned_by_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN banned_by TEXT", BLOCK_LIST_TABLE);
+    let add_max_messages_per_minute_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN max_messages_per_minute INTEGER", ROOM_INFO_TABLE);
+    // Message data is a base64 encoded protobuf, so it's searched through a decoded copy of it
+    let add_search_text_column_cmd =
+        format!("ALTER TABLE {} ADD COLUMN search_text TEXT", MESSAGES_TABLE);
+    let migrations = Migrations::new(vec![
+        M::up("DROP TABLE tokens"),
+        M::up(&create_tokens_table_cmd),
+        M::up(&add_banned_until_column_cmd),
+        M::up(&add_deletion_timestamp_column_cmd),
+        M::up(&add_is_restored_column_cmd),
+        M::up(&add_reply_to_column_cmd),
+        M::up(&create_reply_to_index_cmd),
+        M::up(&add_invite_only_column_cmd),
+        M::up(&create_public_key_index_cmd),
+        M::up(&add_file_metadata_column_cmd),
+        M::up(&add_max_message_length_column_cmd),
+        M::up(&add_ban_reason_column_cmd),
+        M::up(&add_max_messages_per_minute_column_cmd),
+        M::up(&add_banned_at_column_cmd),
+        M::up(&add_banned_by_column_cmd),
+        M::up(&add_search_text_column_cmd),
+    ]);
+    return migrations.to_latest(conn);
 }
 
 // Utilities
 
+pub fn room_exists(room_id: &str) -> Result<bool, Error> {
+    // Get a database connection
+    let conn = MAIN_POOL.get().map_err(|_| Error::DatabaseUnavailable)?;
+    // Query the database
+    let raw_query = format!("SELECT COUNT(id) FROM {} WHERE id = (?1)", MAIN_TABLE);
+    let count: u32 = match conn.query_row(&raw_query, params![room_id], |row| row.get(0)) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Couldn't query database due to error: {}.", e);
+            return Err(Error::DatabaseFailedInternally);
+        }
+    };
+    // Return
+    return Ok(count != 0);
+}
+
 fn get_all_room_ids() -> Result<Vec<String>, Error> {
     // Get a database connection
-    let conn = MAIN_POOL.get().map_err(|_| Error::DatabaseFailedInternally)?;
+    let conn = MAIN_POOL.get().map_err(|_| Error::DatabaseUnavailable)?;
     // Query the database
     let raw_query = format!("SELECT id FROM {}", MAIN_TABLE);
     let mut query = conn.prepare(&raw_query).map_err(|_| Error::DatabaseFailedInternally)?;
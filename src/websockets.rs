@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use warp::ws::{Message, WebSocket};
+use warp::{http::StatusCode, Rejection};
+
+use super::errors;
+use super::errors::Error;
+use super::handlers;
+use super::models;
+use super::storage;
+
+// The number of messages a subscriber can fall behind before it's dropped
+const BUFFER_SIZE: usize = 256;
+// How long a subscriber can take to accept a message before it's dropped
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a subscriber can take to send its auth token before it's dropped
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    // Carries (room ID, JSON encoded message) pairs
+    static ref NEW_MESSAGES: broadcast::Sender<(String, String)> = broadcast::channel(BUFFER_SIZE).0;
+}
+
+/// Forwards `message` to everyone that's subscribed to the room with the given `room_id`.
+///
+/// This never blocks; subscribers that can't keep up are dropped instead.
+pub fn publish(room_id: &str, message: &models::Message) {
+    // Serialize the message once rather than once per subscriber
+    let json = match serde_json::to_string(message) {
+        Ok(json) => json,
+        Err(e) => return error!("Couldn't publish message due to error: {}.", e),
+    };
+    // This only fails if there are no subscribers
+    let _ = NEW_MESSAGES.send((room_id.to_string(), json));
+}
+
+/// Authorizes the client with the auth token in the first message it sends over `socket`, and then
+/// sends new messages in the room with the given `room_id` to it until either the client closes
+/// the connection or it falls too far behind.
+pub async fn handle_subscriber(socket: WebSocket, room_id: String) {
+    let (mut sink, mut stream) = socket.split();
+    // The auth token is sent over the socket rather than in the URL, so that it doesn't end up in
+    // access logs
+    let result = match tokio::time::timeout(AUTH_TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(message))) => authorize_subscriber(&message, &room_id),
+        _ => Err(warp::reject::custom(Error::NoAuthToken)),
+    };
+    // Let the client know whether it was subscribed, in the same format as other responses
+    let (json, is_authorized) = match result {
+        Ok(()) => {
+            let json = models::StatusCode { status_code: StatusCode::OK.as_u16() };
+            // Safe because the status code can always be serialized
+            (serde_json::to_string(&json).unwrap(), true)
+        }
+        Err(e) => (describe_error(e).await, false),
+    };
+    let result = tokio::time::timeout(SEND_TIMEOUT, sink.send(Message::text(json))).await;
+    if !is_authorized || !matches!(result, Ok(Ok(_))) {
+        let _ = sink.close().await;
+        return;
+    }
+    let mut receiver = NEW_MESSAGES.subscribe();
+    info!("Added subscriber to room with ID: {}.", &room_id);
+    loop {
+        tokio::select! {
+            result = receiver.recv() => match result {
+                Ok((message_room_id, json)) => {
+                    if message_room_id != room_id {
+                        continue;
+                    }
+                    match tokio::time::timeout(SEND_TIMEOUT, sink.send(Message::text(json))).await {
+                        Ok(Ok(_)) => (),
+                        _ => break,
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    warn!("Dropping subscriber that fell {} messages behind.", count);
+                    break;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = stream.next() => match message {
+                // Subscribers aren't expected to send anything other than a close message
+                Some(Ok(message)) if !message.is_close() => continue,
+                _ => break,
+            },
+        }
+    }
+    // Dropping the receiver unsubscribes the client
+    drop(receiver);
+    let _ = sink.close().await;
+    info!("Removed subscriber from room with ID: {}.", &room_id);
+}
+
+/// Checks that `message` is a JSON object with an `auth_token` that allows subscribing to the room
+/// with the given `room_id`.
+fn authorize_subscriber(message: &Message, room_id: &str) -> Result<(), Rejection> {
+    #[derive(Debug, Deserialize)]
+    struct JSON {
+        auth_token: String,
+    }
+    let text = message.to_str().map_err(|_| warp::reject::custom(Error::NoAuthToken))?;
+    let json: JSON = match serde_json::from_str(text) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("Couldn't parse subscriber auth token due to error: {}.", e);
+            return Err(warp::reject::custom(Error::InvalidBody));
+        }
+    };
+    let pool = storage::pool_by_room_id(room_id);
+    return handlers::check_subscription_authorization(&json.auth_token, &pool);
+}
+
+/// Returns the JSON error body that an HTTP response for `e` would have.
+async fn describe_error(e: Rejection) -> String {
+    let response = match errors::into_response(e) {
+        Ok(response) => response,
+        Err(_) => return "".to_string(),
+    };
+    return match warp::hyper::body::to_bytes(response.into_body()).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+        Err(_) => "".to_string(),
+    };
+}